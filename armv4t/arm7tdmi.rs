@@ -21,18 +21,13 @@ impl Arm7Tdmi {
 	}
 
 	pub fn reset(&mut self) {
-		use OperationMode::SYS;
+		use OperationMode::SVC;
 		use Reg::r15;
-		// Entering service mode should probably do this automatically
-		// once we have a bit more infrastructure set up.
-		// These are technically undefined behavior.
-		self.registers.r14_svc = self.registers.r14;
-		self.registers.spsr_svc = self.registers.cpsr;
 
-		// Program counter set to 0, and in sys mode
-		self.registers.set_operation_mode(SYS);
-
-		// Disable interupts and thumb instruction mode
+		// Resetting enters supervisor mode with interupts disabled, running ARM
+		// code. The old values of r14_svc and spsr_svc are left as they are,
+		// since what ends up in them is unpredictable.
+		self.registers.set_operation_mode(SVC);
 		self.registers.set_fiq_disable(true);
 		self.registers.set_irq_disable(true);
 		self.registers.set_thumb_bit(false);
@@ -46,6 +41,25 @@ impl Arm7Tdmi {
 
 #[cfg(test)]
 mod tests {
+	use super::*;
+
 	#[test]
 	fn empty() {}
+
+	#[test]
+	fn reset_enters_supervisor_mode() {
+		let mut cpu = Arm7Tdmi::init();
+		cpu.registers.set_operation_mode(OperationMode::USR);
+		cpu.registers.set_irq_disable(false);
+		cpu.registers.set_thumb_bit(true);
+		cpu.registers.r15 = 0x0800_0000;
+
+		cpu.reset();
+		let registers = &cpu.registers;
+		assert_eq!(OperationMode::from(registers), OperationMode::SVC);
+		assert!(registers.is_fiq_disabled());
+		assert!(registers.is_irq_disabled());
+		assert!(!registers.get_thumb_bit());
+		assert_eq!(registers.r15, 0);
+	}
 }
@@ -45,7 +45,7 @@ pub enum ConditionCode {
 }
 
 impl ConditionCode {
-	fn check_condition(&self, rs: &RegisterSet) -> bool {
+	pub fn check_condition(&self, rs: &RegisterSet) -> bool {
 		use ConditionCode::*;
 
 		match self {
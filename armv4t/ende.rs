@@ -2,20 +2,24 @@ use std::ops::Range;
 
 /// Get some bits from a word, following the convention of the ARMv4T manual. Eg.
 /// `3..0` represents the 4 least significant bits, like the `[3:0]` notation ARM uses.
+#[allow(dead_code)]
 fn get_bits(inst: u32, range: Range<u32>) -> u32 {
 	debug_assert!(range.start < 32);
 	debug_assert!(range.end < range.start);
-	(inst >> range.end) & (0xffffffff >> (31 - range.end + range.start))
+	(inst >> range.end) & (0xffffffff >> (31 - range.start + range.end))
 }
 
+#[allow(dead_code)]
 const fn get_bit(inst: u32, bit: u32) -> bool {
 	debug_assert!(bit < 32);
 	(inst >> bit) & 0x1 > 0
 }
 
-pub fn decode_instruction(inst: u32) {}
+pub fn decode_instruction(_inst: u32) {}
 
 #[cfg(test)]
+// The ranges are written high to low, the same way the manual writes them
+#[allow(clippy::reversed_empty_ranges)]
 mod tests {
 	use super::*;
 
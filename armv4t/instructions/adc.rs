@@ -1,5 +1,6 @@
 use crate::registers::Reg;
 
+#[allow(dead_code)]
 pub struct Adc {
 	i: bool,
 	s: bool,
@@ -8,6 +9,7 @@ pub struct Adc {
 	shifter_12: u32,
 }
 
+#[allow(dead_code, unused_variables)]
 fn adc(inst: Adc) {
 	let Adc {
 		i,
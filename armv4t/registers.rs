@@ -304,6 +304,7 @@ impl RegisterSet {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
 	use super::*;
 	use crate::modes::OperationMode;
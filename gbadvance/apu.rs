@@ -0,0 +1,578 @@
+//! Mixes the GBA's sound channels together into samples that can be played by
+//! the host. Direct Sound plays 8-bit samples that games queue up in a pair of
+//! FIFOs, usually with DMA, at a rate set by one of the first two timers. The
+//! four PSG channels from the Game Boy are mixed in alongside them.
+
+pub mod fifo;
+pub mod output;
+pub mod psg;
+pub mod resampler;
+
+use crate::memory::{Memory, IO_START};
+use crate::scheduler::Event;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use fifo::{Fifo, FIFO_REFILL};
+use output::AudioOutput;
+use psg::*;
+
+/// Direct Sound volume, enable and timer settings, and the FIFO reset bits.
+pub const SOUNDCNT_H: u32 = 0x0400_0082;
+/// Master sound enable. The low bits show which PSG channels are playing.
+pub const SOUNDCNT_X: u32 = 0x0400_0084;
+/// The DC offset added to the output before it is clipped, and the resolution
+/// of the output.
+pub const SOUNDBIAS: u32 = 0x0400_0088;
+pub const FIFO_A: u32 = 0x0400_00a0;
+pub const FIFO_B: u32 = 0x0400_00a4;
+
+/// The number of samples produced per second, for each of the left and right
+/// channels. This is the rate that the hardware outputs at with the default
+/// SOUNDBIAS settings, and each step up in resolution doubles it.
+pub const SAMPLE_RATE: u32 = 32768;
+/// The number of cycles between each sample at the default rate.
+pub const SAMPLE_CYCLES: u64 = 512;
+/// How many samples can be waiting to be played before new ones start getting
+/// dropped, which is about a quarter of a second at the highest rate. Both
+/// channels count.
+const MAX_BUFFERED: usize = SAMPLE_RATE as usize * 4;
+/// How much audio the output buffer can hold, in seconds.
+const OUTPUT_BUFFER_SECONDS: f64 = 0.2;
+
+const MASTER_ENABLE: u16 = 1 << 7;
+
+pub struct Apu {
+	/// Direct Sound channels A and B.
+	pub fifos: [Fifo; 2],
+	pub channel1: Square,
+	pub channel2: Square,
+	pub channel3: Wave,
+	pub channel4: Noise,
+	/// Both banks of channel 3's samples.
+	pub wave_ram: [[u8; 16]; 2],
+	/// Which of the eight steps the frame sequencer will do next.
+	frame_step: u8,
+	/// The number of cycles between samples, which depends on the resolution
+	/// chosen in SOUNDBIAS.
+	sample_cycles: u64,
+	/// Samples straight from the mixer, with the left and right channels
+	/// interleaved.
+	pub samples: Vec<i16>,
+	/// Where samples end up once they have been resampled for the host, if it
+	/// has asked for a rate to play them at.
+	pub output: Option<AudioOutput>,
+}
+
+impl Apu {
+	pub fn init() -> Self {
+		Self {
+			fifos: [Fifo::init(), Fifo::init()],
+			channel1: Square::default(),
+			channel2: Square::default(),
+			channel3: Wave::default(),
+			channel4: Noise::default(),
+			wave_ram: [[0; 16]; 2],
+			frame_step: 0,
+			sample_cycles: SAMPLE_CYCLES,
+			samples: Vec::new(),
+			output: None,
+		}
+	}
+
+	/// The number of samples currently being produced per second.
+	pub fn sample_rate(&self) -> u32 {
+		SAMPLE_RATE * (SAMPLE_CYCLES / self.sample_cycles) as u32
+	}
+
+	/// Starts resampling audio to `rate` samples per second for the host. A rate
+	/// too low to fill even a single frame of the buffer switches the output off.
+	pub fn set_output_rate(&mut self, rate: u32) {
+		let capacity = (rate as f64 * OUTPUT_BUFFER_SECONDS) as usize;
+		self.output = (capacity > 0).then(|| AudioOutput::init(self.sample_rate(), rate, capacity));
+	}
+
+	/// Passes the samples that the mixer has produced on to the output, if there
+	/// is one.
+	pub fn flush_samples(&mut self) {
+		let rate = self.sample_rate();
+
+		if let Some(output) = &mut self.output {
+			output.push(&self.samples, rate);
+			self.samples.clear();
+		}
+	}
+}
+
+impl Memory {
+	/// Schedules the first sample, and the first step of the frame sequencer.
+	pub fn start_audio(&mut self) {
+		self.scheduler.schedule(SAMPLE_CYCLES, Event::AudioSample);
+		self.scheduler
+			.schedule(FRAME_SEQUENCER_CYCLES, Event::FrameSequencer);
+	}
+
+	/// Returns the index of the wave RAM bank that the CPU can currently see,
+	/// which is the one that isn't selected for playback.
+	fn visible_wave_bank(&self) -> usize {
+		(self.io[(SOUND3CNT_L as usize) - IO_START] >> 6 & 1 ^ 1) as usize
+	}
+
+	pub fn read_wave_ram(&self, address: u32) -> u8 {
+		self.apu.wave_ram[self.visible_wave_bank()][(address - WAVE_RAM) as usize]
+	}
+
+	pub fn write_wave_ram(&mut self, address: u32, value: u8) {
+		let bank = self.visible_wave_bank();
+		self.apu.wave_ram[bank][(address - WAVE_RAM) as usize] = value;
+	}
+
+	/// The bits of SOUNDCNT_X which show which PSG channels are playing.
+	pub fn psg_status(&self) -> u8 {
+		let apu = &self.apu;
+		[
+			apu.channel1.enabled,
+			apu.channel2.enabled,
+			apu.channel3.enabled,
+			apu.channel4.enabled,
+		]
+		.iter()
+		.enumerate()
+		.fold(0, |status, (channel, &enabled)| {
+			status | (enabled as u8) << channel
+		})
+	}
+
+	/// Called after a byte of one of the PSG registers has been written to.
+	pub fn psg_written(&mut self, address: u32, value: u8) {
+		let register = |address: u32| self.read_half_word(address);
+		let restart = value & 0x80 > 0;
+
+		match address {
+			SOUND1CNT_H => self.apu.channel1.load_length(register(SOUND1CNT_H)),
+			SOUND2CNT_L => self.apu.channel2.load_length(register(SOUND2CNT_L)),
+			SOUND3CNT_H => self.apu.channel3.load_length(register(SOUND3CNT_H)),
+			SOUND4CNT_L => self.apu.channel4.load_length(register(SOUND4CNT_L)),
+			SOUND3CNT_L => self.apu.channel3.select_written(register(SOUND3CNT_L)),
+			_ if address == SOUND1CNT_H + 1 => {
+				self.apu.channel1.envelope_written(register(SOUND1CNT_H))
+			}
+			_ if address == SOUND2CNT_L + 1 => {
+				self.apu.channel2.envelope_written(register(SOUND2CNT_L))
+			}
+			_ if address == SOUND4CNT_L + 1 => {
+				self.apu.channel4.envelope_written(register(SOUND4CNT_L))
+			}
+			_ if address == SOUND1CNT_X + 1 && restart => {
+				let (sweep, control) = (register(SOUND1CNT_L), register(SOUND1CNT_H));
+				let frequency = register(SOUND1CNT_X);
+				self.apu.channel1.restart(sweep, control, frequency);
+				self.set_io_register(SOUND1CNT_X, frequency & !RESTART);
+			}
+			_ if address == SOUND2CNT_H + 1 && restart => {
+				let frequency = register(SOUND2CNT_H);
+				self.apu
+					.channel2
+					.restart(0, register(SOUND2CNT_L), frequency);
+				self.set_io_register(SOUND2CNT_H, frequency & !RESTART);
+			}
+			_ if address == SOUND3CNT_X + 1 && restart => {
+				let frequency = register(SOUND3CNT_X);
+				self.apu.channel3.restart(register(SOUND3CNT_L), frequency);
+				self.set_io_register(SOUND3CNT_X, frequency & !RESTART);
+			}
+			_ if address == SOUND4CNT_H + 1 && restart => {
+				let frequency = register(SOUND4CNT_H);
+				self.apu.channel4.restart(register(SOUND4CNT_L), frequency);
+				self.set_io_register(SOUND4CNT_H, frequency & !RESTART);
+			}
+			// Turning sound off resets all of the PSG registers
+			SOUNDCNT_X if value & MASTER_ENABLE as u8 == 0 => {
+				let start = SOUND1CNT_L as usize - IO_START;
+				let end = SOUNDCNT_L as usize - IO_START + 2;
+				self.io[start..end].fill(0);
+
+				let apu = &mut self.apu;
+				apu.channel1 = Square::default();
+				apu.channel2 = Square::default();
+				apu.channel3 = Wave::default();
+				apu.channel4 = Noise::default();
+			}
+			_ => (),
+		}
+	}
+
+	/// Called 512 times a second to clock the length counters, the envelopes,
+	/// and the sweep of channel 1.
+	pub fn frame_sequencer(&mut self, time: u64) {
+		self.scheduler
+			.schedule_at(time + FRAME_SEQUENCER_CYCLES, Event::FrameSequencer);
+
+		let step = self.apu.frame_step;
+		self.apu.frame_step = (step + 1) % 8;
+
+		let register = |address: u32| self.read_half_word(address);
+		let frequencies = [
+			register(SOUND1CNT_X),
+			register(SOUND2CNT_H),
+			register(SOUND3CNT_X),
+			register(SOUND4CNT_H),
+		];
+		let envelopes = [
+			register(SOUND1CNT_H),
+			register(SOUND2CNT_L),
+			register(SOUND4CNT_L),
+		];
+		let sweep = register(SOUND1CNT_L);
+
+		// Length counters are clocked at 256Hz, on every other step
+		if step & 1 == 0 {
+			self.apu.channel1.step_length(frequencies[0]);
+			self.apu.channel2.step_length(frequencies[1]);
+			self.apu.channel3.step_length(frequencies[2]);
+			self.apu.channel4.step_length(frequencies[3]);
+		}
+
+		if step == 2 || step == 6 {
+			if let Some(frequency) = self.apu.channel1.step_sweep(sweep, frequencies[0]) {
+				self.set_io_register(SOUND1CNT_X, frequency);
+			}
+		}
+
+		if step == 7 {
+			self.apu.channel1.step_envelope(envelopes[0]);
+			self.apu.channel2.step_envelope(envelopes[1]);
+			self.apu.channel4.step_envelope(envelopes[2]);
+		}
+	}
+
+	/// Moves the PSG channels along by the given number of cycles.
+	fn advance_psg(&mut self, cycles: u64) {
+		let register = |address: u32| self.read_half_word(address);
+		let frequencies = [
+			register(SOUND1CNT_X),
+			register(SOUND2CNT_H),
+			register(SOUND3CNT_X),
+			register(SOUND4CNT_H),
+		];
+		let select = register(SOUND3CNT_L);
+
+		self.apu.channel1.advance(cycles, frequencies[0]);
+		self.apu.channel2.advance(cycles, frequencies[1]);
+		self.apu.channel3.advance(cycles, select, frequencies[2]);
+		self.apu.channel4.advance(cycles, frequencies[3]);
+	}
+
+	/// Mixes the PSG channels together according to SOUNDCNT_L, and returns
+	/// the left and right sides.
+	fn mix_psg(&self) -> (i32, i32) {
+		let register = |address: u32| self.read_half_word(address);
+		let control = register(SOUNDCNT_L);
+		let apu = &self.apu;
+
+		let outputs = [
+			apu.channel1.output(register(SOUND1CNT_H)),
+			apu.channel2.output(register(SOUND2CNT_L)),
+			apu.channel3
+				.output(&apu.wave_ram, register(SOUND3CNT_L), register(SOUND3CNT_H)),
+			apu.channel4.output(),
+		];
+
+		let side = |enables: usize, volume: usize| {
+			let sum: i32 = (0..4)
+				.filter(|channel| control >> (enables + channel) & 1 > 0)
+				.map(|channel| outputs[channel])
+				.sum();
+			sum * ((control >> volume & 7) as i32 + 1)
+		};
+
+		// The PSG can be turned down to a quarter or a half of its full volume
+		let shift = match register(SOUNDCNT_H) & 3 {
+			0 => 2,
+			1 => 1,
+			_ => 0,
+		};
+
+		(side(12, 4) >> shift, side(8, 0) >> shift)
+	}
+
+	/// Called when a byte is written to one of the FIFO registers.
+	pub fn fifo_written(&mut self, fifo: usize, value: u8) {
+		self.apu.fifos[fifo].push(value);
+	}
+
+	/// Called after the high byte of SOUNDCNT_H is written to, which holds the
+	/// FIFO reset bits. They don't stay set.
+	pub fn sound_control_written(&mut self) {
+		let control = self.read_half_word(SOUNDCNT_H);
+
+		for (fifo, reset) in [11, 15].iter().enumerate() {
+			if control >> reset & 1 > 0 {
+				self.apu.fifos[fifo].reset();
+			}
+		}
+
+		self.set_io_register(SOUNDCNT_H, control & !(1 << 11 | 1 << 15));
+	}
+
+	/// Called whenever timer 0 or 1 overflows, to move any FIFOs which are using
+	/// that timer on to their next sample.
+	pub fn sound_timer_overflow(&mut self, timer: usize) {
+		let control = self.read_half_word(SOUNDCNT_H);
+
+		for (fifo, address) in [FIFO_A, FIFO_B].into_iter().enumerate() {
+			if (control >> (10 + fifo * 4) & 1) as usize != timer {
+				continue;
+			}
+
+			self.apu.fifos[fifo].advance();
+			if self.apu.fifos[fifo].len() <= FIFO_REFILL {
+				self.trigger_sound_dma(address);
+			}
+		}
+	}
+
+	/// Called when it is time to produce the next sample.
+	pub fn audio_sample(&mut self, time: u64) {
+		self.advance_psg(self.apu.sample_cycles);
+
+		// Higher resolutions sample more often. Anything produced at the old rate
+		// has to be resampled before the rate changes.
+		let resolution = self.read_half_word(SOUNDBIAS) >> 14;
+		let sample_cycles = SAMPLE_CYCLES >> resolution;
+		if sample_cycles != self.apu.sample_cycles {
+			self.apu.flush_samples();
+			self.apu.sample_cycles = sample_cycles;
+		}
+
+		self.scheduler
+			.schedule_at(time + sample_cycles, Event::AudioSample);
+
+		let (left, right) = self.mix_audio();
+		if self.apu.samples.len() < MAX_BUFFERED {
+			self.apu.samples.push(left);
+			self.apu.samples.push(right);
+		}
+	}
+
+	/// Mixes together everything that is currently playing.
+	fn mix_audio(&self) -> (i16, i16) {
+		if self.read_half_word(SOUNDCNT_X) & MASTER_ENABLE == 0 {
+			return (0, 0);
+		}
+
+		let control = self.read_half_word(SOUNDCNT_H);
+		let (mut left, mut right) = self.mix_psg();
+
+		for (fifo, state) in self.apu.fifos.iter().enumerate() {
+			// Full volume is twice as loud as half volume
+			let volume = if control >> (2 + fifo) & 1 > 0 { 4 } else { 2 };
+			let sample = state.current as i32 * volume;
+
+			if control >> (8 + fifo * 4) & 1 > 0 {
+				right += sample;
+			}
+			if control >> (9 + fifo * 4) & 1 > 0 {
+				left += sample;
+			}
+		}
+
+		// The hardware adds the bias to move the signal into an unsigned 10-bit
+		// range, and clips anything outside of it. The result is centered again
+		// and scaled up to 16 bits.
+		let bias_register = self.read_half_word(SOUNDBIAS);
+		let bias = (bias_register & 0x3fe) as i32;
+
+		// The resolution setting trades amplitude resolution for a higher
+		// sampling rate, so the lowest bits are dropped as the rate goes up.
+		let resolution = !((2 << (bias_register >> 14)) - 1);
+		let output = |sample: i32| {
+			let clipped = (sample + bias).clamp(0, 0x3ff) & resolution;
+			((clipped - 0x200) * 64) as i16
+		};
+
+		(output(left), output(right))
+	}
+}
+
+/// The samples waiting to be played by the host are left alone, so that
+/// loading a state doesn't cause a gap in the audio.
+impl Snapshot for Apu {
+	fn write_state(&self, state: &mut StateWriter) {
+		self.fifos[0].write_state(state);
+		self.fifos[1].write_state(state);
+		self.channel1.write_state(state);
+		self.channel2.write_state(state);
+		self.channel3.write_state(state);
+		self.channel4.write_state(state);
+		state.bytes(&self.wave_ram.concat());
+		state.u8(self.frame_step);
+		state.u64(self.sample_cycles);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.fifos[0].read_state(state)?;
+		self.fifos[1].read_state(state)?;
+		self.channel1.read_state(state)?;
+		self.channel2.read_state(state)?;
+		self.channel3.read_state(state)?;
+		self.channel4.read_state(state)?;
+
+		let mut wave_ram = [0; 32];
+		state.fill(&mut wave_ram)?;
+		self.wave_ram[0].copy_from_slice(&wave_ram[..16]);
+		self.wave_ram[1].copy_from_slice(&wave_ram[16..]);
+
+		self.frame_step = state.u8()?;
+		self.sample_cycles = state.u64()?;
+		if self.sample_cycles == 0 {
+			return Err(StateError::Invalid);
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dma::{CHANNEL_SIZE, DMA0CNT_H, DMA0DAD, DMA0SAD};
+	use crate::memory::RAM_START;
+	use crate::timers::TM0CNT_H;
+
+	#[test]
+	fn mixing() {
+		let mut memory = Memory::init();
+
+		memory.write_half_word(SOUNDCNT_X, MASTER_ENABLE);
+		memory.write_half_word(SOUNDBIAS, 0x200);
+		// A at full volume on both sides, B at half volume on the left only
+		memory.write_half_word(SOUNDCNT_H, 0b0010_0011_0000_0100);
+
+		memory.write_word(FIFO_A, 0x0000_0010);
+		memory.write_word(FIFO_B, 0x0000_0020);
+		memory.sound_timer_overflow(0);
+
+		memory.audio_sample(0);
+		assert_eq!(memory.apu.samples, [(0x40 + 0x40) * 64, 0x40 * 64]);
+
+		// Turning sound off silences everything
+		memory.write_half_word(SOUNDCNT_X, 0);
+		memory.audio_sample(0);
+		assert_eq!(memory.apu.samples[2..], [0, 0]);
+	}
+
+	#[test]
+	fn reset_fifo() {
+		let mut memory = Memory::init();
+
+		memory.write_word(FIFO_A, 0x0403_0201);
+		memory.write_word(FIFO_B, 0x0403_0201);
+		assert_eq!(memory.apu.fifos[0].len(), 4);
+
+		memory.write_half_word(SOUNDCNT_H, 1 << 11);
+		assert!(memory.apu.fifos[0].is_empty());
+		assert_eq!(memory.apu.fifos[1].len(), 4);
+		assert_eq!(memory.read_half_word(SOUNDCNT_H), 0);
+	}
+
+	#[test]
+	fn dma_refills_fifo() {
+		let mut memory = Memory::init();
+		let ram = RAM_START as u32;
+
+		for i in 0..16 {
+			memory.write_word(ram + i * 4, 0x0101_0101 * (i + 1));
+		}
+
+		// DMA1 feeds FIFO A, which plays a sample every time timer 0 overflows
+		let dma1 = CHANNEL_SIZE;
+		memory.write_word(DMA0SAD + dma1, ram);
+		memory.write_word(DMA0DAD + dma1, FIFO_A);
+		memory.write_half_word(DMA0CNT_H + dma1, 0xb600);
+		memory.write_half_word(TM0CNT_H, 0x80);
+
+		memory.timer_overflow(0, 0);
+		memory.run_dma();
+		assert_eq!(memory.apu.fifos[0].len(), 16);
+
+		memory.timer_overflow(0, 0);
+		assert_eq!(memory.apu.fifos[0].current, 1);
+		memory.run_dma();
+		assert_eq!(memory.apu.fifos[0].len(), 31);
+	}
+
+	#[test]
+	fn output_rate_too_low() {
+		let mut memory = Memory::init();
+		memory.apu.set_output_rate(48000);
+		assert!(memory.apu.output.is_some());
+
+		for rate in [0, 4] {
+			memory.apu.set_output_rate(rate);
+			assert!(memory.apu.output.is_none());
+		}
+
+		// Nothing is resampled, so nothing can go wrong
+		memory.audio_sample(0);
+		memory.apu.flush_samples();
+	}
+
+	#[test]
+	fn sample_rate_follows_resolution() {
+		let mut memory = Memory::init();
+		memory.apu.set_output_rate(48000);
+
+		memory.audio_sample(0);
+		assert_eq!(memory.scheduler.next_event_time(), Some(SAMPLE_CYCLES));
+
+		// Switching to a higher rate hands the old samples over to be resampled
+		memory.write_half_word(SOUNDBIAS, 0x4200);
+		memory.scheduler.advance(SAMPLE_CYCLES);
+		memory.scheduler.pop_due();
+		memory.audio_sample(SAMPLE_CYCLES);
+		assert_eq!(memory.apu.sample_rate(), SAMPLE_RATE * 2);
+		assert_eq!(memory.apu.samples.len(), 2);
+		assert_eq!(
+			memory.scheduler.next_event_time(),
+			Some(SAMPLE_CYCLES + SAMPLE_CYCLES / 2)
+		);
+	}
+
+	#[test]
+	fn psg_registers() {
+		let mut memory = Memory::init();
+
+		memory.write_half_word(SOUNDCNT_X, MASTER_ENABLE);
+		memory.write_half_word(SOUNDBIAS, 0x200);
+		// Channel 2 on the right at full volume, and the PSG at full volume
+		memory.write_half_word(SOUNDCNT_L, 0x0207);
+		memory.write_half_word(SOUNDCNT_H, 2);
+
+		// 50% duty at volume 15, and then restart it
+		memory.write_half_word(SOUND2CNT_L, 2 << 6 | 0xf000);
+		memory.write_half_word(SOUND2CNT_H, RESTART);
+		assert_eq!(memory.read_half_word(SOUNDCNT_X), 0x82);
+		assert_eq!(memory.read_half_word(SOUND2CNT_H), 0);
+
+		memory.audio_sample(0);
+		assert_eq!(memory.apu.samples, [0, 15 * 8 * 64]);
+
+		// Turning sound off resets everything
+		memory.write_half_word(SOUNDCNT_X, 0);
+		assert_eq!(memory.read_half_word(SOUNDCNT_X), 0);
+		assert_eq!(memory.read_half_word(SOUND2CNT_L), 0);
+	}
+
+	#[test]
+	fn wave_ram_banks() {
+		let mut memory = Memory::init();
+
+		// With bank 0 selected for playback, the CPU sees bank 1
+		memory.write_byte(WAVE_RAM, 0x12);
+		assert_eq!(memory.apu.wave_ram[1][0], 0x12);
+
+		memory.write_half_word(SOUND3CNT_L, 1 << 6);
+		assert_eq!(memory.read_byte(WAVE_RAM), 0);
+		memory.write_byte(WAVE_RAM, 0x34);
+		assert_eq!(memory.apu.wave_ram[0][0], 0x34);
+	}
+}
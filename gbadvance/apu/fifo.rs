@@ -0,0 +1,100 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+/// The number of samples that fit in a FIFO.
+pub const FIFO_SIZE: usize = 32;
+/// Once a FIFO has this many samples or fewer left, it asks DMA for more.
+pub const FIFO_REFILL: usize = 16;
+
+/// One of the two Direct Sound queues of 8-bit signed samples. Games keep them
+/// topped up, usually with DMA, and a timer overflow plays the next sample.
+#[derive(Clone, Debug, Default)]
+pub struct Fifo {
+	samples: VecDeque<i8>,
+	/// The sample currently being played, which stays the same until the next
+	/// timer overflow.
+	pub current: i8,
+}
+
+impl Fifo {
+	pub fn init() -> Self {
+		Self {
+			samples: VecDeque::with_capacity(FIFO_SIZE),
+			current: 0,
+		}
+	}
+
+	/// Adds a sample to the end of the queue. Samples written while the queue
+	/// is full are dropped.
+	pub fn push(&mut self, sample: u8) {
+		if self.samples.len() < FIFO_SIZE {
+			self.samples.push_back(sample as i8);
+		}
+	}
+
+	/// Moves on to the next sample. If the queue has run dry, the last sample
+	/// keeps playing.
+	pub fn advance(&mut self) {
+		if let Some(sample) = self.samples.pop_front() {
+			self.current = sample;
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.samples.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.samples.is_empty()
+	}
+
+	pub fn reset(&mut self) {
+		self.samples.clear();
+		self.current = 0;
+	}
+}
+
+impl Snapshot for Fifo {
+	fn write_state(&self, state: &mut StateWriter) {
+		let samples: Vec<u8> = self.samples.iter().map(|&sample| sample as u8).collect();
+		state.bytes(&samples);
+		state.u8(self.current as u8);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		let samples = state.bytes()?;
+		if samples.len() > FIFO_SIZE {
+			return Err(StateError::Invalid);
+		}
+
+		self.samples = samples.iter().map(|&sample| sample as i8).collect();
+		self.current = state.u8()? as i8;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn push_and_advance() {
+		let mut fifo = Fifo::init();
+
+		fifo.push(0x7f);
+		fifo.push(0x80);
+		fifo.advance();
+		assert_eq!(fifo.current, 127);
+		fifo.advance();
+		assert_eq!(fifo.current, -128);
+
+		// Running out keeps the last sample
+		fifo.advance();
+		assert_eq!(fifo.current, -128);
+
+		for _ in 0..40 {
+			fifo.push(0);
+		}
+		assert_eq!(fifo.len(), FIFO_SIZE);
+	}
+}
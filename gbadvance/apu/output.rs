@@ -0,0 +1,136 @@
+use super::resampler::Resampler;
+
+/// How far the resampling ratio is allowed to stray from the real ratio to keep
+/// the buffer from running dry or overflowing. Half a percent is too small of a
+/// change in pitch to be heard.
+const MAX_DEVIATION: f64 = 0.005;
+
+/// A fixed size queue of stereo samples, with the left and right channels
+/// interleaved. The host reads samples straight out of `data`, starting at
+/// `read` and wrapping around at the end, and then calls `consume` once it is
+/// done with them.
+pub struct RingBuffer {
+	pub data: Vec<f32>,
+	/// The index of the oldest sample that hasn't been read, in frames (pairs
+	/// of left and right samples).
+	pub read: usize,
+	/// How many frames are waiting to be read.
+	pub len: usize,
+}
+
+impl RingBuffer {
+	pub fn init(capacity: usize) -> Self {
+		Self {
+			data: vec![0.0; capacity * 2],
+			read: 0,
+			len: 0,
+		}
+	}
+
+	/// The number of frames that the buffer can hold.
+	pub fn capacity(&self) -> usize {
+		self.data.len() / 2
+	}
+
+	/// Adds a frame to the end of the buffer, unless it is already full.
+	pub fn push(&mut self, frame: [f32; 2]) -> bool {
+		if self.len == self.capacity() {
+			return false;
+		}
+
+		let write = (self.read + self.len) % self.capacity();
+		self.data[write * 2..write * 2 + 2].copy_from_slice(&frame);
+		self.len += 1;
+		true
+	}
+
+	/// Marks frames as read, making room for new ones.
+	pub fn consume(&mut self, frames: usize) {
+		let frames = frames.min(self.len);
+		self.read = (self.read + frames) % self.capacity();
+		self.len -= frames;
+	}
+}
+
+/// Turns the mixer's output into samples at the rate that the host plays audio
+/// at. The host and the emulator each run on their own clocks, which never quite
+/// agree, so the resampling ratio is nudged up or down to keep the buffer about
+/// half full.
+pub struct AudioOutput {
+	resampler: Resampler,
+	pub buffer: RingBuffer,
+	/// Where the mixer's output is gathered before being resampled.
+	input: Vec<[f32; 2]>,
+}
+
+impl AudioOutput {
+	/// Creates an output at `rate` samples per second, with room for
+	/// `capacity` frames in the buffer.
+	pub fn init(input_rate: u32, rate: u32, capacity: usize) -> Self {
+		Self {
+			resampler: Resampler::init(input_rate, rate),
+			buffer: RingBuffer::init(capacity),
+			input: Vec::new(),
+		}
+	}
+
+	/// Resamples interleaved 16-bit samples from the mixer, and adds them to the
+	/// buffer. Samples that don't fit in the buffer are dropped.
+	pub fn push(&mut self, samples: &[i16], input_rate: u32) {
+		self.input.clear();
+		self.input.extend(
+			samples
+				.chunks_exact(2)
+				.map(|frame| [frame[0] as f32 / 32768.0, frame[1] as f32 / 32768.0]),
+		);
+
+		// Use up the input faster when the buffer is more than half full, and
+		// slower when it is less than half full.
+		let fill = self.buffer.len as f64 / self.buffer.capacity() as f64;
+		let adjust = 1.0 + MAX_DEVIATION * (fill * 2.0 - 1.0);
+
+		let buffer = &mut self.buffer;
+		self.resampler.set_input_rate(input_rate);
+		self.resampler.process(&self.input, adjust, |frame| {
+			buffer.push(frame);
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ring_buffer() {
+		let mut buffer = RingBuffer::init(3);
+
+		assert!(buffer.push([1.0, -1.0]));
+		assert!(buffer.push([2.0, -2.0]));
+		assert!(buffer.push([3.0, -3.0]));
+		assert!(!buffer.push([4.0, -4.0]));
+
+		buffer.consume(2);
+		assert!(buffer.push([5.0, -5.0]));
+		assert_eq!(buffer.len, 2);
+		assert_eq!(buffer.read, 2);
+		assert_eq!(buffer.data, [5.0, -5.0, 2.0, -2.0, 3.0, -3.0]);
+	}
+
+	#[test]
+	fn rate_control() {
+		// Pushing the same audio into an empty buffer and a nearly full one
+		// should make slightly more samples for the empty one.
+		let samples = vec![0; 32768];
+		let mut empty = AudioOutput::init(32768, 48000, 96000);
+		let mut full = AudioOutput::init(32768, 48000, 96000);
+		full.buffer.len = 90000;
+
+		empty.push(&samples, 32768);
+		full.push(&samples, 32768);
+
+		let made_full = full.buffer.len - 90000;
+		assert!(empty.buffer.len > 24000 + 100);
+		assert!(made_full < 24000 - 100);
+	}
+}
@@ -0,0 +1,570 @@
+//! The four programmable sound generator channels carried over from the Game
+//! Boy: two square waves (the first with a frequency sweep), a wave channel which
+//! plays back 4-bit samples from wave RAM, and a noise channel.
+//!
+//! The channels only keep their internal state, and are handed the values of
+//! their registers whenever they need them, so that they don't depend on how
+//! the rest of the system is put together.
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Sweep settings of channel 1.
+pub const SOUND1CNT_L: u32 = 0x0400_0060;
+/// Length, duty and envelope settings of channel 1.
+pub const SOUND1CNT_H: u32 = 0x0400_0062;
+/// Frequency, length enable and restart bit of channel 1.
+pub const SOUND1CNT_X: u32 = 0x0400_0064;
+pub const SOUND2CNT_L: u32 = 0x0400_0068;
+pub const SOUND2CNT_H: u32 = 0x0400_006c;
+/// Wave RAM bank settings and DAC enable of channel 3.
+pub const SOUND3CNT_L: u32 = 0x0400_0070;
+/// Length and volume of channel 3.
+pub const SOUND3CNT_H: u32 = 0x0400_0072;
+pub const SOUND3CNT_X: u32 = 0x0400_0074;
+pub const SOUND4CNT_L: u32 = 0x0400_0078;
+/// Frequency settings, length enable and restart bit of channel 4.
+pub const SOUND4CNT_H: u32 = 0x0400_007c;
+/// PSG master volume and channel panning.
+pub const SOUNDCNT_L: u32 = 0x0400_0080;
+/// The two banks of wave RAM share these 16 bytes. The CPU sees whichever bank
+/// isn't currently selected for playback.
+pub const WAVE_RAM: u32 = 0x0400_0090;
+
+/// Bit of the frequency registers which makes the channel stop when its length
+/// counter runs out.
+pub const LENGTH_ENABLE: u16 = 1 << 14;
+/// Bit of the frequency registers which starts the channel from the beginning.
+pub const RESTART: u16 = 1 << 15;
+
+/// The number of cycles between each step of the frame sequencer, which clocks
+/// the length counters, envelopes and sweep at 512Hz.
+pub const FRAME_SEQUENCER_CYCLES: u64 = 32768;
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Moves a channel's frequency timer forward, and returns the number of times
+/// that it ran out and needed to be reloaded with `period`.
+fn advance_timer(timer: &mut u64, cycles: u64, period: u64) -> u64 {
+	if cycles < *timer {
+		*timer -= cycles;
+		return 0;
+	}
+
+	let over = cycles - *timer;
+	*timer = period - over % period;
+	1 + over / period
+}
+
+/// Checks if an envelope register leaves the channel's DAC turned on. An
+/// envelope that starts at zero and can only go down turns the channel off.
+fn dac_enabled(envelope: u16) -> bool {
+	envelope & 0xf800 > 0
+}
+
+/// The volume envelope of the square and noise channels, configured by bits
+/// 8-15 of their control registers.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Envelope {
+	pub volume: u8,
+	timer: u8,
+}
+
+impl Envelope {
+	fn restart(&mut self, control: u16) {
+		self.volume = (control >> 12) as u8;
+		self.timer = (control >> 8 & 7) as u8;
+	}
+
+	fn step(&mut self, control: u16) {
+		let period = (control >> 8 & 7) as u8;
+		if period == 0 {
+			return;
+		}
+
+		self.timer = self.timer.saturating_sub(1);
+		if self.timer > 0 {
+			return;
+		}
+
+		self.timer = period;
+		if control >> 11 & 1 > 0 {
+			self.volume = (self.volume + 1).min(15);
+		} else {
+			self.volume = self.volume.saturating_sub(1);
+		}
+	}
+}
+
+/// A length counter, which turns its channel off when it runs out.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Length {
+	remaining: u16,
+}
+
+impl Length {
+	fn load(&mut self, max: u16, value: u16) {
+		self.remaining = max - value;
+	}
+
+	/// Restarting a channel with an empty length counter fills it back up.
+	fn restart(&mut self, max: u16) {
+		if self.remaining == 0 {
+			self.remaining = max;
+		}
+	}
+
+	/// Returns true if the channel should be turned off.
+	fn step(&mut self, frequency: u16) -> bool {
+		if frequency & LENGTH_ENABLE == 0 || self.remaining == 0 {
+			return false;
+		}
+
+		self.remaining -= 1;
+		self.remaining == 0
+	}
+}
+
+/// Channels 1 and 2. Channel 2 just never uses its sweep.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Square {
+	pub enabled: bool,
+	length: Length,
+	envelope: Envelope,
+	/// The position within the duty pattern.
+	step: u8,
+	/// The number of cycles until the next step.
+	timer: u64,
+	sweep_timer: u8,
+}
+
+impl Square {
+	/// Called when the length field of the control register is written to.
+	pub fn load_length(&mut self, control: u16) {
+		self.length.load(64, control & 0x3f);
+	}
+
+	/// Called when the envelope of the control register is written to.
+	pub fn envelope_written(&mut self, control: u16) {
+		if !dac_enabled(control) {
+			self.enabled = false;
+		}
+	}
+
+	pub fn restart(&mut self, sweep: u16, control: u16, frequency: u16) {
+		self.enabled = dac_enabled(control);
+		self.length.restart(64);
+		self.envelope.restart(control);
+		self.timer = period(frequency, 16);
+		self.sweep_timer = (sweep >> 4 & 7) as u8;
+	}
+
+	pub fn advance(&mut self, cycles: u64, frequency: u16) {
+		let steps = advance_timer(&mut self.timer, cycles, period(frequency, 16));
+		self.step = ((self.step as u64 + steps) % 8) as u8;
+	}
+
+	pub fn step_length(&mut self, frequency: u16) {
+		if self.length.step(frequency) {
+			self.enabled = false;
+		}
+	}
+
+	pub fn step_envelope(&mut self, control: u16) {
+		self.envelope.step(control);
+	}
+
+	/// Steps the frequency sweep, and returns the new frequency register if it
+	/// changed. Sweeping past the highest frequency turns the channel off.
+	pub fn step_sweep(&mut self, sweep: u16, frequency: u16) -> Option<u16> {
+		let time = (sweep >> 4 & 7) as u8;
+		if !self.enabled || time == 0 {
+			return None;
+		}
+
+		self.sweep_timer = self.sweep_timer.saturating_sub(1);
+		if self.sweep_timer > 0 {
+			return None;
+		}
+		self.sweep_timer = time;
+
+		let shift = sweep & 7;
+		let current = frequency & 0x7ff;
+		let change = current >> shift;
+		let next = if sweep >> 3 & 1 > 0 {
+			current.saturating_sub(change)
+		} else {
+			current + change
+		};
+
+		if next > 0x7ff {
+			self.enabled = false;
+			None
+		} else if shift > 0 {
+			Some(frequency & !0x7ff | next)
+		} else {
+			None
+		}
+	}
+
+	/// Returns the current output, from -15 to 15.
+	pub fn output(&self, control: u16) -> i32 {
+		if !self.enabled {
+			return 0;
+		}
+
+		let pattern = DUTY_PATTERNS[(control >> 6 & 3) as usize];
+		let volume = self.envelope.volume as i32;
+
+		if pattern >> (7 - self.step) & 1 > 0 {
+			volume
+		} else {
+			-volume
+		}
+	}
+}
+
+/// Channel 3.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Wave {
+	pub enabled: bool,
+	length: Length,
+	/// Which sample is being played. In two bank mode this goes through both
+	/// banks, starting with the selected one.
+	position: u8,
+	timer: u64,
+}
+
+impl Wave {
+	pub fn load_length(&mut self, control: u16) {
+		self.length.load(256, control & 0xff);
+	}
+
+	/// Called when SOUND3CNT_L is written to, which has the DAC enable bit.
+	pub fn select_written(&mut self, select: u16) {
+		if select & 0x80 == 0 {
+			self.enabled = false;
+		}
+	}
+
+	pub fn restart(&mut self, select: u16, frequency: u16) {
+		self.enabled = select & 0x80 > 0;
+		self.length.restart(256);
+		self.position = 0;
+		self.timer = period(frequency, 8);
+	}
+
+	pub fn advance(&mut self, cycles: u64, select: u16, frequency: u16) {
+		let samples = if select >> 5 & 1 > 0 { 64 } else { 32 };
+		let steps = advance_timer(&mut self.timer, cycles, period(frequency, 8));
+		self.position = ((self.position as u64 + steps) % samples) as u8;
+	}
+
+	pub fn step_length(&mut self, frequency: u16) {
+		if self.length.step(frequency) {
+			self.enabled = false;
+		}
+	}
+
+	/// Returns the current output, from -15 to 15.
+	pub fn output(&self, ram: &[[u8; 16]; 2], select: u16, control: u16) -> i32 {
+		if !self.enabled {
+			return 0;
+		}
+
+		let bank = (select >> 6 & 1) as usize ^ (self.position / 32) as usize;
+		let index = (self.position % 32) as usize;
+		let byte = ram[bank][index / 2];
+		// The high nibble of each byte is played first
+		let sample = if index & 1 == 0 {
+			byte >> 4
+		} else {
+			byte & 0xf
+		};
+		let sample = sample as i32 * 2 - 15;
+
+		if control >> 15 & 1 > 0 {
+			return sample * 3 / 4;
+		}
+
+		match control >> 13 & 3 {
+			0 => 0,
+			1 => sample,
+			2 => sample / 2,
+			_ => sample / 4,
+		}
+	}
+}
+
+/// Channel 4.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Noise {
+	pub enabled: bool,
+	length: Length,
+	envelope: Envelope,
+	/// The linear feedback shift register which produces the noise.
+	lfsr: u16,
+	timer: u64,
+}
+
+impl Noise {
+	pub fn load_length(&mut self, control: u16) {
+		self.length.load(64, control & 0x3f);
+	}
+
+	pub fn envelope_written(&mut self, control: u16) {
+		if !dac_enabled(control) {
+			self.enabled = false;
+		}
+	}
+
+	pub fn restart(&mut self, control: u16, frequency: u16) {
+		self.enabled = dac_enabled(control);
+		self.length.restart(64);
+		self.envelope.restart(control);
+		self.lfsr = 0x7fff;
+		self.timer = noise_period(frequency).unwrap_or(u64::MAX);
+	}
+
+	pub fn advance(&mut self, cycles: u64, frequency: u16) {
+		// Shift clock frequencies 14 and 15 stop the channel from changing
+		let Some(period) = noise_period(frequency) else {
+			return;
+		};
+
+		let narrow = frequency >> 3 & 1 > 0;
+		for _ in 0..advance_timer(&mut self.timer, cycles, period) {
+			let bit = (self.lfsr ^ self.lfsr >> 1) & 1;
+			self.lfsr = self.lfsr >> 1 | bit << 14;
+
+			if narrow {
+				self.lfsr = self.lfsr & !(1 << 6) | bit << 6;
+			}
+		}
+	}
+
+	pub fn step_length(&mut self, frequency: u16) {
+		if self.length.step(frequency) {
+			self.enabled = false;
+		}
+	}
+
+	pub fn step_envelope(&mut self, control: u16) {
+		self.envelope.step(control);
+	}
+
+	/// Returns the current output, from -15 to 15.
+	pub fn output(&self) -> i32 {
+		if !self.enabled {
+			return 0;
+		}
+
+		let volume = self.envelope.volume as i32;
+		if self.lfsr & 1 == 0 {
+			volume
+		} else {
+			-volume
+		}
+	}
+}
+
+/// The number of cycles between steps of a square or wave channel, where `unit`
+/// is the number of cycles per step at the highest frequency.
+fn period(frequency: u16, unit: u64) -> u64 {
+	(2048 - (frequency & 0x7ff) as u64) * unit
+}
+
+/// The number of cycles between steps of the noise channel, or `None` if the
+/// shift clock frequency is one of the ones which doesn't work.
+fn noise_period(frequency: u16) -> Option<u64> {
+	let ratio = (frequency & 7) as u64;
+	let shift = frequency >> 4 & 0xf;
+
+	if shift >= 14 {
+		return None;
+	}
+
+	// A ratio of 0 counts as 0.5
+	let base = if ratio == 0 { 16 } else { 32 * ratio };
+	Some(base << (shift + 1))
+}
+
+impl Snapshot for Length {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.u16(self.remaining);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.remaining = state.u16()?;
+		Ok(())
+	}
+}
+
+impl Snapshot for Envelope {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.u8(self.volume);
+		state.u8(self.timer);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.volume = state.u8()?;
+		self.timer = state.u8()?;
+		Ok(())
+	}
+}
+
+impl Snapshot for Square {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.bool(self.enabled);
+		self.length.write_state(state);
+		self.envelope.write_state(state);
+		state.u8(self.step);
+		state.u64(self.timer);
+		state.u8(self.sweep_timer);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.enabled = state.bool()?;
+		self.length.read_state(state)?;
+		self.envelope.read_state(state)?;
+		self.step = state.u8()?;
+		self.timer = state.u64()?;
+		self.sweep_timer = state.u8()?;
+		Ok(())
+	}
+}
+
+impl Snapshot for Wave {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.bool(self.enabled);
+		self.length.write_state(state);
+		state.u8(self.position);
+		state.u64(self.timer);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.enabled = state.bool()?;
+		self.length.read_state(state)?;
+		self.position = state.u8()?;
+		self.timer = state.u64()?;
+		Ok(())
+	}
+}
+
+impl Snapshot for Noise {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.bool(self.enabled);
+		self.length.write_state(state);
+		self.envelope.write_state(state);
+		state.u16(self.lfsr);
+		state.u64(self.timer);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.enabled = state.bool()?;
+		self.length.read_state(state)?;
+		self.envelope.read_state(state)?;
+		self.lfsr = state.u16()?;
+		self.timer = state.u64()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn square_duty() {
+		let mut square = Square::default();
+
+		// 25% duty at full volume, with the longest period so that each step is
+		// 16 * 2048 cycles long
+		let control = 1 << 6 | 0xf000;
+		square.restart(0, control, 0);
+		assert_eq!(square.output(control), 15);
+
+		let outputs: Vec<i32> = (0..8)
+			.map(|_| {
+				square.advance(16 * 2048, 0);
+				square.output(control)
+			})
+			.collect();
+		assert_eq!(outputs, [-15, -15, -15, -15, -15, -15, 15, 15]);
+	}
+
+	#[test]
+	fn length_and_envelope() {
+		let mut square = Square::default();
+
+		// Envelope going down every step from volume 2, with 2 steps of length
+		let control = 62 | 0x2100;
+		square.load_length(control);
+		square.restart(0, control, LENGTH_ENABLE);
+
+		square.step_envelope(control);
+		assert_eq!(square.envelope.volume, 1);
+		square.step_length(LENGTH_ENABLE);
+		assert!(square.enabled);
+		square.step_length(LENGTH_ENABLE);
+		assert!(!square.enabled);
+
+		// An envelope that starts at zero and goes down turns the DAC off
+		square.restart(0, 0x0000, 0);
+		assert!(!square.enabled);
+	}
+
+	#[test]
+	fn sweep() {
+		let mut square = Square::default();
+
+		// Increase by a half every sweep step
+		let sweep = 1 << 4 | 1;
+		square.restart(sweep, 0xf000, 0x400);
+		assert_eq!(square.step_sweep(sweep, 0x400), Some(0x600));
+
+		// Going past 0x7ff turns the channel off
+		assert_eq!(square.step_sweep(sweep, 0x600), None);
+		assert!(!square.enabled);
+	}
+
+	#[test]
+	fn wave_banks() {
+		let mut wave = Wave::default();
+		let mut ram = [[0; 16]; 2];
+		ram[0][0] = 0xf0;
+		ram[1][0] = 0x80;
+
+		// Two bank mode, starting from bank 1, at full volume
+		let select = 0x80 | 1 << 6 | 1 << 5;
+		let control = 1 << 13;
+		wave.restart(select, 0x7ff);
+		assert_eq!(wave.output(&ram, select, control), 1);
+
+		// After 32 samples, it moves on to bank 0
+		wave.advance(8 * 32, select, 0x7ff);
+		assert_eq!(wave.output(&ram, select, control), 15);
+		wave.advance(8, select, 0x7ff);
+		assert_eq!(wave.output(&ram, select, control), -15);
+
+		// Quarter volume
+		assert_eq!(wave.output(&ram, select, 3 << 13), -3);
+	}
+
+	#[test]
+	fn noise() {
+		let mut noise = Noise::default();
+
+		noise.restart(0xf000, 0);
+		let period = noise_period(0).unwrap();
+		assert_eq!(period, 32);
+
+		// The register is all ones to begin with, so it takes 15 steps before a
+		// zero reaches the output
+		noise.advance(period * 14, 0);
+		assert_eq!(noise.output(), -15);
+		noise.advance(period, 0);
+		assert_eq!(noise.output(), 15);
+
+		assert_eq!(noise_period(0xe0), None);
+	}
+}
@@ -0,0 +1,186 @@
+use std::f64::consts::PI;
+
+/// The number of input samples on either side of an output sample that are
+/// used to work out its value.
+const HALF_TAPS: usize = 8;
+const TAPS: usize = HALF_TAPS * 2;
+/// The number of fractional positions between two input samples that the
+/// filter is worked out for ahead of time. Positions in between are linearly
+/// interpolated.
+const PHASES: usize = 64;
+
+/// Converts stereo audio from one sample rate to another, using a windowed sinc
+/// filter so that frequencies which can't be represented at the lower of the
+/// two rates are filtered out instead of aliasing.
+pub struct Resampler {
+	input_rate: u32,
+	output_rate: u32,
+	/// Samples that haven't been completely used up yet. The first `HALF_TAPS`
+	/// samples are only kept around for the filter to look back at.
+	input: Vec<[f32; 2]>,
+	/// The position of the next output sample, measured in input samples from
+	/// the start of `input`.
+	position: f64,
+	/// The filter weights for each phase, with one extra phase at the end so
+	/// that interpolating never goes out of bounds.
+	filter: Vec<[f32; TAPS]>,
+}
+
+impl Resampler {
+	pub fn init(input_rate: u32, output_rate: u32) -> Self {
+		Self {
+			input_rate,
+			output_rate,
+			input: vec![[0.0; 2]; HALF_TAPS],
+			position: HALF_TAPS as f64,
+			filter: build_filter(input_rate, output_rate),
+		}
+	}
+
+	/// Changes the rate of the samples being passed in. Samples that have
+	/// already been passed in are treated as having been at the new rate.
+	pub fn set_input_rate(&mut self, input_rate: u32) {
+		if input_rate != self.input_rate {
+			self.input_rate = input_rate;
+			self.filter = build_filter(input_rate, self.output_rate);
+		}
+	}
+
+	/// Resamples as much of the input as possible, and hands each output sample
+	/// to `output`. `adjust` slightly speeds up (above 1) or slows down (below 1)
+	/// the rate that input is used up at, which makes fewer or more output
+	/// samples respectively.
+	pub fn process<F>(&mut self, input: &[[f32; 2]], adjust: f64, mut output: F)
+	where
+		F: FnMut([f32; 2]),
+	{
+		self.input.extend_from_slice(input);
+		let step = self.input_rate as f64 / self.output_rate as f64 * adjust;
+
+		while (self.position as usize) + HALF_TAPS < self.input.len() {
+			output(self.sample_at(self.position));
+			self.position += step;
+		}
+
+		// Throw away anything that the filter won't need to look at again
+		let used = (self.position as usize).saturating_sub(HALF_TAPS);
+		self.input.drain(..used);
+		self.position -= used as f64;
+	}
+
+	fn sample_at(&self, position: f64) -> [f32; 2] {
+		let whole = position as usize;
+		let phase = (position - whole as f64) * PHASES as f64;
+		let index = phase as usize;
+		let blend = (phase - index as f64) as f32;
+
+		let start = whole + 1 - HALF_TAPS;
+		let mut result = [0.0; 2];
+
+		for (tap, sample) in self.input[start..start + TAPS].iter().enumerate() {
+			let weight =
+				self.filter[index][tap] * (1.0 - blend) + self.filter[index + 1][tap] * blend;
+			result[0] += sample[0] * weight;
+			result[1] += sample[1] * weight;
+		}
+
+		result
+	}
+}
+
+/// Works out the weights of the filter for every phase. The cutoff is a little
+/// below the Nyquist frequency of whichever rate is lower, to leave room for the
+/// filter to roll off.
+fn build_filter(input_rate: u32, output_rate: u32) -> Vec<[f32; TAPS]> {
+	let cutoff = (output_rate as f64 / input_rate as f64).min(1.0) * 0.9;
+
+	(0..=PHASES)
+		.map(|phase| {
+			let fraction = phase as f64 / PHASES as f64;
+			let mut weights = [0.0; TAPS];
+
+			for (tap, weight) in weights.iter_mut().enumerate() {
+				// The distance from the output sample to this input sample
+				let x = fraction + HALF_TAPS as f64 - 1.0 - tap as f64;
+				*weight = cutoff * sinc(cutoff * x) * blackman(x / HALF_TAPS as f64);
+			}
+
+			// Make sure that the weights add up to exactly 1, so that the volume
+			// doesn't wobble from one phase to the next.
+			let total: f64 = weights.iter().sum();
+			weights.map(|weight| (weight / total) as f32)
+		})
+		.collect()
+}
+
+fn sinc(x: f64) -> f64 {
+	if x == 0.0 {
+		1.0
+	} else {
+		(PI * x).sin() / (PI * x)
+	}
+}
+
+/// The Blackman window, for `x` from -1 to 1.
+fn blackman(x: f64) -> f64 {
+	if x.abs() >= 1.0 {
+		return 0.0;
+	}
+
+	0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn resample(resampler: &mut Resampler, input: &[[f32; 2]]) -> Vec<[f32; 2]> {
+		let mut output = Vec::new();
+		resampler.process(input, 1.0, |sample| output.push(sample));
+		output
+	}
+
+	#[test]
+	fn output_length() {
+		let mut resampler = Resampler::init(32768, 48000);
+
+		// A second of input should become very close to a second of output,
+		// minus the samples that are still waiting for more input after them.
+		let output = resample(&mut resampler, &vec![[0.0; 2]; 32768]);
+		assert!((47980..=48000).contains(&output.len()));
+	}
+
+	#[test]
+	fn keeps_constant_signal() {
+		let mut resampler = Resampler::init(32768, 44100);
+
+		let output = resample(&mut resampler, &vec![[0.5, -0.25]; 1000]);
+
+		// Skip the start, where the filter is still seeing the initial silence
+		for sample in &output[20..] {
+			assert!((sample[0] - 0.5).abs() < 0.001);
+			assert!((sample[1] + 0.25).abs() < 0.001);
+		}
+	}
+
+	#[test]
+	fn filters_out_high_frequencies() {
+		let mut resampler = Resampler::init(48000, 16000);
+
+		// A tone at 12kHz can't be represented at 16kHz, and should be almost
+		// entirely removed rather than aliasing down to 4kHz.
+		let input: Vec<[f32; 2]> = (0..4800)
+			.map(|i| {
+				let value = (2.0 * PI * 12000.0 * i as f64 / 48000.0).sin() as f32;
+				[value, value]
+			})
+			.collect();
+
+		let output = resample(&mut resampler, &input);
+		let peak = output[20..]
+			.iter()
+			.map(|sample| sample[0].abs())
+			.fold(0.0, f32::max);
+		assert!(peak < 0.05, "peak was {}", peak);
+	}
+}
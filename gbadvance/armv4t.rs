@@ -7,6 +7,10 @@ pub mod thumb;
 pub mod utils;
 
 #[cfg(test)]
+// The instructions are grouped by field rather than by nibble, and the
+// decoded functions are compared by address
+#[allow(clippy::unusual_byte_groupings, function_casts_as_integer)]
 mod arm_tests;
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod thumb_tests;
@@ -1,5 +1,6 @@
-use crate::emulator::{core::*, ConditionCode, Emulator, Reg};
+use crate::emulator::Emulator;
 use instructions::*;
+use lavender_armv4t::conditions::ConditionCode;
 use std::convert::TryFrom;
 
 /// Decodes and runs the instruction using the given emulator, and returns the
@@ -7,7 +8,7 @@ use std::convert::TryFrom;
 pub fn process_instruction(emulator: &mut Emulator, instruction: u32) -> u32 {
 	// Check if the condition is met before executing the instruction.
 	let condition = ConditionCode::try_from(instruction >> 28 & 15).unwrap();
-	if !emulator.cpu.registers.check_condition(condition) {
+	if !condition.check_condition(&emulator.cpu.registers) {
 		return 1;
 	}
 
@@ -129,7 +130,8 @@ pub fn decode_instruction(instruction: u32) -> fn(&mut Emulator, u32) -> u32 {
 	}
 }
 
-#[allow(non_camel_case_types)]
+// Nothing is decoded into these yet
+#[allow(dead_code, non_camel_case_types)]
 enum Instruction {
 	adc {
 		i: u32,
@@ -182,7 +184,9 @@ enum Instruction {
 /// A module containing functions which implement all of the 32-bit ARM v4T
 /// instructions.
 pub mod instructions {
-	use crate::emulator::{armv4t::utils::*, core::*, Emulator, Reg::*};
+	use crate::armv4t::utils::*;
+	use crate::emulator::Emulator;
+	use lavender_armv4t::registers::Reg::{self, *};
 	use std::convert::TryFrom;
 
 	/// Addition that includes carry from the carry bit in the CPSR register.
@@ -205,7 +209,7 @@ pub mod instructions {
 		if should_update_flags {
 			emulator.cpu.registers.set_nzcv(
 				result >> 31 & 1 > 0,
-				if result == 0 { true } else { false },
+				result == 0,
 				// xxx: one of these two is incorrect
 				overflow, // c: an unsigned overflow occured
 				overflow, // v: a signed overflow occured
@@ -241,7 +245,7 @@ pub mod instructions {
 			} else {
 				emulator.cpu.registers.set_nzcv(
 					result >> 31 & 1 > 0,
-					if result == 0 { true } else { false },
+					result == 0,
 					// xxx: one of these two is incorrect
 					overflow, // c: an unsigned overflow occured
 					overflow, // v: a signed overflow occured
@@ -277,7 +281,7 @@ pub mod instructions {
 			} else {
 				emulator.cpu.registers.set_nzcv(
 					result >> 31 & 1 > 0,
-					if result == 0 { true } else { false },
+					result == 0,
 					false, // xxx: c: shifter_carry_out
 					false, // xxx: this actually shouldn't be mutated at all
 				);
@@ -337,7 +341,7 @@ pub mod instructions {
 			} else {
 				emulator.cpu.registers.set_nzcv(
 					result >> 31 & 1 > 0,
-					if result == 0 { true } else { false },
+					result == 0,
 					false, // xxx: c: shifter_carry_out
 					false, // xxx: this actually shouldn't be mutated at all
 				);
@@ -461,7 +465,7 @@ pub mod instructions {
 		PC = data AND 0xFFFFFFFC Rd = data
 		*/
 
-		let operand_register = Reg::try_from(instruction >> 16 & 0xf).unwrap();
+		let _operand_register = Reg::try_from(instruction >> 16 & 0xf).unwrap();
 		let destination_register = Reg::try_from(instruction >> 12 & 0xf).unwrap();
 		let addressing_mode = process_addressing_mode(emulator, instruction);
 
@@ -562,8 +566,9 @@ pub mod instructions {
 	}
 	/// Triggers an interupt vector from software. Usually used to make system
 	/// calls into the BIOS.
-	pub fn swi(_emulator: &mut Emulator, _instruction: u32) -> u32 {
-		1
+	pub fn swi(emulator: &mut Emulator, instruction: u32) -> u32 {
+		// The BIOS only looks at bits [23:16] of the comment field
+		emulator.software_interrupt((instruction >> 16 & 0xff) as u8)
 	}
 	pub fn swp(_emulator: &mut Emulator, _instruction: u32) -> u32 {
 		1
@@ -1,8 +1,6 @@
-use crate::emulator::{
-	armv4t::arm::{decode_instruction, instructions::*, process_instruction},
-	Emulator,
-	Reg::*,
-};
+use crate::armv4t::arm::{decode_instruction, instructions::*, process_instruction};
+use crate::emulator::Emulator;
+use lavender_armv4t::registers::Reg::*;
 
 #[test]
 fn decode_adc() {
@@ -22,14 +22,8 @@ pub fn decode_instruction(instruction: u16) -> fn(&mut Emulator, u16) -> u32 {
 		0b010 => {
 			let subcategory = instruction >> 10 & 0x7;
 			match subcategory {
-				0b000 => {
-					// Data processing register
-					let opcode = instruction >> 6 & 0xf;
-
-					match opcode {
-						_ => placeholder,
-					}
-				}
+				// Data processing register, with the opcode in [9:6]
+				0b000 => placeholder,
 				0b001 => placeholder,         // Special data processing and branch/exchange
 				0b010 | 0b011 => placeholder, // Load from literal pool
 				0b100..=0b111 => placeholder, // Load/store register offset
@@ -71,7 +65,6 @@ pub fn placeholder(_emulator: &mut Emulator, _instruction: u16) -> u32 {
 }
 
 pub mod instructions {
-	use super::super::arm::instructions::*;
 	use crate::emulator::Emulator;
 
 	pub fn adc(_emulator: &mut Emulator, _instruction: u16) -> u32 {
@@ -175,8 +168,10 @@ pub mod instructions {
 	pub fn sub(_emulator: &mut Emulator, _instruction: u16) -> u32 {
 		1
 	}
-	pub fn swi(_emulator: &mut Emulator, _instruction: u16) -> u32 {
-		1
+	/// Triggers an interupt vector from software, with the function number in
+	/// the low byte.
+	pub fn swi(emulator: &mut Emulator, instruction: u16) -> u32 {
+		emulator.software_interrupt(instruction as u8)
 	}
 	pub fn tst(_emulator: &mut Emulator, _instruction: u16) -> u32 {
 		1
@@ -1,4 +1,5 @@
-use crate::emulator::{core::*, Emulator};
+use crate::emulator::Emulator;
+use lavender_armv4t::registers::Reg;
 use std::convert::TryFrom;
 
 pub fn process_shifter_operand(emulator: &mut Emulator, instruction: u32) -> u32 {
@@ -62,7 +63,7 @@ pub fn process_shifter_operand(emulator: &mut Emulator, instruction: u32) -> u32
 	}
 }
 
-pub fn process_addressing_mode(emulator: &mut Emulator, instruction: u32) -> u32 {
+pub fn process_addressing_mode(_emulator: &mut Emulator, _instruction: u32) -> u32 {
 	0x0800_0000
 }
 
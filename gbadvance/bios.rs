@@ -0,0 +1,1109 @@
+//! High level emulation of the BIOS functions that games call with `swi`.
+//! Rather than running the BIOS's own code, the emulator does the same work
+//! directly, so games can run without a copy of the real BIOS. The functions
+//! that wait for interrupts still jump into the BIOS, because the game's own
+//! interrupt handler has to run while they wait.
+//!
+//! A dump of the official BIOS can also be loaded in place of the built-in one,
+//! to run its code instead, or to boot through its intro.
+
+use crate::apu::{FIFO_A, FIFO_B, SOUNDBIAS};
+use crate::emulator::Emulator;
+use crate::memory::{Memory, BIOS_SIZE, EXT_START, IO_START, RAM_START, ROM_START};
+use crate::ppu::{BG2PA, BG2PD, DISPCNT};
+use crate::scheduler::Scheduler;
+use lavender_armv4t::modes::OperationMode;
+use lavender_armv4t::registers::Reg;
+use std::f64::consts::TAU;
+
+/// How `swi` instructions are handled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BiosMode {
+	/// Handle the functions in Rust, and only run BIOS code for the ones that
+	/// wait for interrupts.
+	HighLevel,
+	/// Always jump into the BIOS, like the real hardware does. This needs a
+	/// real BIOS to be loaded to be of much use.
+	Native,
+}
+
+// The BIOS functions, numbered by the comment field of the `swi` instruction
+const SOFT_RESET: u8 = 0x00;
+const REGISTER_RAM_RESET: u8 = 0x01;
+const HALT: u8 = 0x02;
+const STOP: u8 = 0x03;
+const INTR_WAIT: u8 = 0x04;
+const VBLANK_INTR_WAIT: u8 = 0x05;
+const DIV: u8 = 0x06;
+const DIV_ARM: u8 = 0x07;
+const SQRT: u8 = 0x08;
+const ARC_TAN: u8 = 0x09;
+const ARC_TAN2: u8 = 0x0a;
+const CPU_SET: u8 = 0x0b;
+const CPU_FAST_SET: u8 = 0x0c;
+const GET_BIOS_CHECKSUM: u8 = 0x0d;
+const BG_AFFINE_SET: u8 = 0x0e;
+const OBJ_AFFINE_SET: u8 = 0x0f;
+const BIT_UNPACK: u8 = 0x10;
+const LZ77_UNCOMP_WRAM: u8 = 0x11;
+const LZ77_UNCOMP_VRAM: u8 = 0x12;
+const HUFF_UNCOMP: u8 = 0x13;
+const RL_UNCOMP_WRAM: u8 = 0x14;
+const RL_UNCOMP_VRAM: u8 = 0x15;
+const DIFF_8BIT_UNFILTER_WRAM: u8 = 0x16;
+const DIFF_8BIT_UNFILTER_VRAM: u8 = 0x17;
+const DIFF_16BIT_UNFILTER: u8 = 0x18;
+const SOUND_BIAS: u8 = 0x19;
+const MIDI_KEY_2_FREQ: u8 = 0x1f;
+
+/// Where the BIOS jumps to when a `swi` instruction runs.
+const SWI_VECTOR: u32 = 0x08;
+/// The checksum of the GBA BIOS. The DS version gives a different one.
+const BIOS_CHECKSUM: u32 = 0xbaae_187f;
+
+/// The top of work RAM, which SoftReset clears, and which holds the stacks and
+/// the BIOS's own variables.
+const RESET_AREA: u32 = 0x0300_7e00;
+/// SoftReset boots from work RAM rather than the cartridge if this byte is set.
+const RETURN_TO_EXT: u32 = 0x0300_7ffa;
+const SVC_STACK: u32 = 0x0300_7fe0;
+const IRQ_STACK: u32 = 0x0300_7fa0;
+const SYS_STACK: u32 = 0x0300_7f00;
+
+/// The IO registers cleared by each bit of RegisterRamReset.
+const SERIAL_REGISTERS: [(u32, u32); 2] = [(0x120, 0x130), (0x134, 0x15c)];
+const SOUND_REGISTERS: [(u32, u32); 2] = [(0x60, 0x88), (0x8a, 0xa8)];
+const OTHER_REGISTERS: [(u32, u32); 3] = [(0x00, 0x60), (0xb0, 0x120), (0x200, 0x20c)];
+/// RCNT, which puts the serial port in general purpose mode after a reset.
+const RCNT: u32 = 0x0400_0134;
+/// Set by the BIOS once it has booted, so that it knows to skip the intro if
+/// the game resets.
+const POSTFLG: u32 = 0x0400_0300;
+
+/// The dumps of the official BIOS that can be loaded, by their CRC32.
+const KNOWN_DUMPS: [(u32, BiosVersion); 2] = [
+	(0x8197_7335, BiosVersion::Gba),
+	(0xa647_3709, BiosVersion::Ds),
+];
+
+/// The versions of the official BIOS.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BiosVersion {
+	/// The BIOS from the Game Boy Advance itself.
+	Gba,
+	/// The BIOS used by the Nintendo DS when it runs Game Boy Advance games. It
+	/// is almost identical, but has a different checksum.
+	Ds,
+}
+
+/// The reasons that a BIOS image can be rejected.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BiosError {
+	/// The image isn't 16K, so it's either truncated or something else entirely.
+	WrongSize(usize),
+	/// The image doesn't match any known dump, so it's probably corrupted.
+	UnknownChecksum(u32),
+}
+
+impl BiosVersion {
+	/// Works out which BIOS an image is a dump of.
+	pub fn identify(data: &[u8]) -> Result<Self, BiosError> {
+		if data.len() != BIOS_SIZE {
+			return Err(BiosError::WrongSize(data.len()));
+		}
+
+		let checksum = crc32(data);
+		KNOWN_DUMPS
+			.iter()
+			.find(|(crc, _)| *crc == checksum)
+			.map(|&(_, version)| version)
+			.ok_or(BiosError::UnknownChecksum(checksum))
+	}
+}
+
+/// The same CRC32 used by zip files, which is what BIOS dumps are usually
+/// identified by.
+fn crc32(data: &[u8]) -> u32 {
+	!data.iter().fold(!0, |crc, &byte| {
+		(0..8).fold(crc ^ byte as u32, |crc, _| {
+			(crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+		})
+	})
+}
+
+/// The BIOS keeps a table of 256 sines, as 1.14 fixed point numbers. Angles are
+/// in 256ths of a full turn.
+fn sine(angle: u8) -> i32 {
+	((angle as f64 * TAU / 256.0).sin() * 16384.0).round() as i32
+}
+
+/// Works out the rotation and scaling matrix used by BgAffineSet and
+/// ObjAffineSet, as 8.8 fixed point numbers.
+fn affine_parameters(scale_x: i32, scale_y: i32, angle: u8) -> [i32; 4] {
+	let (sin, cos) = (sine(angle), sine(angle.wrapping_add(64)));
+	[
+		(scale_x * cos) >> 14,
+		(-scale_x * sin) >> 14,
+		(scale_y * sin) >> 14,
+		(scale_y * cos) >> 14,
+	]
+}
+
+fn arc_tan(tan: i32) -> i32 {
+	// The BIOS uses a polynomial approximation with these coefficients
+	let a = -(tan.wrapping_mul(tan) >> 14);
+	let b = [0x390, 0x91c, 0xfb6, 0x16aa, 0x2081, 0x3651, 0xa2f9]
+		.iter()
+		.fold(0xa9, |b: i32, coefficient| {
+			(b.wrapping_mul(a) >> 14) + coefficient
+		});
+	tan.wrapping_mul(b) >> 16
+}
+
+/// Works out the angle of a vector, in 65536ths of a full turn.
+fn arc_tan2(x: i32, y: i32) -> i32 {
+	if y == 0 {
+		return if x >= 0 { 0 } else { 0x8000 };
+	}
+	if x == 0 {
+		return if y >= 0 { 0x4000 } else { 0xc000 };
+	}
+
+	// Fold everything into the first octant, which is the only part that the
+	// approximation is accurate for
+	let y_over_x = || arc_tan(y.wrapping_shl(14) / x);
+	let x_over_y = || arc_tan(x.wrapping_shl(14) / y);
+
+	if y >= 0 {
+		if x >= 0 && x >= y {
+			y_over_x()
+		} else if x < 0 && -x >= y {
+			y_over_x() + 0x8000
+		} else {
+			0x4000 - x_over_y()
+		}
+	} else if x <= 0 && -x > -y {
+		y_over_x() + 0x8000
+	} else if x > 0 && x >= -y {
+		y_over_x() + 0x10000
+	} else {
+		0xc000 - x_over_y()
+	}
+}
+
+fn sqrt(value: u32) -> u32 {
+	let mut root = (value as f64).sqrt() as u64;
+	while root * root > value as u64 {
+		root -= 1;
+	}
+	while (root + 1) * (root + 1) <= value as u64 {
+		root += 1;
+	}
+	root as u32
+}
+
+impl Memory {
+	/// Replaces the built-in BIOS with a dump of the official one. The image is
+	/// only used if it matches a known dump.
+	pub fn load_bios(&mut self, data: &[u8]) -> Result<BiosVersion, BiosError> {
+		let version = BiosVersion::identify(data)?;
+		self.bios.copy_from_slice(data);
+		self.bios_version = Some(version);
+		Ok(version)
+	}
+}
+
+impl Emulator {
+	/// Starts the emulator from the beginning. Booting through the BIOS shows
+	/// the intro and checks the cartridge header before starting the game,
+	/// while skipping it jumps straight into the game as if the BIOS had
+	/// already finished.
+	pub fn boot(&mut self, skip_bios: bool) {
+		self.cpu.reset();
+
+		if skip_bios {
+			self.direct_boot();
+		}
+
+		// Nothing that was scheduled before the reset happens anymore, and the
+		// PPU starts again from the top of the screen
+		self.memory.scheduler = Scheduler::init();
+		self.ppu.reset(&mut self.memory);
+		self.memory.start_audio();
+	}
+
+	/// Leaves the hardware in the same state that the BIOS does when it jumps
+	/// into the game, so that games can run without a BIOS at all.
+	fn direct_boot(&mut self) {
+		// The last thing the BIOS does is clear all of memory and the IO
+		// registers, and it doesn't leave anything of its own in work RAM
+		self.register_ram_reset(0xff);
+		self.memory.ram.fill(0);
+		self.memory.write_half_word(SOUNDBIAS, 0x200);
+		self.memory.write_byte(POSTFLG, 1);
+		self.memory.halted = false;
+		self.memory.stopped = false;
+
+		self.reset_registers(ROM_START as u32);
+		let registers = &mut self.cpu.registers;
+		registers.set_operation_mode(OperationMode::SYS);
+		registers.set_irq_disable(false);
+		registers.set_fiq_disable(false);
+	}
+}
+
+impl Emulator {
+	/// Runs the BIOS function called by a `swi` instruction, and returns the
+	/// number of cycles used.
+	pub fn software_interrupt(&mut self, function: u8) -> u32 {
+		if self.bios_mode == BiosMode::HighLevel {
+			if let Some(cycles) = self.call_bios_function(function) {
+				return cycles;
+			}
+
+			// The built-in BIOS only has code for waiting on interrupts, so
+			// jumping into it for anything else would run off into garbage
+			let waits = matches!(function, INTR_WAIT | VBLANK_INTR_WAIT);
+			if !waits && self.memory.bios_version.is_none() {
+				return 1;
+			}
+		}
+
+		self.enter_software_interrupt();
+		3
+	}
+
+	/// Switches the CPU into supervisor mode and jumps to the BIOS, which reads
+	/// the function number back out of the `swi` instruction.
+	fn enter_software_interrupt(&mut self) {
+		use Reg::*;
+
+		let registers = &mut self.cpu.registers;
+		let status = registers.cpsr;
+		// r15 already points at the next instruction, which is where the BIOS
+		// returns to.
+		let return_address = registers.r15;
+
+		registers.set_operation_mode(OperationMode::SVC);
+		registers.set_value(spsr, status);
+		registers.set_value(r14, return_address);
+		registers.set_irq_disable(true);
+		registers.set_thumb_bit(false);
+		registers.set_value(r15, SWI_VECTOR);
+	}
+
+	/// Runs a BIOS function in Rust. Returns `None` for functions that need to
+	/// run in the BIOS itself, or that aren't supported.
+	fn call_bios_function(&mut self, function: u8) -> Option<u32> {
+		use Reg::*;
+
+		let [a, b, c, d] = [r0, r1, r2, r3].map(|reg| self.cpu.registers.get_value(reg));
+
+		// The real BIOS takes longer than this, but the exact timing isn't
+		// emulated. Functions that move memory around charge for each access.
+		let cycles = match function {
+			SOFT_RESET => self.soft_reset(),
+			REGISTER_RAM_RESET => self.register_ram_reset(a),
+			HALT => {
+				self.memory.halted = true;
+				1
+			}
+			STOP => {
+				self.memory.stopped = true;
+				1
+			}
+			DIV => self.div(a as i32, b as i32),
+			DIV_ARM => self.div(b as i32, a as i32),
+			SQRT => {
+				self.cpu.registers.set_value(r0, sqrt(a));
+				20
+			}
+			ARC_TAN => {
+				self.cpu.registers.set_value(r0, arc_tan(a as i32) as u32);
+				20
+			}
+			ARC_TAN2 => {
+				let angle = arc_tan2(a as i32, b as i32) as u32 & 0xffff;
+				self.cpu.registers.set_value(r0, angle);
+				20
+			}
+			CPU_SET => self.cpu_set(a, b, c, false),
+			CPU_FAST_SET => self.cpu_set(a, b, c, true),
+			GET_BIOS_CHECKSUM => {
+				self.cpu.registers.set_value(r0, BIOS_CHECKSUM);
+				1
+			}
+			BG_AFFINE_SET => self.bg_affine_set(a, b, c),
+			OBJ_AFFINE_SET => self.obj_affine_set(a, b, c, d),
+			BIT_UNPACK => self.bit_unpack(a, b, c),
+			LZ77_UNCOMP_WRAM => self.uncompress(a, b, 1, lz77),
+			LZ77_UNCOMP_VRAM => self.uncompress(a, b, 2, lz77),
+			HUFF_UNCOMP => self.uncompress(a, b, 4, huffman),
+			RL_UNCOMP_WRAM => self.uncompress(a, b, 1, run_length),
+			RL_UNCOMP_VRAM => self.uncompress(a, b, 2, run_length),
+			DIFF_8BIT_UNFILTER_WRAM => self.uncompress(a, b, 1, diff_8bit),
+			DIFF_8BIT_UNFILTER_VRAM => self.uncompress(a, b, 2, diff_8bit),
+			DIFF_16BIT_UNFILTER => self.uncompress(a, b, 2, diff_16bit),
+			SOUND_BIAS => {
+				// The BIOS moves the bias level a step at a time to avoid a pop,
+				// but jumping straight there sounds the same
+				let level = if a == 0 { 0 } else { 0x200 };
+				let bias = self.memory.read_half_word(SOUNDBIAS) & !0x3ff | level;
+				self.memory.write_half_word(SOUNDBIAS, bias);
+				10
+			}
+			MIDI_KEY_2_FREQ => {
+				let frequency = self.memory.read_word(a.wrapping_add(4) & !3) as f64;
+				let semitones = 180.0 - b as f64 - c as f64 / 256.0;
+				let result = frequency / 2f64.powf(semitones / 12.0);
+				self.cpu.registers.set_value(r0, result as u32);
+				20
+			}
+			_ => return None,
+		};
+
+		Some(cycles)
+	}
+
+	fn soft_reset(&mut self) -> u32 {
+		let return_to_ext = self.memory.read_byte(RETURN_TO_EXT) != 0;
+		for address in RESET_AREA..RAM_START as u32 + 0x8000 {
+			self.memory.write_byte(address, 0);
+		}
+
+		let entry = if return_to_ext {
+			EXT_START as u32
+		} else {
+			ROM_START as u32
+		};
+		self.reset_registers(entry);
+		20
+	}
+
+	/// Sets the registers up the way the BIOS leaves them before it jumps to a
+	/// game, with each mode's stack in place and everything else cleared.
+	fn reset_registers(&mut self, entry: u32) {
+		use Reg::*;
+
+		let registers = &mut self.cpu.registers;
+		for (mode, stack) in [
+			(OperationMode::SVC, SVC_STACK),
+			(OperationMode::IRQ, IRQ_STACK),
+			(OperationMode::SYS, SYS_STACK),
+		] {
+			registers.set_operation_mode(mode);
+			registers.set_value(r13, stack);
+			registers.set_value(r14, 0);
+			if mode != OperationMode::SYS {
+				registers.set_value(spsr, 0);
+			}
+		}
+
+		for reg in [r0, r1, r2, r3, r4, r5, r6, r7, r8, r9, r10, r11, r12] {
+			registers.set_value(reg, 0);
+		}
+
+		registers.set_thumb_bit(false);
+		registers.set_value(r15, entry);
+	}
+
+	fn register_ram_reset(&mut self, flags: u32) -> u32 {
+		let memory = &mut self.memory;
+
+		if flags & 1 > 0 {
+			memory.ext.fill(0);
+		}
+		if flags & 2 > 0 {
+			// The top of work RAM holds the stacks, so it's left alone
+			let end = memory.ram.len().saturating_sub(0x200);
+			memory.ram[..end].fill(0);
+		}
+		if flags & 4 > 0 {
+			memory.palette.fill(0);
+		}
+		if flags & 8 > 0 {
+			memory.vram.fill(0);
+		}
+		if flags & 16 > 0 {
+			memory.object.fill(0);
+		}
+
+		let clear = |memory: &mut Memory, ranges: &[(u32, u32)]| {
+			for &(start, end) in ranges {
+				for offset in start..end {
+					let address = IO_START as u32 + offset;
+					// Writing through the bus lets DMA, the timers and the sound
+					// hardware see that they've been switched off, but the FIFOs
+					// would take each zero as another sample
+					if !(FIFO_A..FIFO_B + 4).contains(&address) {
+						memory.write_byte(address, 0);
+					}
+					// Some bits can't be cleared by writing to them, like the
+					// acknowledge-only IF and the status bits of DISPSTAT
+					memory.io[offset as usize] = 0;
+				}
+			}
+		};
+
+		if flags & 32 > 0 {
+			clear(memory, &SERIAL_REGISTERS);
+			memory.write_half_word(RCNT, 0x8000);
+		}
+		if flags & 64 > 0 {
+			clear(memory, &SOUND_REGISTERS);
+			for fifo in &mut memory.apu.fifos {
+				fifo.reset();
+			}
+		}
+		if flags & 128 > 0 {
+			clear(memory, &OTHER_REGISTERS);
+			// The affine backgrounds are left unscaled
+			for background in [0, 0x10] {
+				memory.write_half_word(BG2PA + background, 0x100);
+				memory.write_half_word(BG2PD + background, 0x100);
+			}
+		}
+
+		// The screen is always left blank
+		memory.write_half_word(DISPCNT, 0x80);
+		100
+	}
+
+	fn div(&mut self, numerator: i32, denominator: i32) -> u32 {
+		use Reg::*;
+
+		let (quotient, remainder) = match denominator {
+			// The real BIOS gets stuck forever for most numbers, which isn't
+			// worth emulating
+			0 => (if numerator < 0 { -1 } else { 1 }, numerator),
+			_ => (
+				numerator.wrapping_div(denominator),
+				numerator.wrapping_rem(denominator),
+			),
+		};
+
+		let registers = &mut self.cpu.registers;
+		registers.set_value(r0, quotient as u32);
+		registers.set_value(r1, remainder as u32);
+		registers.set_value(r3, quotient.unsigned_abs());
+		20
+	}
+
+	/// CpuSet and CpuFastSet, which copy or fill memory. The fast version always
+	/// works on words, and rounds the count up to a multiple of 8.
+	fn cpu_set(&mut self, source: u32, destination: u32, control: u32, fast: bool) -> u32 {
+		// The BIOS refuses to copy itself out
+		if source & 0x0e00_0000 == 0 {
+			return 10;
+		}
+
+		let fill = control >> 24 & 1 > 0;
+		let words = fast || control >> 26 & 1 > 0;
+		let mut count = control & 0x1f_ffff;
+		if fast {
+			count = count.wrapping_add(7) & !7;
+		}
+
+		let size = if words { 4 } else { 2 };
+		let source = source & !(size - 1);
+		let destination = destination & !(size - 1);
+
+		for index in 0..count {
+			let from = if fill {
+				source
+			} else {
+				source.wrapping_add(index * size)
+			};
+			let to = destination.wrapping_add(index * size);
+
+			if words {
+				let value = self.memory.read_word(from);
+				self.memory.write_word(to, value);
+			} else {
+				let value = self.memory.read_half_word(from);
+				self.memory.write_half_word(to, value);
+			}
+		}
+
+		10 + count * 2
+	}
+
+	fn bg_affine_set(&mut self, source: u32, destination: u32, count: u32) -> u32 {
+		for index in 0..count {
+			let from = source.wrapping_add(index * 20);
+			let to = destination.wrapping_add(index * 16);
+			let read =
+				|offset: u32| self.memory.read_half_word(from.wrapping_add(offset)) as i16 as i32;
+
+			let origin_x = self.memory.read_word(from) as i32;
+			let origin_y = self.memory.read_word(from.wrapping_add(4)) as i32;
+			let (center_x, center_y) = (read(8), read(10));
+			let (scale_x, scale_y) = (read(12), read(14));
+			let angle = (self.memory.read_half_word(from.wrapping_add(16)) >> 8) as u8;
+
+			let [pa, pb, pc, pd] = affine_parameters(scale_x, scale_y, angle);
+
+			// The reference point is wherever the center of the screen ends up
+			// once it's been transformed
+			let x = origin_x.wrapping_sub((pa * center_x).wrapping_add(pb * center_y));
+			let y = origin_y.wrapping_sub((pc * center_x).wrapping_add(pd * center_y));
+
+			for (offset, value) in [pa, pb, pc, pd].into_iter().enumerate() {
+				self.memory
+					.write_half_word(to.wrapping_add(offset as u32 * 2), value as u16);
+			}
+			self.memory.write_word(to.wrapping_add(8), x as u32);
+			self.memory.write_word(to.wrapping_add(12), y as u32);
+		}
+
+		10 + count * 40
+	}
+
+	fn obj_affine_set(&mut self, source: u32, destination: u32, count: u32, stride: u32) -> u32 {
+		for index in 0..count {
+			let from = source.wrapping_add(index * 8);
+			let scale_x = self.memory.read_half_word(from) as i16 as i32;
+			let scale_y = self.memory.read_half_word(from.wrapping_add(2)) as i16 as i32;
+			let angle = (self.memory.read_half_word(from.wrapping_add(4)) >> 8) as u8;
+
+			let parameters = affine_parameters(scale_x, scale_y, angle);
+
+			// The parameters are usually spread out through OAM, between the
+			// attributes of each object
+			let to = destination.wrapping_add(index * stride * 4);
+			for (offset, value) in parameters.into_iter().enumerate() {
+				let address = to.wrapping_add(offset as u32 * stride);
+				self.memory.write_half_word(address, value as u16);
+			}
+		}
+
+		10 + count * 20
+	}
+
+	/// Expands each of the units in the source into a wider unit, optionally
+	/// adding an offset to each one.
+	fn bit_unpack(&mut self, source: u32, destination: u32, info: u32) -> u32 {
+		let length = self.memory.read_half_word(info & !1) as u32;
+		let source_width = self.memory.read_byte(info.wrapping_add(2)) as u32;
+		let destination_width = self.memory.read_byte(info.wrapping_add(3)) as u32;
+		let offset = self.memory.read_word(info.wrapping_add(4) & !3);
+		// Usually zeros are left as zeros, but this bit adds the offset to them too
+		let offset_zeros = offset >> 31 > 0;
+		let offset = offset & 0x7fff_ffff;
+
+		if ![1, 2, 4, 8].contains(&source_width)
+			|| ![1, 2, 4, 8, 16, 32].contains(&destination_width)
+		{
+			return 10;
+		}
+
+		let source_mask = (1 << source_width) - 1;
+		let destination_mask = (1u64 << destination_width) - 1;
+		let mut destination = destination & !3;
+		let (mut word, mut bits) = (0u64, 0);
+
+		for index in 0..length {
+			let byte = self.memory.read_byte(source.wrapping_add(index)) as u32;
+
+			for shift in (0..8).step_by(source_width as usize) {
+				let mut unit = byte >> shift & source_mask;
+				if unit != 0 || offset_zeros {
+					unit = unit.wrapping_add(offset);
+				}
+
+				word |= (unit as u64 & destination_mask) << bits;
+				bits += destination_width;
+
+				if bits == 32 {
+					self.memory.write_word(destination, word as u32);
+					destination = destination.wrapping_add(4);
+					word = 0;
+					bits = 0;
+				}
+			}
+		}
+
+		10 + length * 8
+	}
+
+	/// Runs one of the decompression functions, which all start with a header
+	/// word giving the size of the output. The VRAM versions write 16 bits at a
+	/// time, since VRAM can't be written a byte at a time, and Huffman writes
+	/// 32 bits at a time.
+	fn uncompress(
+		&mut self,
+		source: u32,
+		destination: u32,
+		unit: usize,
+		decode: fn(&Emulator, u32, usize) -> Vec<u8>,
+	) -> u32 {
+		let source = source & !3;
+		let header = self.memory.read_word(source);
+		let size = (header >> 8) as usize;
+		let data = decode(self, source, size);
+
+		let destination = destination & !(unit as u32 - 1);
+		for (index, chunk) in data.chunks_exact(unit).enumerate() {
+			let address = destination.wrapping_add((index * unit) as u32);
+			match *chunk {
+				[byte] => self.memory.write_byte(address, byte),
+				[low, high] => self
+					.memory
+					.write_half_word(address, u16::from_le_bytes([low, high])),
+				_ => self
+					.memory
+					.write_word(address, u32::from_le_bytes(chunk.try_into().unwrap())),
+			}
+		}
+
+		10 + data.len() as u32 * 2
+	}
+}
+
+/// LZ77 data is split into blocks, each of which is either a byte to copy
+/// straight to the output, or a reference to an earlier run of the output to
+/// copy again. A flag byte says which each of the next 8 blocks are.
+fn lz77(emulator: &Emulator, source: u32, size: usize) -> Vec<u8> {
+	let memory = &emulator.memory;
+	let mut output = Vec::with_capacity(size);
+	let mut address = source.wrapping_add(4);
+	let mut next = || {
+		address = address.wrapping_add(1);
+		memory.read_byte(address.wrapping_sub(1))
+	};
+
+	while output.len() < size {
+		let flags = next();
+
+		for block in (0..8).rev() {
+			if output.len() >= size {
+				break;
+			}
+
+			if flags >> block & 1 == 0 {
+				output.push(next());
+				continue;
+			}
+
+			let (first, second) = (next() as usize, next() as usize);
+			let length = (first >> 4) + 3;
+			let distance = ((first & 0xf) << 8 | second) + 1;
+
+			for _ in 0..length {
+				let byte = output
+					.len()
+					.checked_sub(distance)
+					.map_or(0, |at| output[at]);
+				output.push(byte);
+			}
+		}
+	}
+
+	output.truncate(size);
+	output
+}
+
+/// Huffman data starts with a tree, and then a stream of bits that walk down
+/// the tree from the root to find each unit of data. Units are 4 or 8 bits.
+fn huffman(emulator: &Emulator, source: u32, size: usize) -> Vec<u8> {
+	let memory = &emulator.memory;
+	let unit_bits = memory.read_byte(source) as u32 & 0xf;
+	let tree = source.wrapping_add(4);
+	let root = tree.wrapping_add(1);
+	let mut stream = tree.wrapping_add((memory.read_byte(tree) as u32 + 1) * 2);
+
+	let mut output = Vec::with_capacity(size);
+	let (mut word, mut word_bits) = (0u32, 0);
+	let mut node_address = root;
+
+	if unit_bits != 4 && unit_bits != 8 {
+		return output;
+	}
+
+	while output.len() < size {
+		let bits = memory.read_word(stream);
+		stream = stream.wrapping_add(4);
+
+		for bit in (0..32).rev() {
+			let direction = bits >> bit & 1;
+			let node = memory.read_byte(node_address) as u32;
+			let child = (node_address & !1).wrapping_add((node & 0x3f) * 2 + 2 + direction);
+
+			// Bit 7 says whether the left child is data, and bit 6 is for the right
+			if node >> (7 - direction) & 1 == 0 {
+				node_address = child;
+				continue;
+			}
+
+			let unit = memory.read_byte(child) as u32 & ((1 << unit_bits) - 1);
+			word |= unit << word_bits;
+			word_bits += unit_bits;
+			node_address = root;
+
+			if word_bits == 32 {
+				output.extend(word.to_le_bytes());
+				(word, word_bits) = (0, 0);
+				if output.len() >= size {
+					break;
+				}
+			}
+		}
+	}
+
+	output.truncate(size);
+	output
+}
+
+/// Run length encoded data is made of runs that either repeat a single byte,
+/// or are copied to the output as they are.
+fn run_length(emulator: &Emulator, source: u32, size: usize) -> Vec<u8> {
+	let memory = &emulator.memory;
+	let mut output = Vec::with_capacity(size);
+	let mut address = source.wrapping_add(4);
+	let mut next = || {
+		address = address.wrapping_add(1);
+		memory.read_byte(address.wrapping_sub(1))
+	};
+
+	while output.len() < size {
+		let flag = next() as usize;
+
+		if flag & 0x80 > 0 {
+			let byte = next();
+			output.extend(std::iter::repeat_n(byte, (flag & 0x7f) + 3));
+		} else {
+			for _ in 0..(flag & 0x7f) + 1 {
+				output.push(next());
+			}
+		}
+	}
+
+	output.truncate(size);
+	output
+}
+
+/// Each byte is stored as the difference from the one before it.
+fn diff_8bit(emulator: &Emulator, source: u32, size: usize) -> Vec<u8> {
+	let mut previous = 0u8;
+	(0..size as u32)
+		.map(|index| {
+			previous =
+				previous.wrapping_add(emulator.memory.read_byte(source.wrapping_add(4 + index)));
+			previous
+		})
+		.collect()
+}
+
+/// Each half word is stored as the difference from the one before it.
+fn diff_16bit(emulator: &Emulator, source: u32, size: usize) -> Vec<u8> {
+	let mut previous = 0u16;
+	(0..size as u32 / 2)
+		.flat_map(|index| {
+			let difference = emulator
+				.memory
+				.read_half_word(source.wrapping_add(4 + index * 2));
+			previous = previous.wrapping_add(difference);
+			previous.to_le_bytes()
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use Reg::*;
+
+	const DATA: u32 = EXT_START as u32;
+	const OUTPUT: u32 = EXT_START as u32 + 0x1000;
+
+	fn call(emulator: &mut Emulator, function: u8, arguments: &[u32]) {
+		for (reg, &value) in [r0, r1, r2, r3].iter().zip(arguments) {
+			emulator.cpu.registers.set_value(*reg, value);
+		}
+		emulator.software_interrupt(function);
+	}
+
+	fn write_bytes(emulator: &mut Emulator, address: u32, bytes: &[u8]) {
+		for (index, &byte) in bytes.iter().enumerate() {
+			emulator.memory.write_byte(address + index as u32, byte);
+		}
+	}
+
+	fn read_bytes(emulator: &Emulator, address: u32, length: u32) -> Vec<u8> {
+		(0..length)
+			.map(|index| emulator.memory.read_byte(address + index))
+			.collect()
+	}
+
+	#[test]
+	fn arithmetic() {
+		let mut emulator = Emulator::new();
+
+		call(&mut emulator, DIV, &[-7i32 as u32, 2]);
+		let registers = &emulator.cpu.registers;
+		assert_eq!(registers.r0 as i32, -3);
+		assert_eq!(registers.r1 as i32, -1);
+		assert_eq!(registers.r3, 3);
+
+		call(&mut emulator, DIV_ARM, &[2, 7]);
+		assert_eq!(emulator.cpu.registers.r0, 3);
+
+		call(&mut emulator, SQRT, &[0xffff_ffff]);
+		assert_eq!(emulator.cpu.registers.r0, 0xffff);
+		call(&mut emulator, SQRT, &[99]);
+		assert_eq!(emulator.cpu.registers.r0, 9);
+	}
+
+	#[test]
+	fn angles() {
+		let mut emulator = Emulator::new();
+
+		for (x, y, expected) in [
+			(0x100, 0, 0),
+			(0, 0x100, 0x4000),
+			(-0x100, 0, 0x8000),
+			(0, -0x100, 0xc000),
+		] {
+			call(&mut emulator, ARC_TAN2, &[x as u32, y as u32]);
+			assert_eq!(emulator.cpu.registers.r0, expected);
+		}
+
+		// The approximation is close, but not exact
+		call(&mut emulator, ARC_TAN2, &[0x100, 0x100]);
+		assert!(emulator.cpu.registers.r0.abs_diff(0x2000) < 8);
+		call(
+			&mut emulator,
+			ARC_TAN2,
+			&[-0x100i32 as u32, -0x100i32 as u32],
+		);
+		assert!(emulator.cpu.registers.r0.abs_diff(0xa000) < 8);
+	}
+
+	#[test]
+	fn cpu_set() {
+		let mut emulator = Emulator::new();
+		write_bytes(&mut emulator, DATA, &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+		// Copy 3 half words
+		call(&mut emulator, CPU_SET, &[DATA, OUTPUT, 3]);
+		assert_eq!(read_bytes(&emulator, OUTPUT, 8), [1, 2, 3, 4, 5, 6, 0, 0]);
+
+		// Fill 2 words
+		call(
+			&mut emulator,
+			CPU_SET,
+			&[DATA, OUTPUT, 1 << 26 | 1 << 24 | 2],
+		);
+		assert_eq!(read_bytes(&emulator, OUTPUT, 8), [1, 2, 3, 4, 1, 2, 3, 4]);
+
+		// The fast version always copies 8 words at a time
+		call(&mut emulator, CPU_FAST_SET, &[DATA, OUTPUT + 0x100, 1]);
+		assert_eq!(emulator.memory.read_word(OUTPUT + 0x100 + 28), 0);
+		assert_eq!(emulator.memory.read_word(OUTPUT + 0x100 + 4), 0x0807_0605);
+
+		// Reading from the BIOS isn't allowed
+		call(&mut emulator, CPU_SET, &[0, OUTPUT, 1 << 26 | 1]);
+		assert_eq!(emulator.memory.read_word(OUTPUT), 0x0403_0201);
+	}
+
+	#[test]
+	fn lz77_and_run_length() {
+		let mut emulator = Emulator::new();
+
+		// Three literal bytes, and then a copy of 6 bytes from 3 bytes back
+		let header = 0x10 | 9 << 8;
+		emulator.memory.write_word(DATA, header);
+		write_bytes(
+			&mut emulator,
+			DATA + 4,
+			&[0x10, b'A', b'B', b'C', 0x30, 0x02],
+		);
+		call(&mut emulator, LZ77_UNCOMP_WRAM, &[DATA, OUTPUT]);
+		assert_eq!(read_bytes(&emulator, OUTPUT, 10), b"ABCABCABC\0");
+
+		// A run of 4 bytes, and then 2 bytes as they are
+		emulator.memory.write_word(DATA, 0x30 | 6 << 8);
+		write_bytes(&mut emulator, DATA + 4, &[0x81, b'x', 0x01, b'y', b'z']);
+		call(&mut emulator, RL_UNCOMP_VRAM, &[DATA, OUTPUT]);
+		assert_eq!(read_bytes(&emulator, OUTPUT, 6), b"xxxxyz");
+	}
+
+	#[test]
+	fn huffman() {
+		let mut emulator = Emulator::new();
+
+		emulator.memory.write_word(DATA, 0x28 | 4 << 8);
+		// A tree with a root node and two leaves
+		write_bytes(&mut emulator, DATA + 4, &[1, 0xc0, b'a', b'b']);
+		emulator.memory.write_word(DATA + 8, 0b0110 << 28);
+		call(&mut emulator, HUFF_UNCOMP, &[DATA, OUTPUT]);
+		assert_eq!(read_bytes(&emulator, OUTPUT, 4), b"abba");
+	}
+
+	#[test]
+	fn unpack_and_unfilter() {
+		let mut emulator = Emulator::new();
+
+		// 1 bit units into 4 bit units, adding 1 to each non-zero unit
+		write_bytes(&mut emulator, DATA, &[0b1010_0101]);
+		write_bytes(&mut emulator, DATA + 8, &[1, 0, 1, 4]);
+		emulator.memory.write_word(DATA + 12, 1);
+		call(&mut emulator, BIT_UNPACK, &[DATA, OUTPUT, DATA + 8]);
+		assert_eq!(emulator.memory.read_word(OUTPUT), 0x2020_0202);
+
+		emulator.memory.write_word(DATA, 0x81 | 4 << 8);
+		write_bytes(&mut emulator, DATA + 4, &[10, 1, 0xff, 5]);
+		call(&mut emulator, DIFF_8BIT_UNFILTER_WRAM, &[DATA, OUTPUT]);
+		assert_eq!(read_bytes(&emulator, OUTPUT, 4), [10, 11, 10, 15]);
+	}
+
+	#[test]
+	fn affine() {
+		let mut emulator = Emulator::new();
+
+		// Double the size, turned a quarter of the way around
+		emulator.memory.write_half_word(DATA, 0x200);
+		emulator.memory.write_half_word(DATA + 2, 0x200);
+		emulator.memory.write_half_word(DATA + 4, 0x4000);
+		call(&mut emulator, OBJ_AFFINE_SET, &[DATA, OUTPUT, 1, 2]);
+		let parameters: Vec<i16> = (0..4)
+			.map(|index| emulator.memory.read_half_word(OUTPUT + index * 2) as i16)
+			.collect();
+		assert_eq!(parameters, [0, -0x200, 0x200, 0]);
+	}
+
+	#[test]
+	fn loading() {
+		assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+		let mut memory = Memory::init();
+		assert_eq!(memory.load_bios(&[0; 16]), Err(BiosError::WrongSize(16)));
+		assert!(matches!(
+			memory.load_bios(&[0; BIOS_SIZE]),
+			Err(BiosError::UnknownChecksum(_))
+		));
+		// The built-in BIOS is kept when the image is rejected
+		assert_eq!(memory.bios[..4], crate::memory::BIOS[..4]);
+	}
+
+	#[test]
+	fn skipping_the_bios() {
+		let mut emulator = Emulator::new();
+		emulator.boot(true);
+
+		let registers = &emulator.cpu.registers;
+		assert_eq!(registers.r15, ROM_START as u32);
+		assert_eq!(registers.get_value(r13), SYS_STACK);
+		assert_eq!(registers.r13_svc, SVC_STACK);
+		assert_eq!(registers.r13_irq, IRQ_STACK);
+		assert_eq!(OperationMode::from(registers), OperationMode::SYS);
+		assert!(!registers.is_irq_disabled());
+
+		emulator.boot(false);
+		assert_eq!(emulator.cpu.registers.r15, 0);
+	}
+
+	#[test]
+	fn direct_boot_state() {
+		let mut emulator = Emulator::new();
+		emulator.memory.write_word(DATA, 0x1234_5678);
+		emulator
+			.memory
+			.write_word(RAM_START as u32 + 0x7ffc, 0x0300_0000);
+		emulator.memory.write_half_word(BG2PA, 0x4000);
+		emulator.cpu.registers.set_value(r7, 7);
+		emulator.boot(true);
+
+		let registers = &emulator.cpu.registers;
+		assert_eq!(registers.cpsr, 0x1f);
+		assert_eq!(registers.get_value(r7), 0);
+		assert_eq!(registers.r14_svc, 0);
+		assert_eq!(registers.spsr_svc, 0);
+
+		let memory = &emulator.memory;
+		assert_eq!(memory.read_word(DATA), 0);
+		assert_eq!(memory.read_word(RAM_START as u32 + 0x7ffc), 0);
+		assert_eq!(memory.read_half_word(DISPCNT), 0x80);
+		assert_eq!(memory.read_half_word(BG2PA), 0x100);
+		assert_eq!(memory.read_half_word(BG2PD + 0x10), 0x100);
+		assert_eq!(memory.read_half_word(SOUNDBIAS), 0x200);
+		assert_eq!(memory.read_half_word(RCNT), 0x8000);
+		assert_eq!(memory.read_byte(POSTFLG), 1);
+	}
+
+	#[test]
+	fn direct_boot_clears_pending_interrupts() {
+		use crate::interrupts::{IE, IF};
+		use crate::ppu::{DISPSTAT, VCOUNT};
+
+		let mut emulator = Emulator::new();
+		emulator.memory.write_half_word(IE, 0x3fff);
+		emulator.memory.set_io_register(IF, 0x3fff);
+		emulator.memory.set_io_register(DISPSTAT, 0b111);
+		emulator.memory.set_io_register(VCOUNT, 100);
+		emulator.memory.write_word(FIFO_A, 0x0102_0304);
+		emulator.memory.scheduler.advance(12345);
+		emulator.boot(true);
+
+		let memory = &emulator.memory;
+		assert_eq!(memory.read_half_word(IE), 0);
+		assert_eq!(memory.read_half_word(IF), 0);
+		assert_eq!(memory.read_half_word(DISPSTAT), 0);
+		assert_eq!(memory.read_half_word(VCOUNT), 0);
+		assert_eq!(memory.read_half_word(DISPCNT), 0x80);
+		assert_eq!(memory.read_half_word(SOUNDBIAS), 0x200);
+		assert_eq!(memory.read_byte(POSTFLG), 1);
+		assert!(memory.apu.fifos[0].is_empty());
+		assert_eq!(memory.scheduler.now, 0);
+	}
+
+	#[test]
+	fn native_mode_enters_the_bios() {
+		let mut emulator = Emulator::new();
+		emulator.bios_mode = BiosMode::Native;
+		emulator.cpu.registers.r15 = 0x0800_0004;
+
+		call(&mut emulator, DIV, &[7, 2]);
+		let registers = &emulator.cpu.registers;
+		assert_eq!(registers.r0, 7);
+		assert_eq!(registers.r15, SWI_VECTOR);
+		assert_eq!(registers.get_value(r14), 0x0800_0004);
+		assert_eq!(OperationMode::from(registers), OperationMode::SVC);
+	}
+
+	#[test]
+	fn unsupported_functions_do_nothing() {
+		let mut emulator = Emulator::new();
+		emulator.cpu.registers.r15 = 0x0800_0004;
+
+		// SoundDriverInit, which the built-in BIOS has no code for
+		call(&mut emulator, 0x1a, &[DATA]);
+		assert_eq!(emulator.cpu.registers.r15, 0x0800_0004);
+
+		// The waits still need the BIOS, for the game's interrupt handler to run
+		call(&mut emulator, VBLANK_INTR_WAIT, &[]);
+		assert_eq!(emulator.cpu.registers.r15, SWI_VECTOR);
+	}
+
+	#[test]
+	fn unsupported_functions_run_a_loaded_bios() {
+		let mut emulator = Emulator::new();
+		emulator.memory.bios_version = Some(BiosVersion::Gba);
+		emulator.cpu.registers.r15 = 0x0800_0004;
+
+		call(&mut emulator, 0x1a, &[DATA]);
+		assert_eq!(emulator.cpu.registers.r15, SWI_VECTOR);
+	}
+
+	#[test]
+	fn addresses_wrap_around() {
+		let mut emulator = Emulator::new();
+		let top = 0xffff_fff8;
+
+		call(&mut emulator, CPU_SET, &[DATA, top, 1 << 26 | 4]);
+		call(&mut emulator, BG_AFFINE_SET, &[top, OUTPUT, 1]);
+		call(&mut emulator, BIT_UNPACK, &[top, OUTPUT, top]);
+		call(&mut emulator, LZ77_UNCOMP_WRAM, &[top, OUTPUT]);
+		call(&mut emulator, RL_UNCOMP_WRAM, &[top, OUTPUT]);
+	}
+}
@@ -0,0 +1,173 @@
+//! Every cartridge starts with a 192 byte header, which the BIOS checks before
+//! it will boot the game. It also says which game is on the cartridge, which is
+//! useful for working out what kind of save memory it has, and for showing the
+//! game in a library.
+
+/// The size of the header at the start of the ROM.
+pub const HEADER_SIZE: usize = 0xc0;
+
+const LOGO: std::ops::Range<usize> = 0x04..0xa0;
+const TITLE: std::ops::Range<usize> = 0xa0..0xac;
+const GAME_CODE: std::ops::Range<usize> = 0xac..0xb0;
+const MAKER_CODE: std::ops::Range<usize> = 0xb0..0xb2;
+const FIXED_VALUE: usize = 0xb2;
+const MAIN_UNIT_CODE: usize = 0xb3;
+const DEVICE_TYPE: usize = 0xb4;
+const VERSION: usize = 0xbc;
+const COMPLEMENT_CHECK: usize = 0xbd;
+
+/// Compressed bitmap of the Nintendo logo that the BIOS shows while booting.
+/// The BIOS refuses to start a game unless its header contains an exact copy.
+pub static NINTENDO_LOGO: [u8; 156] = [
+	0x24, 0xff, 0xae, 0x51, 0x69, 0x9a, 0xa2, 0x21, 0x3d, 0x84, 0x82, 0x0a, 0x84, 0xe4, 0x09, 0xad,
+	0x11, 0x24, 0x8b, 0x98, 0xc0, 0x81, 0x7f, 0x21, 0xa3, 0x52, 0xbe, 0x19, 0x93, 0x09, 0xce, 0x20,
+	0x10, 0x46, 0x4a, 0x4a, 0xf8, 0x27, 0x31, 0xec, 0x58, 0xc7, 0xe8, 0x33, 0x82, 0xe3, 0xce, 0xbf,
+	0x85, 0xf4, 0xdf, 0x94, 0xce, 0x4b, 0x09, 0xc1, 0x94, 0x56, 0x8a, 0xc0, 0x13, 0x72, 0xa7, 0xfc,
+	0x9f, 0x84, 0x4d, 0x73, 0xa3, 0xca, 0x9a, 0x61, 0x58, 0x97, 0xa3, 0x27, 0xfc, 0x03, 0x98, 0x76,
+	0x23, 0x1d, 0xc7, 0x61, 0x03, 0x04, 0xae, 0x56, 0xbf, 0x38, 0x84, 0x00, 0x40, 0xa7, 0x0e, 0xfd,
+	0xff, 0x52, 0xfe, 0x03, 0x6f, 0x95, 0x30, 0xf1, 0x97, 0xfb, 0xc0, 0x85, 0x60, 0xd6, 0x80, 0x25,
+	0xa9, 0x63, 0xbe, 0x03, 0x01, 0x4e, 0x38, 0xe2, 0xf9, 0xa2, 0x34, 0xff, 0xbb, 0x3e, 0x03, 0x44,
+	0x78, 0x00, 0x90, 0xcb, 0x88, 0x11, 0x3a, 0x94, 0x65, 0xc0, 0x7c, 0x63, 0x87, 0xf0, 0x3c, 0xaf,
+	0xd6, 0x25, 0xe4, 0x8b, 0x38, 0x0a, 0xac, 0x72, 0x21, 0xd4, 0xf8, 0x07,
+];
+
+/// The details of a game, read from its cartridge header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cartridge {
+	/// The first instruction of the game, which is almost always a branch past
+	/// the rest of the header.
+	pub entry_instruction: u32,
+	/// Up to 12 characters of uppercase ASCII.
+	pub title: String,
+	/// A 4 character code, with the type of game first and the region last.
+	pub game_code: String,
+	/// A 2 character code for the company that made the game.
+	pub maker_code: String,
+	pub main_unit_code: u8,
+	pub device_type: u8,
+	pub version: u8,
+	/// Whether the header has an exact copy of the Nintendo logo in it.
+	pub logo_valid: bool,
+	/// Whether the complement check byte matches the rest of the header, and
+	/// the byte that must always be 0x96 is.
+	pub checksum_valid: bool,
+}
+
+impl Cartridge {
+	/// Reads the header from the start of a ROM. Returns `None` if the ROM is
+	/// too small to have a header at all. Headers that wouldn't pass the BIOS's
+	/// checks are still returned, since plenty of homebrew doesn't bother to get
+	/// them right, and they run fine when booting directly.
+	pub fn parse(rom: &[u8]) -> Option<Self> {
+		let header = rom.get(..HEADER_SIZE)?;
+
+		Some(Self {
+			entry_instruction: u32::from_le_bytes([header[0], header[1], header[2], header[3]]),
+			title: read_string(&header[TITLE]),
+			game_code: read_string(&header[GAME_CODE]),
+			maker_code: read_string(&header[MAKER_CODE]),
+			main_unit_code: header[MAIN_UNIT_CODE],
+			device_type: header[DEVICE_TYPE],
+			version: header[VERSION],
+			logo_valid: header[LOGO] == NINTENDO_LOGO,
+			checksum_valid: header[FIXED_VALUE] == 0x96
+				&& header[COMPLEMENT_CHECK] == complement_check(header),
+		})
+	}
+
+	/// Whether the BIOS would accept this header.
+	pub fn is_valid(&self) -> bool {
+		self.logo_valid && self.checksum_valid
+	}
+
+	/// Works out where the entry instruction jumps to, if it is a branch.
+	pub fn entry_point(&self) -> Option<u32> {
+		// An unconditional B, without the link bit
+		if self.entry_instruction >> 24 != 0xea {
+			return None;
+		}
+
+		// The offset is a signed number of words, relative to the instruction
+		// after next because of the pipeline.
+		let offset = ((self.entry_instruction << 8) as i32 >> 6) as u32;
+		Some(0x0800_0008u32.wrapping_add(offset))
+	}
+}
+
+/// Works out what the complement check byte in a header should be.
+pub fn complement_check(header: &[u8]) -> u8 {
+	header[TITLE.start..COMPLEMENT_CHECK]
+		.iter()
+		.fold(0u8, |sum, byte| sum.wrapping_sub(*byte))
+		.wrapping_sub(0x19)
+}
+
+/// Reads the text out of a fixed size field, which is padded with zeros.
+fn read_string(bytes: &[u8]) -> String {
+	bytes
+		.iter()
+		.take_while(|&&byte| byte != 0)
+		.map(|&byte| if byte.is_ascii() { byte as char } else { '?' })
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_rom() -> Vec<u8> {
+		let mut rom = vec![0; 0x200];
+		// b 0x080000c0
+		rom[0..4].copy_from_slice(&0xea00_002eu32.to_le_bytes());
+		rom[LOGO].copy_from_slice(&NINTENDO_LOGO);
+		rom[TITLE][..8].copy_from_slice(b"LAVENDER");
+		rom[GAME_CODE].copy_from_slice(b"ALVE");
+		rom[MAKER_CODE].copy_from_slice(b"01");
+		rom[FIXED_VALUE] = 0x96;
+		rom[VERSION] = 2;
+		rom[COMPLEMENT_CHECK] = complement_check(&rom);
+		rom
+	}
+
+	#[test]
+	fn parse_header() {
+		let cartridge = Cartridge::parse(&test_rom()).unwrap();
+
+		assert_eq!(cartridge.title, "LAVENDER");
+		assert_eq!(cartridge.game_code, "ALVE");
+		assert_eq!(cartridge.maker_code, "01");
+		assert_eq!(cartridge.version, 2);
+		assert_eq!(cartridge.entry_point(), Some(0x0800_00c0));
+		assert!(cartridge.is_valid());
+	}
+
+	#[test]
+	fn invalid_headers() {
+		assert_eq!(Cartridge::parse(&[0; HEADER_SIZE - 1]), None);
+
+		let mut rom = test_rom();
+		rom[VERSION] = 3;
+		let cartridge = Cartridge::parse(&rom).unwrap();
+		assert!(cartridge.logo_valid);
+		assert!(!cartridge.checksum_valid);
+
+		let mut rom = test_rom();
+		rom[LOGO.start] = 0;
+		let cartridge = Cartridge::parse(&rom).unwrap();
+		assert!(!cartridge.logo_valid);
+		assert!(cartridge.checksum_valid);
+		assert!(!cartridge.is_valid());
+	}
+
+	#[test]
+	fn entry_point() {
+		let mut cartridge = Cartridge::parse(&test_rom()).unwrap();
+
+		// Branches can go backwards too
+		cartridge.entry_instruction = 0xeaff_fffe;
+		assert_eq!(cartridge.entry_point(), Some(0x0800_0000));
+
+		cartridge.entry_instruction = 0xe3a0_0000;
+		assert_eq!(cartridge.entry_point(), None);
+	}
+}
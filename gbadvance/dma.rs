@@ -0,0 +1,409 @@
+//! The four DMA channels copy memory around without the CPU's help, which games
+//! use for almost every bulk copy, as well as for streaming audio. The CPU is
+//! stalled while a transfer is running.
+
+use crate::interrupts::Interrupt;
+use crate::memory::Memory;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Source address of DMA0. Each channel has a source address, a destination
+/// address, a count and a control register, and the channels are laid out one
+/// after another.
+pub const DMA0SAD: u32 = 0x0400_00b0;
+pub const DMA0DAD: u32 = 0x0400_00b4;
+pub const DMA0CNT_L: u32 = 0x0400_00b8;
+pub const DMA0CNT_H: u32 = 0x0400_00ba;
+/// The distance between the registers of one channel and the next.
+pub const CHANNEL_SIZE: u32 = 12;
+
+// Bits of DMAxCNT_H
+const REPEAT: u16 = 1 << 9;
+const WORD: u16 = 1 << 10;
+const IRQ: u16 = 1 << 14;
+const ENABLE: u16 = 1 << 15;
+
+// Address control modes
+const INCREMENT: u16 = 0;
+const DECREMENT: u16 = 1;
+const FIXED: u16 = 2;
+const INCREMENT_RELOAD: u16 = 3;
+
+/// When a channel starts its transfer, once it has been enabled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Timing {
+	Immediate,
+	VBlank,
+	HBlank,
+	/// Feeds the sound FIFOs on DMA1 and DMA2, and captures video on DMA3.
+	/// DMA0 can't use it.
+	Special,
+}
+
+impl From<u16> for Timing {
+	fn from(control: u16) -> Self {
+		match control >> 12 & 3 {
+			0 => Timing::Immediate,
+			1 => Timing::VBlank,
+			2 => Timing::HBlank,
+			_ => Timing::Special,
+		}
+	}
+}
+
+/// The internal state of a channel. The addresses and count are copied out of
+/// the registers when the channel is enabled, and the registers can then be
+/// changed without affecting the transfer.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Channel {
+	source: u32,
+	destination: u32,
+	count: u32,
+	/// Set when the channel's start condition has been met, but the transfer
+	/// hasn't happened yet.
+	pending: bool,
+}
+
+#[derive(Default)]
+pub struct Dma {
+	pub channels: [Channel; 4],
+}
+
+impl Dma {
+	pub fn init() -> Self {
+		Self::default()
+	}
+}
+
+fn register(base: u32, channel: usize) -> u32 {
+	base + channel as u32 * CHANNEL_SIZE
+}
+
+/// If the address is the high byte of a channel's control register, which is
+/// the byte with the enable bit in it, returns the number of that channel.
+pub fn enable_byte_channel(address: u32) -> Option<usize> {
+	(0..4).find(|&channel| register(DMA0CNT_H, channel) + 1 == address)
+}
+
+/// Source addresses can only point at internal memory on DMA0, and the
+/// destination can only point at the cartridge on DMA3.
+fn address_masks(channel: usize) -> (u32, u32) {
+	match channel {
+		0 => (0x07ff_ffff, 0x07ff_ffff),
+		3 => (0x0fff_ffff, 0x0fff_ffff),
+		_ => (0x0fff_ffff, 0x07ff_ffff),
+	}
+}
+
+/// Reads the transfer count of a channel, where 0 means the largest amount.
+fn read_count(memory: &Memory, channel: usize) -> u32 {
+	let count = memory.read_half_word(register(DMA0CNT_L, channel)) as u32;
+	let max = if channel == 3 { 0x10000 } else { 0x4000 };
+
+	match count & (max - 1) {
+		0 => max,
+		count => count,
+	}
+}
+
+impl Memory {
+	fn dma_control(&self, channel: usize) -> u16 {
+		self.read_half_word(register(DMA0CNT_H, channel))
+	}
+
+	/// Called after the byte holding a channel's enable bit has been written to,
+	/// with the value that it had beforehand.
+	pub fn dma_enable_written(&mut self, channel: usize, previous: u8) {
+		let control = self.dma_control(channel);
+
+		if control & ENABLE == 0 {
+			self.dma.channels[channel].pending = false;
+			return;
+		}
+
+		// Writing to a channel that's already running doesn't restart it
+		if previous & 0x80 > 0 {
+			return;
+		}
+
+		let (source_mask, destination_mask) = address_masks(channel);
+		self.dma.channels[channel] = Channel {
+			source: self.read_word(register(DMA0SAD, channel)) & source_mask,
+			destination: self.read_word(register(DMA0DAD, channel)) & destination_mask,
+			count: read_count(self, channel),
+			pending: Timing::from(control) == Timing::Immediate,
+		};
+	}
+
+	/// Starts every enabled channel which is waiting for `timing`.
+	pub fn trigger_dma(&mut self, timing: Timing) {
+		for channel in 0..4 {
+			let control = self.dma_control(channel);
+
+			if control & ENABLE > 0 && Timing::from(control) == timing {
+				self.dma.channels[channel].pending = true;
+			}
+		}
+	}
+
+	/// Called when one of the sound FIFOs is running low, to start whichever of
+	/// DMA1 and DMA2 is set up to refill it.
+	pub fn trigger_sound_dma(&mut self, fifo: u32) {
+		for channel in 1..3 {
+			let control = self.dma_control(channel);
+
+			if control & ENABLE > 0
+				&& Timing::from(control) == Timing::Special
+				&& self.dma.channels[channel].destination == fifo
+			{
+				self.dma.channels[channel].pending = true;
+			}
+		}
+	}
+
+	/// Starts DMA3 if it is set up for video capture, which happens once per
+	/// line from line 2 to line 161.
+	pub fn trigger_video_capture(&mut self) {
+		let control = self.dma_control(3);
+
+		if control & ENABLE > 0 && Timing::from(control) == Timing::Special {
+			self.dma.channels[3].pending = true;
+		}
+	}
+
+	/// Video capture turns itself off once it reaches line 162.
+	pub fn stop_video_capture(&mut self) {
+		let control = self.dma_control(3);
+
+		if control & ENABLE > 0 && Timing::from(control) == Timing::Special {
+			self.set_io_register(register(DMA0CNT_H, 3), control & !ENABLE);
+			self.dma.channels[3].pending = false;
+		}
+	}
+
+	/// Runs the transfer of the highest priority channel that is waiting for
+	/// one, which is the one with the lowest number. Returns the number of
+	/// cycles that the CPU spent stalled, or `None` if nothing was waiting.
+	pub fn run_dma(&mut self) -> Option<u64> {
+		let channel = (0..4).find(|&channel| self.dma.channels[channel].pending)?;
+		let control = self.dma_control(channel);
+		let timing = Timing::from(control);
+		let state = self.dma.channels[channel];
+
+		// Sound FIFO transfers always move four words, and never change the
+		// destination address.
+		let fifo = timing == Timing::Special && (channel == 1 || channel == 2);
+		let (count, unit, destination_control) = if fifo {
+			(4, 4, FIXED)
+		} else {
+			let unit = if control & WORD > 0 { 4 } else { 2 };
+			(state.count, unit, control >> 5 & 3)
+		};
+
+		let step = |address_control: u16| match address_control {
+			INCREMENT | INCREMENT_RELOAD => unit,
+			DECREMENT => 0u32.wrapping_sub(unit),
+			FIXED => 0,
+			_ => unreachable!("address control is only two bits"),
+		};
+		let source_step = step(control >> 7 & 3);
+		let destination_step = step(destination_control);
+
+		// EEPROM doesn't say how big it is, but the length of the requests that
+		// games send it gives it away.
+		if self.is_eeprom_address(state.destination) {
+			self.save.eeprom_transfer(count as usize);
+		}
+
+		let mut source = state.source;
+		let mut destination = state.destination;
+		for _ in 0..count {
+			if unit == 4 {
+				let value = self.read_word(source & !3);
+				self.write_word(destination & !3, value);
+			} else {
+				let value = self.read_half_word(source & !1);
+				self.write_half_word(destination & !1, value);
+			}
+
+			source = source.wrapping_add(source_step);
+			destination = destination.wrapping_add(destination_step);
+		}
+
+		let state = &mut self.dma.channels[channel];
+		state.source = source;
+		state.destination = destination;
+		state.pending = false;
+
+		if control & REPEAT > 0 && timing != Timing::Immediate {
+			self.dma.channels[channel].count = read_count(self, channel);
+
+			if destination_control == INCREMENT_RELOAD {
+				let (_, destination_mask) = address_masks(channel);
+				self.dma.channels[channel].destination =
+					self.read_word(register(DMA0DAD, channel)) & destination_mask;
+			}
+		} else {
+			self.set_io_register(register(DMA0CNT_H, channel), control & !ENABLE);
+		}
+
+		if control & IRQ > 0 {
+			let interrupts = [
+				Interrupt::Dma0,
+				Interrupt::Dma1,
+				Interrupt::Dma2,
+				Interrupt::Dma3,
+			];
+			self.request_interrupt(interrupts[channel]);
+		}
+
+		// There's no wait state emulation yet, so each unit takes one cycle to
+		// read and one to write, with a couple of cycles to get started.
+		Some(2 + count as u64 * 2)
+	}
+}
+
+impl Snapshot for Dma {
+	fn write_state(&self, state: &mut StateWriter) {
+		for channel in &self.channels {
+			state.u32(channel.source);
+			state.u32(channel.destination);
+			state.u32(channel.count);
+			state.bool(channel.pending);
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		for channel in &mut self.channels {
+			channel.source = state.u32()?;
+			channel.destination = state.u32()?;
+			channel.count = state.u32()?;
+			channel.pending = state.bool()?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::interrupts::IF;
+	use crate::memory::{EXT_START, RAM_START};
+
+	const RAM: u32 = RAM_START as u32;
+	const EXT: u32 = EXT_START as u32;
+
+	fn set_up(memory: &mut Memory, channel: usize, source: u32, destination: u32, count: u16) {
+		memory.write_word(register(DMA0SAD, channel), source);
+		memory.write_word(register(DMA0DAD, channel), destination);
+		memory.write_half_word(register(DMA0CNT_L, channel), count);
+	}
+
+	#[test]
+	fn immediate_transfer() {
+		let mut memory = Memory::init();
+
+		for i in 0..4 {
+			memory.write_half_word(RAM + i * 2, i as u16 + 1);
+		}
+
+		set_up(&mut memory, 3, RAM, EXT, 4);
+		memory.write_half_word(DMA0CNT_H + 3 * CHANNEL_SIZE, ENABLE | IRQ);
+
+		assert_eq!(memory.run_dma(), Some(10));
+		assert_eq!(memory.read_word(EXT), 0x0002_0001);
+		assert_eq!(memory.read_word(EXT + 4), 0x0004_0003);
+		assert_eq!(memory.run_dma(), None);
+
+		// The channel turns itself off when it's done
+		assert_eq!(memory.read_half_word(DMA0CNT_H + 3 * CHANNEL_SIZE), IRQ);
+		assert_eq!(memory.read_half_word(IF), 1 << 11);
+	}
+
+	#[test]
+	fn address_control() {
+		let mut memory = Memory::init();
+
+		memory.write_word(RAM, 0x1111_1111);
+		memory.write_word(RAM + 4, 0x2222_2222);
+
+		// Decrementing source and a fixed destination, so only the last word
+		// read should be left behind
+		set_up(&mut memory, 0, RAM + 4, EXT, 2);
+		memory.write_half_word(DMA0CNT_H, ENABLE | WORD | DECREMENT << 7 | FIXED << 5);
+		memory.run_dma();
+
+		assert_eq!(memory.read_word(EXT), 0x1111_1111);
+		assert_eq!(memory.read_word(EXT + 4), 0);
+	}
+
+	#[test]
+	fn repeating_hblank_transfer() {
+		let mut memory = Memory::init();
+
+		memory.write_word(RAM, 0xaaaa_aaaa);
+		memory.write_word(RAM + 4, 0xbbbb_bbbb);
+
+		let control = ENABLE | REPEAT | WORD | 2 << 12 | INCREMENT_RELOAD << 5;
+		set_up(&mut memory, 0, RAM, EXT, 1);
+		memory.write_half_word(DMA0CNT_H, control);
+
+		// Nothing happens until H-Blank
+		assert_eq!(memory.run_dma(), None);
+
+		memory.trigger_dma(Timing::HBlank);
+		memory.run_dma();
+		assert_eq!(memory.read_word(EXT), 0xaaaa_aaaa);
+
+		// The source keeps going from where it was, but the destination reloads
+		memory.trigger_dma(Timing::HBlank);
+		memory.run_dma();
+		assert_eq!(memory.read_word(EXT), 0xbbbb_bbbb);
+		assert_eq!(memory.read_half_word(DMA0CNT_H), control);
+	}
+
+	#[test]
+	fn priority() {
+		let mut memory = Memory::init();
+
+		memory.write_word(RAM, 1);
+		memory.write_word(RAM + 4, 2);
+
+		// Both channels write to the same place, and DMA0 should go first
+		set_up(&mut memory, 0, RAM, EXT, 1);
+		set_up(&mut memory, 1, RAM + 4, EXT, 1);
+		memory.write_half_word(DMA0CNT_H + CHANNEL_SIZE, ENABLE | WORD | 1 << 12);
+		memory.write_half_word(DMA0CNT_H, ENABLE | WORD | 1 << 12);
+
+		memory.trigger_dma(Timing::VBlank);
+		memory.run_dma();
+		assert_eq!(memory.read_word(EXT), 1);
+		memory.run_dma();
+		assert_eq!(memory.read_word(EXT), 2);
+	}
+
+	#[test]
+	fn sound_fifo_transfer() {
+		let mut memory = Memory::init();
+
+		for i in 0..8 {
+			memory.write_word(RAM + i * 4, i + 1);
+		}
+
+		// The count and unit size are ignored in FIFO mode
+		set_up(&mut memory, 1, RAM, EXT, 1);
+		memory.write_half_word(DMA0CNT_H + CHANNEL_SIZE, ENABLE | REPEAT | 3 << 12);
+
+		memory.trigger_sound_dma(EXT + 4);
+		assert_eq!(memory.run_dma(), None);
+
+		memory.trigger_sound_dma(EXT);
+		assert_eq!(memory.run_dma(), Some(10));
+		assert_eq!(memory.read_word(EXT), 4);
+		assert_eq!(memory.read_word(EXT + 4), 0);
+
+		memory.trigger_sound_dma(EXT);
+		memory.run_dma();
+		assert_eq!(memory.read_word(EXT), 8);
+	}
+}
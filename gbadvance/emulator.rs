@@ -1,30 +1,50 @@
-pub mod arm;
-pub mod armv4t;
-pub mod core;
-pub mod memory;
-
 use crate::armv4t::{arm as old_arm, thumb};
-pub use arm::*;
-use core::*;
-use memory::*;
+use crate::bios::BiosMode;
+use crate::cartridge::Cartridge;
+use crate::memory::*;
+use crate::movie::Playback;
+use crate::ppu::Ppu;
+use crate::rewind::Rewind;
+use crate::save::{Save, SaveType};
+use crate::scheduler::Event;
+use lavender_armv4t::arm7tdmi::Arm7Tdmi;
+use lavender_armv4t::modes::OperationMode;
+use lavender_armv4t::registers::Reg;
 
 pub struct Emulator {
 	pub cpu: Arm7Tdmi,
 	pub memory: Memory,
+	pub ppu: Ppu,
+	/// The header of the inserted cartridge, if it has one.
+	pub cartridge: Option<Cartridge>,
 
-	/// Used to keep track of how much more the emulator should do before
-	/// updating the screen. When this reaches zero, the emulator will pause
-	/// execution until the next `requestAnimationFrame` delegation.
-	pub remaining_cycles: u32,
+	/// Set when the PPU enters V-Blank, which means that a frame is finished
+	/// and the emulator should pause until the next `requestAnimationFrame`.
+	pub frame_complete: bool,
+	/// Whether BIOS functions are run in Rust, or by the BIOS itself.
+	pub bios_mode: BiosMode,
+	/// The history of recent frames, if rewinding is switched on.
+	pub rewind: Option<Rewind>,
+	/// The movie being recorded or played back, if there is one.
+	pub movie: Option<Playback>,
 }
 
 impl Default for Emulator {
 	fn default() -> Self {
-		Self {
+		let mut emulator = Self {
 			cpu: Arm7Tdmi::init(),
 			memory: Memory::init(),
-			remaining_cycles: 0,
-		}
+			ppu: Ppu::init(),
+			cartridge: None,
+			frame_complete: false,
+			bios_mode: BiosMode::HighLevel,
+			rewind: None,
+			movie: None,
+		};
+
+		emulator.ppu.reset(&mut emulator.memory);
+		emulator.memory.start_audio();
+		emulator
 	}
 }
 
@@ -41,54 +61,258 @@ impl Emulator {
 		Self {
 			cpu: Arm7Tdmi::init(),
 			memory: Memory::init_small_no_bios(),
-			remaining_cycles: 0,
+			ppu: Ppu::init(),
+			cartridge: None,
+			frame_complete: false,
+			bios_mode: BiosMode::HighLevel,
+			rewind: None,
+			movie: None,
 		}
 	}
 
+	/// Creates an emulator for tests, with a small cartridge that has a clock
+	/// in it. It runs instructions from empty work RAM, which are all
+	/// conditional on the zero flag. That isn't set, so they do nothing and
+	/// take a single cycle each.
+	#[cfg(test)]
+	pub fn new_idle() -> Self {
+		let mut rom = vec![0; 0x200];
+		rom[0xac..0xb0].copy_from_slice(b"TEST");
+		rom[0x100..0x10b].copy_from_slice(b"SIIRTC_V001");
+
+		let mut emulator = Self::new();
+		emulator.load_rom(&rom);
+		emulator.cpu.registers.r15 = EXT_START as u32;
+		emulator
+	}
+
 	/// Insert a cartridge into the emulator.
 	pub fn load_rom(&mut self, rom: &[u8]) {
+		self.cartridge = Cartridge::parse(rom);
 		self.memory.rom = rom.to_vec();
+
+		let game_code = self
+			.cartridge
+			.as_ref()
+			.map(|cartridge| cartridge.game_code.as_str());
+		self.memory.save = Save::init(SaveType::detect(rom, game_code));
+		self.memory.peripherals.connect(rom, game_code);
+
+		if let Some(rewind) = &mut self.rewind {
+			rewind.clear();
+		}
 	}
 
-	/// Step forward by one frame (or about 280 thousand cycles)
+	/// Step forward until the PPU enters V-Blank, which is when a game will
+	/// have finished drawing its frame.
 	pub fn step_frame(&mut self) {
-		// 16.78MHz CPU clock speed / 60Hz display refresh rate = 279,666 CPU cycles
-		self.remaining_cycles += 279_666;
+		self.frame_complete = false;
+		self.movie_frame_started();
+		let keys = self.memory.pressed_keys();
 
-		while self.remaining_cycles > 0 {
+		// Nothing runs during STOP, so the frame would never finish
+		while !self.frame_complete && !self.memory.stopped {
 			self.step_instruction();
 		}
+
+		self.memory.apu.flush_samples();
+		self.movie_frame_finished(keys);
+
+		if let Some(mut rewind) = self.rewind.take() {
+			rewind.frame_finished(self);
+			self.rewind = Some(rewind);
+		}
 	}
 
 	/// Step forward by one instruction
 	pub fn step_instruction(&mut self) {
 		use Reg::*;
 
-		if self.cpu.registers.get_thumb_bit() {
+		// Read the instruction and increment the PC before running the
+		// instruction so that we don't do anything weird if the instruction
+		// changes the value of r15.
+		let cycles_used = if self.memory.halted {
+			// Nothing happens until an interrupt is requested, so skip straight
+			// to the next thing the hardware does
+			let now = self.memory.scheduler.now;
+			self.memory
+				.scheduler
+				.next_event_time()
+				.map_or(1, |time| time.saturating_sub(now).max(1)) as u32
+		} else if self.cpu.registers.get_thumb_bit() {
+			let instruction = self.memory.read_half_word(self.cpu.registers.r15);
 			self.cpu.registers.map_value(r15, |v| v + 2);
+
+			thumb::process_instruction(self, instruction)
 		} else {
-			// Read the instruction and increment the PC before running the
-			// instruction so that we don't do anything weird if the instruction
-			// changes the value of r15.
 			let instruction = self.memory.read_word(self.cpu.registers.r15);
 			self.cpu.registers.map_value(r15, |v| v + 4);
 
-			let cycles_used = old_arm::process_instruction(self, instruction);
-			// let cycles_used = old_arm::process_instruction(self, 0b1110_00_1_0100_1_0011_0011_0000_00000001);
-			self.remaining_cycles = self.remaining_cycles.saturating_sub(cycles_used);
+			old_arm::process_instruction(self, instruction)
+		};
+
+		self.memory.scheduler.advance(cycles_used as u64);
+		self.handle_events();
+
+		// The CPU is stalled while DMA is running, but the rest of the hardware
+		// keeps going, and might start even more transfers.
+		while let Some(cycles) = self.memory.run_dma() {
+			self.memory.scheduler.advance(cycles);
+			self.handle_events();
+		}
+
+		// Halt ends when an enabled interrupt is requested, even if IME is off
+		if self.memory.halted && self.memory.has_pending_interrupt() {
+			self.memory.halted = false;
+		}
+
+		if self.memory.should_interrupt() && !self.cpu.registers.is_irq_disabled() {
+			self.enter_interrupt();
 		}
 	}
+
+	/// Lets the rest of the hardware catch up with the CPU.
+	fn handle_events(&mut self) {
+		while let Some((time, event)) = self.memory.scheduler.pop_due() {
+			match event {
+				Event::HBlank => self.ppu.hblank(&mut self.memory, time),
+				Event::LineEnd => {
+					if self.ppu.end_line(&mut self.memory, time) {
+						self.frame_complete = true;
+					}
+				}
+				Event::TimerOverflow(timer) => self.memory.timer_overflow(timer, time),
+				Event::AudioSample => self.memory.audio_sample(time),
+				Event::FrameSequencer => self.memory.frame_sequencer(time),
+			}
+		}
+	}
+
+	/// Switches the CPU into IRQ mode and jumps to the interrupt vector, where
+	/// the BIOS takes care of calling the game's own handler.
+	fn enter_interrupt(&mut self) {
+		use Reg::*;
+
+		let registers = &mut self.cpu.registers;
+		let status = registers.cpsr;
+		// r15 already points at the next instruction, and the handler returns
+		// with `subs pc, lr, #4`.
+		let return_address = registers.r15 + 4;
+
+		registers.set_operation_mode(OperationMode::IRQ);
+		registers.set_value(spsr, status);
+		registers.set_value(r14, return_address);
+		registers.set_irq_disable(true);
+		registers.set_thumb_bit(false);
+		registers.set_value(r15, 0x18);
+	}
 }
 
-impl Emulator {
-	pub fn test(&mut self) {
-		// Set display mode to bitmap
-		self.memory.write_half_word(0x0400_0000, 0x0403);
-		let point = |x, y: u32| 0x0600_0000 + (x + y * 240) * 2;
-
-		// Write a few test pixels into vram
-		self.memory.write_half_word(point(120, 80), 0x03ff);
-		self.memory.write_half_word(point(136, 80), 0x7c16);
-		self.memory.write_half_word(point(120, 96), 0x4fe3);
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::interrupts::{IE, IF, IME};
+	use crate::ppu::{DISPSTAT, LINE_CYCLES, SCREEN_HEIGHT, VCOUNT};
+
+	#[test]
+	fn step_frame_runs_until_vblank() {
+		let mut emulator = Emulator::new_idle();
+
+		emulator.step_frame();
+		assert_eq!(emulator.memory.read_half_word(VCOUNT), SCREEN_HEIGHT as u16);
+		assert_eq!(emulator.memory.read_half_word(DISPSTAT) & 1, 1);
+		assert_eq!(
+			emulator.memory.scheduler.now,
+			SCREEN_HEIGHT as u64 * LINE_CYCLES
+		);
+
+		// The next frame should end exactly one frame later
+		emulator.step_frame();
+		assert_eq!(
+			emulator.memory.scheduler.now,
+			(SCREEN_HEIGHT as u64 + 228) * LINE_CYCLES
+		);
+	}
+
+	#[test]
+	fn vblank_interrupt() {
+		let mut emulator = Emulator::new_idle();
+
+		emulator.memory.write_half_word(DISPSTAT, 1 << 3);
+		emulator.memory.write_half_word(IE, 1);
+		emulator.memory.write_half_word(IME, 1);
+		emulator.cpu.registers.set_irq_disable(false);
+		let status = emulator.cpu.registers.cpsr;
+
+		emulator.step_frame();
+		assert_eq!(emulator.memory.read_half_word(IF), 1);
+
+		let registers = &emulator.cpu.registers;
+		assert_eq!(OperationMode::from(registers), OperationMode::IRQ);
+		assert_eq!(registers.r15, 0x18);
+		assert_eq!(registers.spsr_irq, status);
+		assert!(registers.is_irq_disabled());
+	}
+
+	#[test]
+	fn dma_stalls_cpu() {
+		use crate::dma::{CHANNEL_SIZE, DMA0CNT_H, DMA0CNT_L, DMA0DAD, DMA0SAD};
+
+		let mut emulator = Emulator::new_idle();
+		let dma3 = 3 * CHANNEL_SIZE;
+
+		emulator.memory.write_word(DMA0SAD + dma3, RAM_START as u32);
+		emulator
+			.memory
+			.write_word(DMA0DAD + dma3, RAM_START as u32 + 0x100);
+		emulator.memory.write_half_word(DMA0CNT_L + dma3, 16);
+		emulator.memory.write_half_word(DMA0CNT_H + dma3, 0x8000);
+
+		// One cycle for the instruction, and then the whole transfer
+		emulator.step_instruction();
+		assert_eq!(emulator.memory.scheduler.now, 1 + 2 + 16 * 2);
+	}
+
+	#[test]
+	fn halt_until_interrupt() {
+		use crate::interrupts::HALTCNT;
+
+		let mut emulator = Emulator::new_idle();
+		emulator.memory.write_half_word(DISPSTAT, 1 << 3);
+		emulator.memory.write_half_word(IE, 1);
+		emulator.memory.write_byte(HALTCNT, 0);
+
+		// The CPU wakes up at V-Blank, even though IME is off
+		let mut steps = 0u64;
+		while emulator.memory.halted {
+			emulator.step_instruction();
+			steps += 1;
+		}
+		assert_eq!(
+			emulator.memory.scheduler.now,
+			SCREEN_HEIGHT as u64 * LINE_CYCLES
+		);
+		// Each step skips ahead to the next event, rather than a cycle at a time
+		assert!(steps < emulator.memory.scheduler.now / 100);
+	}
+
+	#[test]
+	fn stop_until_keypad_interrupt() {
+		use crate::interrupts::HALTCNT;
+		use crate::keypad::{Key, KEYCNT};
+
+		let mut emulator = Emulator::new_idle();
+		emulator.memory.write_half_word(IE, 1 << 12);
+		emulator
+			.memory
+			.write_half_word(KEYCNT, 1 << 14 | Key::Start.mask());
+		emulator.memory.write_byte(HALTCNT, 0x80);
+
+		emulator.step_frame();
+		assert_eq!(emulator.memory.scheduler.now, 0);
+
+		emulator.memory.set_keys(Key::Start.mask());
+		emulator.step_frame();
+		assert_eq!(emulator.memory.read_half_word(VCOUNT), SCREEN_HEIGHT as u16);
 	}
 }
@@ -0,0 +1,94 @@
+//! Interrupt requests from the rest of the hardware. Each source sets its bit
+//! in IF, and the CPU takes the interrupt if the same bit is set in IE, and IME
+//! is enabled.
+
+use crate::memory::Memory;
+
+/// Interrupt enable
+pub const IE: u32 = 0x0400_0200;
+/// Interrupt request flags. Writing a 1 to a bit acknowledges the interrupt and
+/// clears it.
+pub const IF: u32 = 0x0400_0202;
+/// Interrupt master enable
+pub const IME: u32 = 0x0400_0208;
+/// Writing to this register puts the console into a low power state until an
+/// interrupt arrives. Setting the top bit picks STOP rather than halt.
+pub const HALTCNT: u32 = 0x0400_0301;
+
+/// The only interrupts that can wake the console from STOP, since everything
+/// else is switched off.
+const STOP_WAKE: [Interrupt; 3] = [Interrupt::Serial, Interrupt::Keypad, Interrupt::GamePak];
+
+/// All of the sources of interrupts, numbered by their bit in IE and IF.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interrupt {
+	VBlank = 0,
+	HBlank = 1,
+	VCount = 2,
+	Timer0 = 3,
+	Timer1 = 4,
+	Timer2 = 5,
+	Timer3 = 6,
+	Serial = 7,
+	Dma0 = 8,
+	Dma1 = 9,
+	Dma2 = 10,
+	Dma3 = 11,
+	Keypad = 12,
+	GamePak = 13,
+}
+
+impl Memory {
+	/// Sets the request flag for an interrupt.
+	pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+		let flags = self.read_half_word(IF) | 1 << interrupt as u16;
+		self.set_io_register(IF, flags);
+
+		if self.stopped
+			&& STOP_WAKE.contains(&interrupt)
+			&& self.read_half_word(IE) & 1 << interrupt as u16 > 0
+		{
+			self.stopped = false;
+		}
+	}
+
+	/// Checks if there are any interrupts that are both requested and enabled.
+	/// This doesn't check IME or the CPU's own IRQ disable bit, since enabled
+	/// interrupts still wake the CPU up from halt without those.
+	pub fn has_pending_interrupt(&self) -> bool {
+		self.read_half_word(IE) & self.read_half_word(IF) & 0x3fff > 0
+	}
+
+	/// Checks if an interrupt should actually be taken by the CPU, if it isn't
+	/// ignoring them.
+	pub fn should_interrupt(&self) -> bool {
+		self.read_half_word(IME) & 1 > 0 && self.has_pending_interrupt()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn request_and_acknowledge() {
+		let mut memory = Memory::init();
+
+		memory.request_interrupt(Interrupt::VBlank);
+		memory.request_interrupt(Interrupt::Timer1);
+		assert_eq!(memory.read_half_word(IF), 0b1_0001);
+		assert!(!memory.has_pending_interrupt());
+
+		memory.write_half_word(IE, 0b1_0000);
+		assert!(memory.has_pending_interrupt());
+		assert!(!memory.should_interrupt());
+
+		memory.write_half_word(IME, 1);
+		assert!(memory.should_interrupt());
+
+		// Writing a 1 to a bit of IF clears it, and writing a 0 does nothing
+		memory.write_half_word(IF, 0b1_0000);
+		assert_eq!(memory.read_half_word(IF), 0b0_0001);
+		assert!(!memory.should_interrupt());
+	}
+}
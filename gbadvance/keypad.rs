@@ -0,0 +1,130 @@
+//! The buttons on the front of the console. Games either poll KEYINPUT, or ask
+//! for an interrupt when a particular combination of buttons is pressed, which
+//! is also one of the few ways to wake the console back up from STOP.
+
+use crate::interrupts::Interrupt;
+use crate::memory::Memory;
+
+/// The state of each button, with a bit cleared while the button is held down.
+/// It can't be written to by the CPU.
+pub const KEYINPUT: u32 = 0x0400_0130;
+/// Chooses which buttons can trigger the keypad interrupt, and whether it
+/// happens when any of them are pressed or only when all of them are.
+pub const KEYCNT: u32 = 0x0400_0132;
+
+/// Every button has a bit in KEYINPUT and KEYCNT.
+const ALL_KEYS: u16 = 0x3ff;
+
+// Bits of KEYCNT
+const IRQ: u16 = 1 << 14;
+const ALL_PRESSED: u16 = 1 << 15;
+
+/// The buttons, numbered by their bit in KEYINPUT.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Key {
+	A = 0,
+	B = 1,
+	Select = 2,
+	Start = 3,
+	Right = 4,
+	Left = 5,
+	Up = 6,
+	Down = 7,
+	R = 8,
+	L = 9,
+}
+
+impl Key {
+	/// The bit for this button in a mask of pressed buttons.
+	pub fn mask(self) -> u16 {
+		1 << self as u16
+	}
+}
+
+impl Memory {
+	/// Returns a mask of the buttons that are currently pressed, with a bit set
+	/// for each one.
+	pub fn pressed_keys(&self) -> u16 {
+		!self.read_half_word(KEYINPUT) & ALL_KEYS
+	}
+
+	/// Updates the buttons that are being held down, from a mask with a bit set
+	/// for each pressed button.
+	pub fn set_keys(&mut self, pressed: u16) {
+		self.set_io_register(KEYINPUT, !pressed & ALL_KEYS);
+		self.check_keypad_interrupt();
+	}
+
+	/// Requests the keypad interrupt if it is enabled, and the buttons selected
+	/// in KEYCNT are pressed. This is checked again whenever either changes.
+	pub fn check_keypad_interrupt(&mut self) {
+		let control = self.read_half_word(KEYCNT);
+		let selected = control & ALL_KEYS;
+		let pressed = self.pressed_keys() & selected;
+
+		let triggered = if control & ALL_PRESSED > 0 {
+			pressed == selected
+		} else {
+			pressed > 0
+		};
+
+		if control & IRQ > 0 && selected > 0 && triggered {
+			self.request_interrupt(Interrupt::Keypad);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::interrupts::{HALTCNT, IE, IF};
+
+	#[test]
+	fn keyinput() {
+		let mut memory = Memory::init();
+		assert_eq!(memory.read_half_word(KEYINPUT), 0x3ff);
+
+		memory.set_keys(Key::A.mask() | Key::Up.mask());
+		assert_eq!(memory.read_half_word(KEYINPUT), 0x3ff & !0b100_0001);
+		assert_eq!(memory.pressed_keys(), 0b100_0001);
+
+		// The CPU can't change which buttons are pressed
+		memory.write_half_word(KEYINPUT, 0x3ff);
+		assert_eq!(memory.pressed_keys(), 0b100_0001);
+	}
+
+	#[test]
+	fn interrupt_conditions() {
+		let mut memory = Memory::init();
+		let start_select = Key::Start.mask() | Key::Select.mask();
+
+		// Any of the selected buttons
+		memory.write_half_word(KEYCNT, IRQ | start_select);
+		memory.set_keys(Key::A.mask());
+		assert_eq!(memory.read_half_word(IF), 0);
+		memory.set_keys(Key::Start.mask());
+		assert_eq!(memory.read_half_word(IF), 1 << 12);
+
+		// All of the selected buttons
+		memory.write_half_word(IF, 1 << 12);
+		memory.write_half_word(KEYCNT, IRQ | ALL_PRESSED | start_select);
+		assert_eq!(memory.read_half_word(IF), 0);
+		memory.set_keys(start_select);
+		assert_eq!(memory.read_half_word(IF), 1 << 12);
+	}
+
+	#[test]
+	fn wakes_from_stop() {
+		let mut memory = Memory::init();
+		memory.write_half_word(IE, 1 << 12);
+		memory.write_half_word(KEYCNT, IRQ | Key::A.mask());
+
+		memory.write_byte(HALTCNT, 0x80);
+		assert!(memory.stopped);
+
+		memory.set_keys(Key::B.mask());
+		assert!(memory.stopped);
+		memory.set_keys(Key::A.mask());
+		assert!(!memory.stopped);
+	}
+}
@@ -1,3 +1,15 @@
+use crate::apu::psg::{SOUND1CNT_L, WAVE_RAM};
+use crate::apu::{Apu, FIFO_A, FIFO_B, SOUNDCNT_H, SOUNDCNT_X};
+use crate::bios::BiosVersion;
+use crate::dma::{self, Dma};
+use crate::interrupts::{HALTCNT, IF};
+use crate::keypad::{KEYCNT, KEYINPUT};
+use crate::peripherals::Peripherals;
+use crate::ppu::{BG2X, BG3X, DISPSTAT, VCOUNT};
+use crate::save::{Save, SaveType};
+use crate::scheduler::Scheduler;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use crate::timers::{self, Timers};
 use std::convert::TryInto;
 
 pub const BIOS_SIZE: usize = 16 * 1024;
@@ -44,6 +56,8 @@ pub struct Memory {
 	/// Stores the BIOS of the Game Boy Advance, which is home to the software
 	/// interupt table and some useful methods that there are not instructions for.
 	pub bios: Box<[u8; BIOS_SIZE]>,
+	/// Which official BIOS has been loaded in place of the built-in one, if any.
+	pub bios_version: Option<BiosVersion>,
 	/// Links to the RAM made available by the external cartiridge.
 	pub ext: Vec<u8>,
 	/// Links to the RAM that is embedded into the CPU.
@@ -61,10 +75,33 @@ pub struct Memory {
 	pub object: Vec<u8>,
 	/// The ROM memory of the currently inserted cartridge.
 	pub rom: Vec<u8>,
-	/// The writtable save memory inside of the cartridge is mappen here. The
-	/// contents of this memory are copied out exactly as is when creating a
-	/// save state.
-	pub save: Vec<u8>,
+	/// The battery backed save memory inside of the cartridge. Most kinds are
+	/// mapped at 0x0e000000, but EEPROM is accessed through the top of the ROM
+	/// region instead.
+	pub save: Save,
+	/// Extra hardware in the cartridge, like a clock or sensors, and the GPIO
+	/// port that most of it is wired up to.
+	pub peripherals: Peripherals,
+
+	/// Keeps track of upcoming events for the hardware attached to the bus. It
+	/// lives here so that writes to IO registers are able to schedule things.
+	pub scheduler: Scheduler,
+	/// Set when the reference point registers of BG2 or BG3 are written to. The
+	/// PPU copies the new values into its internal registers before drawing the
+	/// next line.
+	pub affine_reference_written: [bool; 2],
+	/// The internal state of the DMA channels.
+	pub dma: Dma,
+	/// The internal state of the timers.
+	pub timers: Timers,
+	/// The sound channels, and the samples that they have produced.
+	pub apu: Apu,
+	/// Set while the console is in STOP, when nothing runs until a keypad,
+	/// serial or cartridge interrupt wakes it up.
+	pub stopped: bool,
+	/// Set while the CPU is halted, waiting for an enabled interrupt to be
+	/// requested. The rest of the hardware keeps running.
+	pub halted: bool,
 }
 
 impl Memory {
@@ -72,6 +109,7 @@ impl Memory {
 		let mut memory = Self {
 			// TODO: Try to switch to `box [0; BIOS_SIZE]` etc. eventually
 			bios: Box::new([0; BIOS_SIZE]),
+			bios_version: None,
 			ext: vec![0; EXT_SIZE],
 			ram: vec![0; RAM_SIZE],
 			io: vec![0; IO_SIZE],
@@ -79,9 +117,20 @@ impl Memory {
 			vram: vec![0; VRAM_SIZE],
 			object: vec![0; OBJECT_ATTRIBUTE_SIZE],
 			rom: vec![0; 1],
-			save: vec![0; SAVE_SIZE],
+			save: Save::init(SaveType::Sram),
+			peripherals: Peripherals::init(),
+			scheduler: Scheduler::init(),
+			affine_reference_written: [false; 2],
+			dma: Dma::init(),
+			timers: Timers::init(),
+			apu: Apu::init(),
+			stopped: false,
+			halted: false,
 		};
 
+		// No buttons are pressed to begin with
+		memory.set_io_register(KEYINPUT, 0x3ff);
+
 		// Copy the BIOS into memory
 		for (index, byte) in BIOS.iter().enumerate() {
 			memory.bios[index] = *byte;
@@ -93,6 +142,7 @@ impl Memory {
 	pub fn init_small_no_bios() -> Self {
 		Self {
 			bios: Box::new([0; BIOS_SIZE]),
+			bios_version: None,
 			ext: vec![0; 32],
 			ram: vec![0; 32],
 			io: vec![0; 32],
@@ -100,7 +150,15 @@ impl Memory {
 			vram: vec![0; 32],
 			object: vec![0; 32],
 			rom: vec![0; 1],
-			save: vec![0; 32],
+			save: Save::init(SaveType::Sram),
+			peripherals: Peripherals::init(),
+			scheduler: Scheduler::init(),
+			affine_reference_written: [false; 2],
+			dma: Dma::init(),
+			timers: Timers::init(),
+			apu: Apu::init(),
+			stopped: false,
+			halted: false,
 		}
 	}
 
@@ -108,7 +166,7 @@ impl Memory {
 		let i = address as usize;
 
 		match i {
-			BIOS_START..=BIOS_END => Some((&self.bios, i)),
+			BIOS_START..=BIOS_END => Some((&self.bios[..], i)),
 			EXT_START..=EXT_END => Some((&self.ext, i - EXT_START)),
 			RAM_START..=RAM_END => Some((&self.ram, i - RAM_START)),
 			IO_START..=IO_END => Some((&self.io, i - IO_START)),
@@ -120,22 +178,49 @@ impl Memory {
 			ROM_START..=ROM_END => Some((&self.rom, i - ROM_START)),
 			ROM_WAIT1_START..=ROM_WAIT1_END => Some((&self.rom, i - ROM_WAIT1_START)),
 			ROM_WAIT2_START..=ROM_WAIT2_END => Some((&self.rom, i - ROM_WAIT2_START)),
-			SAVE_START..=SAVE_END => Some((&self.save, i - SAVE_START)),
 			_ => None,
 		}
 	}
 
+	/// Returns `length` bytes of a region of memory, if they're all there.
+	fn mapped_bytes(&self, address: u32, length: usize) -> Option<&[u8]> {
+		let (mem, offset) = self.get_mapped_segment_and_real_offset(address)?;
+		mem.get(offset..offset + length)
+	}
+
 	pub fn read_word(&self, address: u32) -> u32 {
 		// assert_eq!(address % 4, 0);
 
-		if let Some((mem, offset)) = self.get_mapped_segment_and_real_offset(address) {
-			u32::from_le_bytes(
-				mem[offset..offset + 4]
+		// Some IO registers read differently from what was written to them
+		if is_io(address) {
+			return u32::from_le_bytes([0, 1, 2, 3].map(|i| self.read_byte(address + i)));
+		}
+
+		// The save region only has an 8-bit bus, so the same byte shows up in
+		// every lane.
+		if is_save(address) {
+			return self.read_byte(address) as u32 * 0x0101_0101;
+		}
+
+		// The EEPROM only has a 16-bit bus, so a word read takes two bits from it
+		if self.is_eeprom_address(address) {
+			let low = self.save.read_eeprom() as u32;
+			return low | (self.save.read_eeprom() as u32) << 16;
+		}
+
+		if self.is_gpio_readable(address) {
+			return u32::from_le_bytes([0, 1, 2, 3].map(|i| self.read_byte(address + i)));
+		}
+
+		// Reads that run off the end of a region, like past the end of a small
+		// ROM, are put together from single bytes instead
+		match self.mapped_bytes(address, 4) {
+			Some(bytes) => u32::from_le_bytes(
+				bytes
 					.try_into()
 					.expect("4 bytes should properly form a u32"),
-			)
-		} else {
-			0
+			),
+			None => u32::from_le_bytes([0, 1, 2, 3].map(|i| self.read_byte(address + i))),
 		}
 	}
 
@@ -150,14 +235,29 @@ impl Memory {
 	pub fn read_half_word(&self, address: u32) -> u16 {
 		assert_eq!(address % 2, 0);
 
-		if let Some((mem, offset)) = self.get_mapped_segment_and_real_offset(address) {
-			u16::from_le_bytes(
-				mem[offset..offset + 2]
+		if is_io(address) {
+			return u16::from_le_bytes([self.read_byte(address), self.read_byte(address + 1)]);
+		}
+
+		if is_save(address) {
+			return self.read_byte(address) as u16 * 0x0101;
+		}
+
+		if self.is_eeprom_address(address) {
+			return self.save.read_eeprom();
+		}
+
+		if self.is_gpio_readable(address) {
+			return u16::from_le_bytes([self.read_byte(address), self.read_byte(address + 1)]);
+		}
+
+		match self.mapped_bytes(address, 2) {
+			Some(bytes) => u16::from_le_bytes(
+				bytes
 					.try_into()
 					.expect("2 bytes should properly form a u16"),
-			)
-		} else {
-			0
+			),
+			None => u16::from_le_bytes([self.read_byte(address), self.read_byte(address + 1)]),
 		}
 	}
 
@@ -176,20 +276,34 @@ impl Memory {
 			BIOS_START..=BIOS_END => self.bios[i],
 			EXT_START..=EXT_END => self.ext[i - EXT_START],
 			RAM_START..=RAM_END => self.ram[i - RAM_START],
-			IO_START..=IO_END => self.io[i - IO_START],
+			IO_START..=IO_END => self.read_io(i - IO_START),
 			PALETTE_START..=PALETTE_END => self.palette[i - PALETTE_START],
 			VRAM_START..=VRAM_END => self.vram[i - VRAM_START],
 			OBJECT_ATTRIBUTE_START..=OBJECT_ATTRIBUTE_END => {
 				self.object[i - OBJECT_ATTRIBUTE_START]
 			}
-			ROM_START..=ROM_END => self.rom[i - ROM_START],
-			ROM_WAIT1_START..=ROM_WAIT1_END => self.rom[i - ROM_WAIT1_START],
-			ROM_WAIT2_START..=ROM_WAIT2_END => self.rom[i - ROM_WAIT2_START],
-			SAVE_START..=SAVE_END => self.save[i - SAVE_START],
+			_ if self.is_gpio_readable(address) => self.read_gpio(address),
+			ROM_START..=ROM_END => self.read_rom(i - ROM_START),
+			ROM_WAIT1_START..=ROM_WAIT1_END => self.read_rom(i - ROM_WAIT1_START),
+			ROM_WAIT2_START..=ROM_WAIT2_END => self.read_rom(i - ROM_WAIT2_START),
+			SAVE_START..=SAVE_END => self
+				.peripherals
+				.read_bus(address)
+				.unwrap_or_else(|| self.save.read(i - SAVE_START)),
 			_ => 0,
 		}
 	}
 
+	/// Nothing drives the cartridge bus past the end of the ROM, so it still
+	/// holds the address that was asked for, and each half word reads back as
+	/// the low bits of its own address.
+	fn read_rom(&self, offset: usize) -> u8 {
+		match self.rom.get(offset) {
+			Some(&byte) => byte,
+			None => ((offset >> 1) as u16).to_le_bytes()[offset & 1],
+		}
+	}
+
 	pub fn write_byte(&mut self, address: u32, value: u8) {
 		let i = address as usize;
 
@@ -197,19 +311,142 @@ impl Memory {
 			// Note that BIOS is intentionally missing.
 			EXT_START..=EXT_END => self.ext[i - EXT_START] = value,
 			RAM_START..=RAM_END => self.ram[i - RAM_START] = value,
-			IO_START..=IO_END => self.io[i - IO_START] = value,
+			IO_START..=IO_END => self.write_io(i - IO_START, value),
 			PALETTE_START..=PALETTE_END => self.palette[i - PALETTE_START] = value,
 			VRAM_START..=VRAM_END => self.vram[i - VRAM_START] = value,
 			OBJECT_ATTRIBUTE_START..=OBJECT_ATTRIBUTE_END => {
 				self.object[i - OBJECT_ATTRIBUTE_START] = value
 			}
-			ROM_START..=ROM_END => self.rom[i - ROM_START] = value,
-			ROM_WAIT1_START..=ROM_WAIT1_END => self.rom[i - ROM_WAIT1_START] = value,
-			ROM_WAIT2_START..=ROM_WAIT2_END => self.rom[i - ROM_WAIT2_START] = value,
-			SAVE_START..=SAVE_END => self.save[i - SAVE_START] = value,
+			_ if self.is_gpio_writable(address) => self.write_gpio(address, value),
+			// Each half word written to EEPROM sends it a single bit. The rest of
+			// the ROM can't be written to.
+			ROM_WAIT2_START..=ROM_WAIT2_END if self.is_eeprom_address(address) && i & 1 == 0 => {
+				self.save.write_eeprom(value as u16)
+			}
+			// Peripherals like the tilt sensor can share the region with the save
+			SAVE_START..=SAVE_END if self.peripherals.write_bus(address, value) => (),
+			SAVE_START..=SAVE_END => self.save.write(i - SAVE_START, value),
 			_ => (),
 		};
 	}
+
+	/// Sets the value of a 16-bit IO register directly, including any bits
+	/// which can't be written to by the CPU. Used by the hardware to update its
+	/// own registers.
+	pub fn set_io_register(&mut self, address: u32, value: u16) {
+		let offset = address as usize - IO_START;
+		self.io[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+	}
+
+	/// Handles reads from IO registers, some of which don't just return what was
+	/// last written to them.
+	fn read_io(&self, offset: usize) -> u8 {
+		let address = (IO_START + offset) as u32;
+
+		match address {
+			SOUNDCNT_X => self.io[offset] & 0xf0 | self.psg_status(),
+			_ if (WAVE_RAM..WAVE_RAM + 16).contains(&address) => self.read_wave_ram(address),
+			_ => match timers::counter_timer(address) {
+				Some(timer) => self.timer_counter(timer).to_le_bytes()[offset & 1],
+				None => self.io[offset],
+			},
+		}
+	}
+
+	/// Handles writes to IO registers, some of which have side effects or
+	/// contain bits which can't be written to.
+	fn write_io(&mut self, offset: usize, value: u8) {
+		let address = (IO_START + offset) as u32;
+
+		match address {
+			// The status flags in the low bits of DISPSTAT are read only
+			DISPSTAT => self.io[offset] = self.io[offset] & 0b111 | value & !0b111,
+			_ if address & !1 == VCOUNT => (),
+			_ if address & !1 == KEYINPUT => (),
+			HALTCNT if value & 0x80 > 0 => self.stopped = true,
+			HALTCNT => self.halted = true,
+			// Writing a 1 to an interrupt flag acknowledges it
+			_ if address & !1 == IF => self.io[offset] &= !value,
+			// The FIFOs are write only, and each byte written is a new sample
+			_ if (FIFO_A..FIFO_B + 4).contains(&address) => {
+				self.fifo_written(((address - FIFO_A) / 4) as usize, value)
+			}
+			_ if (WAVE_RAM..WAVE_RAM + 16).contains(&address) => {
+				self.write_wave_ram(address, value)
+			}
+			_ => {
+				// Covers both the X and Y registers of each background
+				if address & !7 == BG2X {
+					self.affine_reference_written[0] = true;
+				} else if address & !7 == BG3X {
+					self.affine_reference_written[1] = true;
+				}
+
+				let previous = self.io[offset];
+				self.io[offset] = value;
+
+				if let Some(channel) = dma::enable_byte_channel(address) {
+					self.dma_enable_written(channel, previous);
+				} else if let Some(timer) = timers::control_timer(address) {
+					self.timer_control_written(timer, previous);
+				} else if address == KEYCNT + 1 {
+					self.check_keypad_interrupt();
+				} else if address == SOUNDCNT_H + 1 {
+					self.sound_control_written();
+				} else if (SOUND1CNT_L..=SOUNDCNT_X).contains(&address) {
+					self.psg_written(address, value);
+				}
+			}
+		}
+	}
+}
+
+/// Only the memory itself is written here. The hardware that lives alongside it
+/// in `Memory` gets its own sections in the save state.
+impl Snapshot for Memory {
+	fn write_state(&self, state: &mut StateWriter) {
+		for region in [
+			&self.ext,
+			&self.ram,
+			&self.io,
+			&self.palette,
+			&self.vram,
+			&self.object,
+		] {
+			state.bytes(region);
+		}
+
+		state.bool(self.affine_reference_written[0]);
+		state.bool(self.affine_reference_written[1]);
+		state.bool(self.stopped);
+		state.bool(self.halted);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		for region in [
+			&mut self.ext,
+			&mut self.ram,
+			&mut self.io,
+			&mut self.palette,
+			&mut self.vram,
+			&mut self.object,
+		] {
+			state.fill(region)?;
+		}
+
+		self.affine_reference_written = [state.bool()?, state.bool()?];
+		self.stopped = state.bool()?;
+		self.halted = state.bool()?;
+		Ok(())
+	}
+}
+
+fn is_io(address: u32) -> bool {
+	(IO_START..=IO_END).contains(&(address as usize))
+}
+
+fn is_save(address: u32) -> bool {
+	(SAVE_START..=SAVE_END).contains(&(address as usize))
 }
 
 pub static BIOS: [u8; 548] = [
@@ -265,6 +502,33 @@ mod tests {
 		assert_eq!(memory.read_word(0), 0xea000006);
 	}
 
+	#[test]
+	fn cant_write_to_rom() {
+		let mut memory = Memory::init();
+		memory.rom = vec![0x12, 0x34, 0x56, 0x78];
+
+		for mirror in [ROM_START, ROM_WAIT1_START, ROM_WAIT2_START] {
+			memory.write_word(mirror as u32, 0xdeadbeef);
+			// Past the end of the ROM too
+			memory.write_word(mirror as u32 + 0x100, 0xdeadbeef);
+		}
+		assert_eq!(memory.rom, [0x12, 0x34, 0x56, 0x78]);
+	}
+
+	#[test]
+	fn read_past_the_end_of_the_rom() {
+		let mut memory = Memory::init();
+		memory.rom = vec![0x12, 0x34, 0x56, 0x78];
+
+		assert_eq!(memory.read_word(ROM_START as u32), 0x7856_3412);
+		assert_eq!(memory.read_half_word(ROM_START as u32 + 0x100), 0x80);
+		assert_eq!(
+			memory.read_word(ROM_WAIT1_START as u32 + 0x100),
+			0x0081_0080
+		);
+		assert_eq!(memory.read_byte(ROM_END as u32), 0xff);
+	}
+
 	#[test]
 	fn write_to_ram() {
 		let mut memory = Memory::init();
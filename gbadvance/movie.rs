@@ -0,0 +1,469 @@
+//! Movies record the buttons that were held down on every frame, so that a play
+//! session can be replayed exactly, such as to reproduce a bug. The emulator
+//! always does the same thing given the same starting state and the same
+//! inputs, so the buttons are all that needs to be kept. The cartridge clock is
+//! the only other input that games normally see, so while a movie is recording
+//! or playing, the clock starts from a fixed time and follows the emulated time
+//! rather than the host's.
+//!
+//! A movie doesn't contain the state that it started from. Instead, it keeps a
+//! hash of the save data and of the whole emulator when recording started, and
+//! the same game has to be booted the same way, with the same save, before it
+//! can be played back. A hash of the emulator is also kept after every frame,
+//! so that playback can spot the first frame where it stops matching.
+
+use crate::emulator::Emulator;
+use crate::peripherals::rtc::FixedClock;
+use crate::ppu::{LINE_CYCLES, TOTAL_LINES};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Every movie starts with this.
+pub const MAGIC: [u8; 4] = *b"LVMV";
+/// The version of the layout that is written.
+pub const VERSION: u32 = 1;
+
+/// The CPU runs at 16.78 MHz.
+const CYCLES_PER_SECOND: u64 = 1 << 24;
+const CYCLES_PER_FRAME: u64 = LINE_CYCLES * TOTAL_LINES as u64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+	/// The time that the cartridge clock started at, in seconds since the start
+	/// of 1970.
+	pub clock_seed: i64,
+	/// A hash of the emulator's state when recording started.
+	pub start_hash: u64,
+	/// A hash of the cartridge's save data when recording started.
+	pub save_hash: u64,
+	pub frames: Vec<MovieFrame>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MovieFrame {
+	/// The buttons that were held down during the frame, in the same order as
+	/// KEYINPUT.
+	pub keys: u16,
+	/// A hash of the emulator's state at the end of the frame.
+	pub hash: u64,
+}
+
+/// The reasons that a movie can't be played.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MovieError {
+	/// The data doesn't start with the magic number, so it isn't a movie.
+	NotAMovie,
+	/// The movie has a layout that this version doesn't understand.
+	UnsupportedVersion(u32),
+	/// The movie ended part of the way through a field.
+	Truncated,
+	/// The cartridge doesn't have the save data that the movie was recorded
+	/// with.
+	WrongSave,
+	/// The emulator isn't in the state that the movie was recorded from, such
+	/// as when a different game is inserted, or the BIOS was skipped.
+	WrongStart,
+}
+
+impl From<StateError> for MovieError {
+	fn from(_: StateError) -> Self {
+		MovieError::Truncated
+	}
+}
+
+/// What the emulator is doing with a movie.
+pub enum Playback {
+	Recording(Movie),
+	Playing {
+		movie: Movie,
+		/// The next frame to be played.
+		frame: usize,
+		/// The first frame whose state didn't match the recording.
+		desync: Option<usize>,
+	},
+}
+
+impl Movie {
+	pub fn encode(&self) -> Vec<u8> {
+		let mut movie = StateWriter::init();
+		movie.u32(u32::from_le_bytes(MAGIC));
+		movie.u32(VERSION);
+		movie.i64(self.clock_seed);
+		movie.u64(self.start_hash);
+		movie.u64(self.save_hash);
+
+		movie.u32(self.frames.len() as u32);
+		for frame in &self.frames {
+			movie.u16(frame.keys);
+			movie.u64(frame.hash);
+		}
+
+		movie.finish()
+	}
+
+	pub fn decode(data: &[u8]) -> Result<Self, MovieError> {
+		let mut movie = StateReader::init(data);
+		if movie.u32() != Ok(u32::from_le_bytes(MAGIC)) {
+			return Err(MovieError::NotAMovie);
+		}
+
+		let version = movie.u32()?;
+		if version != VERSION {
+			return Err(MovieError::UnsupportedVersion(version));
+		}
+
+		let clock_seed = movie.i64()?;
+		let start_hash = movie.u64()?;
+		let save_hash = movie.u64()?;
+
+		let length = movie.u32()?;
+		let frames = (0..length)
+			.map(|_| {
+				Ok(MovieFrame {
+					keys: movie.u16()?,
+					hash: movie.u64()?,
+				})
+			})
+			.collect::<Result<_, MovieError>>()?;
+
+		Ok(Self {
+			clock_seed,
+			start_hash,
+			save_hash,
+			frames,
+		})
+	}
+}
+
+impl Emulator {
+	/// Starts recording a movie from the current state, with the cartridge
+	/// clock starting at `clock_seed`.
+	pub fn start_recording(&mut self, clock_seed: i64) {
+		let movie = Movie {
+			clock_seed,
+			start_hash: hash(&self.save_state()),
+			save_hash: hash(&self.memory.save.export()),
+			frames: Vec::new(),
+		};
+
+		self.movie = Some(Playback::Recording(movie));
+		self.set_movie_clock(clock_seed, 0);
+	}
+
+	/// Stops recording, and returns the movie that was recorded. The cartridge
+	/// clock is left stopped at the last time it showed.
+	pub fn stop_recording(&mut self) -> Option<Movie> {
+		match self.movie.take() {
+			Some(Playback::Recording(movie)) => Some(movie),
+			playback => {
+				self.movie = playback;
+				None
+			}
+		}
+	}
+
+	/// Starts playing a movie back. The emulator has to be in the same state,
+	/// with the same save data, as when the movie started recording.
+	pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+		if hash(&self.memory.save.export()) != movie.save_hash {
+			return Err(MovieError::WrongSave);
+		}
+		if hash(&self.save_state()) != movie.start_hash {
+			return Err(MovieError::WrongStart);
+		}
+
+		self.set_movie_clock(movie.clock_seed, 0);
+		self.movie = Some(Playback::Playing {
+			movie,
+			frame: 0,
+			desync: None,
+		});
+		Ok(())
+	}
+
+	/// Whether a movie is being played, and hasn't reached its end yet.
+	pub fn is_playing_movie(&self) -> bool {
+		matches!(
+			&self.movie,
+			Some(Playback::Playing { movie, frame, .. }) if *frame < movie.frames.len()
+		)
+	}
+
+	/// The first frame where playback stopped matching the recording, if it
+	/// has.
+	pub fn movie_desync(&self) -> Option<usize> {
+		match &self.movie {
+			Some(Playback::Playing { desync, .. }) => *desync,
+			_ => None,
+		}
+	}
+
+	/// Called before each frame runs, to press the movie's buttons and move
+	/// the cartridge clock along.
+	pub(crate) fn movie_frame_started(&mut self) {
+		let (seed, frame, keys) = match &self.movie {
+			Some(Playback::Recording(movie)) => (movie.clock_seed, movie.frames.len(), None),
+			Some(Playback::Playing { movie, frame, .. }) => (
+				movie.clock_seed,
+				*frame,
+				movie.frames.get(*frame).map(|frame| frame.keys),
+			),
+			None => return,
+		};
+
+		self.set_movie_clock(seed, frame);
+		if let Some(keys) = keys {
+			self.memory.set_keys(keys);
+		}
+	}
+
+	/// Called after each frame has finished, to record it or check it against
+	/// the recording.
+	pub(crate) fn movie_frame_finished(&mut self, keys: u16) {
+		if self.movie.is_none() {
+			return;
+		}
+
+		let state_hash = hash(&self.save_state());
+		match &mut self.movie {
+			Some(Playback::Recording(movie)) => movie.frames.push(MovieFrame {
+				keys,
+				hash: state_hash,
+			}),
+			Some(Playback::Playing {
+				movie,
+				frame,
+				desync,
+			}) if *frame < movie.frames.len() => {
+				if desync.is_none() && movie.frames[*frame].hash != state_hash {
+					*desync = Some(*frame);
+				}
+				*frame += 1;
+			}
+			_ => (),
+		}
+	}
+
+	fn set_movie_clock(&mut self, seed: i64, frame: usize) {
+		let elapsed = frame as u64 * CYCLES_PER_FRAME / CYCLES_PER_SECOND;
+		self.memory.peripherals.host.clock = Box::new(FixedClock(seed + elapsed as i64));
+	}
+}
+
+/// The 64-bit FNV-1a hash, which is quick and more than good enough to tell
+/// states apart.
+fn hash(data: &[u8]) -> u64 {
+	data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+		(hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dma::{CHANNEL_SIZE, DMA0CNT_H, DMA0CNT_L, DMA0DAD, DMA0SAD};
+	use crate::memory::{PALETTE_START, RAM_START, VRAM_START};
+	use crate::peripherals::gpio::{GPIO_CONTROL, GPIO_DATA};
+	use crate::ppu::{BG0CNT, BG0HOFS, DISPCNT};
+	use crate::timers::{TM0CNT_H, TM0CNT_L};
+
+	/// 2004-09-30 15:45:10
+	const SEED: i64 = 1096559110;
+
+	// Where the busy program keeps its tables in work RAM
+	const SCROLL: u32 = RAM_START as u32;
+	const PINS: u32 = RAM_START as u32 + 0x1000;
+	const TIMER_SAMPLES: u32 = RAM_START as u32 + 0x3000;
+	const PIN_SAMPLES: u32 = RAM_START as u32 + 0x4000;
+
+	// The clock's pins, and the command that reads the date and time from it
+	const SCK: u16 = 1;
+	const SIO: u16 = 2;
+	const CS: u16 = 4;
+	const READ_DATE_TIME: u8 = 0xa6;
+
+	/// Sets up DMA to keep the rest of the hardware busy on every line, which
+	/// the CPU can't do yet. DMA0 scrolls a background a little differently on
+	/// each line, DMA1 samples a running timer, and DMA3 reads the date and time
+	/// from the cartridge's clock a pin at a time, while DMA2 samples the pins.
+	fn busy_emulator() -> Emulator {
+		let mut emulator = Emulator::new_idle();
+		let memory = &mut emulator.memory;
+
+		// A background of stripes, that wobbles as it scrolls
+		memory.write_half_word(DISPCNT, 0x0100);
+		memory.write_half_word(BG0CNT, 0x0800);
+		for color in 1..8 {
+			memory.write_half_word(PALETTE_START as u32 + color * 2, color as u16 * 0x0c63);
+		}
+		for row in 0..8 {
+			memory.write_word(VRAM_START as u32 + 0x20 + row * 4, 0x7654_3210);
+		}
+		for entry in 0..0x400 {
+			memory.write_half_word(VRAM_START as u32 + 0x4000 + entry * 2, 1);
+		}
+		for line in 0..0x800 {
+			memory.write_half_word(SCROLL + line * 2, (line * line / 64) as u16);
+		}
+
+		// Timer 1 counts the overflows of timer 0
+		memory.write_half_word(TM0CNT_H, 0x0080);
+		memory.write_half_word(TM0CNT_H + 4, 0x0084);
+
+		// Each step writes the pins, and then the direction they go in, so the
+		// data line is only let go of once the command has been sent
+		let mut steps = vec![(0, 7), (CS, 7)];
+		for bit in 0..8 {
+			let sio = (READ_DATE_TIME as u16 >> bit & 1) << 1;
+			steps.extend([(CS | sio, 7), (CS | SCK | sio, 7)]);
+		}
+		for _ in 0..7 * 8 {
+			steps.extend([(CS, 5), (CS | SCK, 5)]);
+		}
+		steps.push((0, 7));
+		for (index, (pins, direction)) in steps.into_iter().enumerate() {
+			let address = PINS + index as u32 * 4;
+			memory.write_half_word(address, pins);
+			memory.write_half_word(address + 2, direction);
+		}
+		memory.write_half_word(GPIO_CONTROL, 1);
+
+		// Every channel repeats on each H-Blank
+		let channels = [
+			(SCROLL, BG0HOFS, 1, 0xa240),
+			(TM0CNT_L, TIMER_SAMPLES, 1, 0xa300),
+			(GPIO_DATA, PIN_SAMPLES, 1, 0xa300),
+			(PINS, GPIO_DATA, 2, 0xa260),
+		];
+		for (channel, (source, destination, count, control)) in channels.into_iter().enumerate() {
+			let offset = channel as u32 * CHANNEL_SIZE;
+			memory.write_word(DMA0SAD + offset, source);
+			memory.write_word(DMA0DAD + offset, destination);
+			memory.write_half_word(DMA0CNT_L + offset, count);
+			memory.write_half_word(DMA0CNT_H + offset, control);
+		}
+
+		emulator
+	}
+
+	/// Records a few frames of pressing different buttons.
+	fn record(emulator: &mut Emulator) -> Movie {
+		emulator.start_recording(SEED);
+		for frame in 0..8 {
+			emulator.memory.set_keys(1 << frame);
+			emulator.step_frame();
+		}
+		emulator.stop_recording().unwrap()
+	}
+
+	#[test]
+	fn determinism() {
+		let movie = record(&mut busy_emulator());
+		assert_eq!(movie.frames.len(), 8);
+		assert_eq!(movie.frames[3].keys, 1 << 3);
+
+		// Running the same inputs from the same start always gives the same
+		// states
+		let mut emulator = busy_emulator();
+		emulator.play_movie(movie.clone()).unwrap();
+		while emulator.is_playing_movie() {
+			emulator.step_frame();
+		}
+		assert_eq!(emulator.movie_desync(), None);
+		assert_eq!(emulator.memory.pressed_keys(), 1 << 7);
+
+		let mut again = busy_emulator();
+		again.play_movie(movie).unwrap();
+		for _ in 0..8 {
+			again.step_frame();
+		}
+		assert_eq!(again.save_state(), emulator.save_state());
+		assert_eq!(again.ppu.framebuffer, emulator.ppu.framebuffer);
+
+		// The clock was read at the time the movie started. The reply starts 18
+		// steps in, after the command, and each bit shows up in the samples on
+		// the line after the clock line rises.
+		let memory = &emulator.memory;
+		let date = (0..3)
+			.map(|byte| {
+				(0..8).fold(0, |value, bit| {
+					let line = 18 + (byte * 8 + bit) * 2 + 2;
+					let pins = memory.read_half_word(PIN_SAMPLES + line * 2);
+					value | (pins & SIO) >> 1 << bit
+				})
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(date, [0x04, 0x09, 0x30]);
+
+		// The timer kept running the whole time
+		let first = memory.read_half_word(TIMER_SAMPLES);
+		let last = memory.read_half_word(TIMER_SAMPLES + 2 * (8 * 160 - 1));
+		assert_ne!(first, last);
+	}
+
+	#[test]
+	fn desync() {
+		let mut movie = record(&mut Emulator::new_idle());
+		movie.frames[5].keys = 0;
+
+		let mut emulator = Emulator::new_idle();
+		emulator.play_movie(movie).unwrap();
+		for _ in 0..8 {
+			emulator.step_frame();
+		}
+		assert_eq!(emulator.movie_desync(), Some(5));
+	}
+
+	#[test]
+	fn clock() {
+		// Halting skips straight through the frames, without running off the
+		// end of the program
+		let mut emulator = Emulator::new_idle();
+		emulator.memory.halted = true;
+		emulator.start_recording(SEED);
+		for _ in 0..120 {
+			emulator.step_frame();
+		}
+
+		// The clock last moved on when the 120th frame started, which was
+		// just before two seconds had passed
+		let host = &emulator.memory.peripherals.host;
+		assert_eq!(host.clock.now(), SEED + 1);
+	}
+
+	#[test]
+	fn wrong_start() {
+		let movie = record(&mut Emulator::new_idle());
+
+		let mut emulator = Emulator::new_idle();
+		emulator.memory.save.write(0, 0x42);
+		assert_eq!(
+			emulator.play_movie(movie.clone()),
+			Err(MovieError::WrongSave)
+		);
+
+		let mut emulator = Emulator::new_idle();
+		emulator.memory.write_word(RAM_START as u32, 1);
+		assert_eq!(emulator.play_movie(movie), Err(MovieError::WrongStart));
+		assert!(!emulator.is_playing_movie());
+	}
+
+	#[test]
+	fn encoding() {
+		let movie = record(&mut Emulator::new_idle());
+		let data = movie.encode();
+		assert_eq!(Movie::decode(&data), Ok(movie));
+
+		assert_eq!(Movie::decode(b"LVST"), Err(MovieError::NotAMovie));
+		assert_eq!(
+			Movie::decode(&data[..data.len() - 1]),
+			Err(MovieError::Truncated)
+		);
+
+		let mut newer = data.clone();
+		newer[4] = 2;
+		assert_eq!(
+			Movie::decode(&newer),
+			Err(MovieError::UnsupportedVersion(2))
+		);
+	}
+}
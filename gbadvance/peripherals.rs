@@ -0,0 +1,225 @@
+//! Extra hardware that some cartridges have alongside the ROM and save memory,
+//! such as clocks, sensors and rumble motors. Each game only has the hardware
+//! that it needs, so the peripherals are picked out by looking at the cartridge
+//! when it's loaded. Most of them are wired up to the GPIO port, but some sit
+//! on the cartridge bus next to the save memory instead.
+
+pub mod gpio;
+pub mod gyro;
+pub mod rtc;
+pub mod rumble;
+pub mod solar;
+pub mod tilt;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use gpio::Gpio;
+use gyro::Gyro;
+use rtc::{Clock, Rtc, SystemClock};
+use rumble::Rumble;
+use solar::SolarSensor;
+use tilt::TiltSensor;
+
+/// The ID string left in the ROM by the library that talks to the clock.
+const RTC_LIBRARY_ID: &[u8] = b"SIIRTC_V";
+
+/// Games with a real-time clock, by the first 3 characters of their game code,
+/// so that every region is covered.
+const RTC_GAMES: [&str; 8] = [
+	"AXV", // Pokémon Ruby
+	"AXP", // Pokémon Sapphire
+	"BPE", // Pokémon Emerald
+	"U3I", // Boktai
+	"U32", // Boktai 2
+	"U33", // Boktai 3
+	"BR4", // Rockman EXE 4.5
+	"BKA", // Sennen Kazoku
+];
+
+/// Something extra inside a cartridge. The methods all do nothing by default,
+/// so each peripheral only needs to handle the signals that it's connected to.
+pub trait Peripheral: Send {
+	/// Whether the peripheral is wired up to the GPIO port.
+	fn uses_gpio(&self) -> bool {
+		true
+	}
+
+	/// The GPIO pins that the peripheral is driving high.
+	fn read_pins(&self) -> u8 {
+		0
+	}
+
+	/// Called when the game changes the GPIO pins that it drives.
+	fn write_pins(&mut self, _pins: u8, _host: &Host) {}
+
+	/// Reads from the cartridge bus at 0x0e000000. Returns `None` if the
+	/// address doesn't belong to the peripheral.
+	fn read_bus(&self, _address: u32) -> Option<u8> {
+		None
+	}
+
+	/// Writes to the cartridge bus at 0x0e000000, and returns whether the
+	/// address belongs to the peripheral.
+	fn write_bus(&mut self, _address: u32, _value: u8, _host: &Host) -> bool {
+		false
+	}
+
+	fn is_rumbling(&self) -> bool {
+		false
+	}
+
+	/// Writes the peripheral's internal state into a save state.
+	fn write_state(&self, _state: &mut StateWriter) {}
+
+	fn read_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+		Ok(())
+	}
+}
+
+/// The parts of the outside world that the peripherals can sense. They are
+/// kept up to date by whoever is running the emulator.
+pub struct Host {
+	/// Where the real-time clock gets the time from.
+	pub clock: Box<dyn Clock + Send>,
+	/// How much light is reaching the solar sensor, from 0 for darkness up to
+	/// 255 for bright sunlight.
+	pub light: u8,
+	/// How fast the cartridge is turning around the gyro sensor's axis, from -1
+	/// to 1.
+	pub rotation: f32,
+	/// How far the cartridge is tilted along the x and y axes, from -1 to 1.
+	pub tilt: [f32; 2],
+}
+
+impl Host {
+	pub fn init() -> Self {
+		Self {
+			clock: Box::new(SystemClock),
+			light: 0,
+			rotation: 0.0,
+			tilt: [0.0; 2],
+		}
+	}
+}
+
+pub struct Peripherals {
+	pub devices: Vec<Box<dyn Peripheral>>,
+	pub host: Host,
+	pub gpio: Gpio,
+}
+
+impl Peripherals {
+	pub fn init() -> Self {
+		Self {
+			devices: Vec::new(),
+			host: Host::init(),
+			gpio: Gpio::init(),
+		}
+	}
+
+	/// Connects whatever hardware the cartridge has, replacing the peripherals
+	/// from the last one. The host is left alone.
+	pub fn connect(&mut self, rom: &[u8], game_code: Option<&str>) {
+		self.devices = detect(rom, game_code);
+		self.gpio = Gpio::init();
+	}
+
+	pub fn is_rumbling(&self) -> bool {
+		self.devices.iter().any(|device| device.is_rumbling())
+	}
+
+	pub fn read_bus(&self, address: u32) -> Option<u8> {
+		self.devices
+			.iter()
+			.find_map(|device| device.read_bus(address))
+	}
+
+	pub fn write_bus(&mut self, address: u32, value: u8) -> bool {
+		self.devices
+			.iter_mut()
+			.any(|device| device.write_bus(address, value, &self.host))
+	}
+}
+
+/// The peripherals themselves come from the cartridge, so only their state is
+/// saved, and it is loaded back into the ones that are already connected.
+impl Snapshot for Peripherals {
+	fn write_state(&self, state: &mut StateWriter) {
+		self.gpio.write_state(state);
+		state.u32(self.devices.len() as u32);
+
+		for device in &self.devices {
+			let mut device_state = StateWriter::init();
+			device.write_state(&mut device_state);
+			state.bytes(&device_state.finish());
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.gpio.read_state(state)?;
+		if state.u32()? as usize != self.devices.len() {
+			return Err(StateError::WrongGame);
+		}
+
+		for device in &mut self.devices {
+			device.read_state(&mut StateReader::init(state.bytes()?))?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Works out which peripherals a cartridge has. There isn't anything in the
+/// header that says, so this goes by the game code and, for the clock, the
+/// library that games use to talk to it.
+pub fn detect(rom: &[u8], game_code: Option<&str>) -> Vec<Box<dyn Peripheral>> {
+	let game = game_code.map_or("", |code| code.get(..3).unwrap_or(code));
+	let mut devices: Vec<Box<dyn Peripheral>> = Vec::new();
+
+	if RTC_GAMES.contains(&game) || has_rtc_library(rom) {
+		devices.push(Box::new(Rtc::init()));
+	}
+
+	match game {
+		// Boktai 1, 2 and 3
+		"U3I" | "U32" | "U33" => devices.push(Box::new(SolarSensor::init())),
+		// WarioWare: Twisted!
+		"RZW" => {
+			devices.push(Box::new(Gyro::init()));
+			devices.push(Box::new(Rumble::init()));
+		}
+		// Drill Dozer
+		"V49" => devices.push(Box::new(Rumble::init())),
+		// Yoshi Topsy-Turvy and Koro Koro Puzzle
+		"KYG" | "KHP" => devices.push(Box::new(TiltSensor::init())),
+		_ => (),
+	}
+
+	devices
+}
+
+fn has_rtc_library(rom: &[u8]) -> bool {
+	// Like the save library IDs, this string is always word aligned
+	(0..rom.len())
+		.step_by(4)
+		.any(|offset| rom[offset..].starts_with(RTC_LIBRARY_ID))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn detection() {
+		assert_eq!(detect(&[], Some("BPEE")).len(), 1);
+		assert_eq!(detect(&[], Some("AXVJ")).len(), 1);
+		assert_eq!(detect(&[], Some("BPRE")).len(), 0);
+		assert_eq!(detect(b"\0\0\0\0SIIRTC_V001", None).len(), 1);
+
+		// Boktai has a clock and a solar sensor
+		assert_eq!(detect(&[], Some("U3IE")).len(), 2);
+
+		let tilt = detect(&[], Some("KYGE"));
+		assert_eq!(tilt.len(), 1);
+		assert!(!tilt[0].uses_gpio());
+	}
+}
@@ -0,0 +1,155 @@
+//! The 4-bit general purpose port that most cartridge peripherals are wired up
+//! to. Its registers sit on top of a few bytes of the ROM header, which only
+//! read back as the port once the game makes them readable. Writes always go to
+//! the port, since the ROM can't be written anyway.
+
+use super::Peripherals;
+use crate::memory::{Memory, ROM_START, ROM_WAIT2_END};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// The state of each pin.
+pub const GPIO_DATA: u32 = 0x0800_00c4;
+/// Which pins are outputs, with a bit set for each pin that the GBA drives.
+pub const GPIO_DIRECTION: u32 = 0x0800_00c6;
+/// Bit 0 chooses whether the registers can be read, or if the ROM shows through.
+pub const GPIO_CONTROL: u32 = 0x0800_00c8;
+
+pub struct Gpio {
+	data: u8,
+	direction: u8,
+	readable: bool,
+}
+
+impl Gpio {
+	pub fn init() -> Self {
+		Self {
+			data: 0,
+			direction: 0,
+			readable: false,
+		}
+	}
+}
+
+impl Peripherals {
+	/// Whether there's anything connected to the port at all.
+	pub fn uses_gpio(&self) -> bool {
+		self.devices.iter().any(|device| device.uses_gpio())
+	}
+
+	/// The pins driven by the GBA, combined with the pins driven by whatever is
+	/// connected to the port.
+	fn pins(&self) -> u8 {
+		let input = self
+			.devices
+			.iter()
+			.fold(0, |pins, device| pins | device.read_pins());
+		(self.gpio.data & self.gpio.direction | input & !self.gpio.direction) & 0xf
+	}
+
+	fn read_port(&self, register: u32) -> u8 {
+		match register {
+			GPIO_DATA => self.pins(),
+			GPIO_DIRECTION => self.gpio.direction,
+			GPIO_CONTROL => self.gpio.readable as u8,
+			_ => 0,
+		}
+	}
+
+	fn write_port(&mut self, register: u32, value: u8) {
+		match register {
+			GPIO_DATA => {
+				self.gpio.data = value & 0xf;
+				let pins = self.gpio.data & self.gpio.direction;
+				for device in &mut self.devices {
+					device.write_pins(pins, &self.host);
+				}
+			}
+			GPIO_DIRECTION => self.gpio.direction = value & 0xf,
+			GPIO_CONTROL => self.gpio.readable = value & 1 > 0,
+			_ => (),
+		}
+	}
+}
+
+impl Snapshot for Gpio {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.u8(self.data);
+		state.u8(self.direction);
+		state.bool(self.readable);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.data = state.u8()?;
+		self.direction = state.u8()?;
+		self.readable = state.bool()?;
+		Ok(())
+	}
+}
+
+/// If the address is one of the GPIO registers in any of the ROM mirrors,
+/// returns the address of the register in the first mirror.
+fn gpio_register(address: u32) -> Option<u32> {
+	if !(ROM_START..=ROM_WAIT2_END).contains(&(address as usize)) {
+		return None;
+	}
+
+	let address = ROM_START as u32 | address & 0x01ff_ffff;
+	(GPIO_DATA..GPIO_CONTROL + 2)
+		.contains(&address)
+		.then_some(address)
+}
+
+impl Memory {
+	/// Checks if reading from the address should give a GPIO register rather
+	/// than the ROM.
+	pub fn is_gpio_readable(&self, address: u32) -> bool {
+		self.peripherals.gpio.readable
+			&& self.peripherals.uses_gpio()
+			&& gpio_register(address).is_some()
+	}
+
+	pub fn read_gpio(&self, address: u32) -> u8 {
+		gpio_register(address).map_or(0, |register| self.peripherals.read_port(register))
+	}
+
+	/// Checks if writing to the address should go to a GPIO register. Writes
+	/// are ignored when there's nothing connected, like they are for the rest
+	/// of the ROM.
+	pub fn is_gpio_writable(&self, address: u32) -> bool {
+		self.peripherals.uses_gpio() && gpio_register(address).is_some()
+	}
+
+	pub fn write_gpio(&mut self, address: u32, value: u8) {
+		if let Some(register) = gpio_register(address) {
+			self.peripherals.write_port(register, value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::rtc::Rtc;
+	use super::*;
+
+	#[test]
+	fn registers() {
+		let mut memory = Memory::init();
+		memory.rom = vec![0x55; 0x200];
+
+		memory.peripherals.devices.push(Box::new(Rtc::init()));
+		memory.write_half_word(GPIO_DIRECTION, 0b0101);
+		assert_eq!(memory.read_half_word(GPIO_DIRECTION), 0x5555);
+
+		memory.write_half_word(GPIO_CONTROL, 1);
+		assert_eq!(memory.read_half_word(GPIO_DIRECTION), 0b0101);
+		assert_eq!(memory.read_word(GPIO_DATA), 0b0101 << 16);
+
+		// The mirrors work too
+		assert_eq!(memory.read_half_word(0x0a00_00c6), 0b0101);
+		assert_eq!(memory.read_half_word(0x0800_00c0), 0x5555);
+
+		// Without anything connected, the ROM shows through
+		memory.peripherals.devices.clear();
+		assert_eq!(memory.read_half_word(GPIO_DIRECTION), 0x5555);
+	}
+}
@@ -0,0 +1,95 @@
+//! The gyro sensor in WarioWare: Twisted!, which measures how fast the
+//! cartridge is being turned. Setting pin 0 takes a sample, which is then
+//! shifted out of pin 2 most significant bit first, one bit on each falling
+//! edge of pin 1. The motor on pin 3 is a separate peripheral.
+
+use super::{Host, Peripheral};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// GPIO pins
+const SAMPLE: u8 = 1 << 0;
+const CLOCK: u8 = 1 << 1;
+const DATA: u8 = 1 << 2;
+
+/// The reading when the cartridge is held still.
+const CENTER: f32 = 0x6c0 as f32;
+/// How far the reading moves from the center when turning at full speed.
+const RANGE: f32 = 0x400 as f32;
+
+pub struct Gyro {
+	/// The sample being shifted out.
+	sample: u16,
+	/// The bit that the sensor is putting on the data line.
+	output: u8,
+	/// The pins that were last written.
+	pins: u8,
+}
+
+impl Gyro {
+	pub fn init() -> Self {
+		Self {
+			sample: 0,
+			output: 0,
+			pins: 0,
+		}
+	}
+}
+
+impl Peripheral for Gyro {
+	fn read_pins(&self) -> u8 {
+		self.output
+	}
+
+	fn write_pins(&mut self, pins: u8, host: &Host) {
+		let previous = std::mem::replace(&mut self.pins, pins);
+
+		if pins & SAMPLE > 0 {
+			self.sample = (CENTER + host.rotation.clamp(-1.0, 1.0) * RANGE) as u16;
+		}
+
+		if previous & CLOCK > 0 && pins & CLOCK == 0 {
+			self.output = if self.sample & 0x8000 > 0 { DATA } else { 0 };
+			self.sample <<= 1;
+		}
+	}
+
+	fn write_state(&self, state: &mut StateWriter) {
+		state.u16(self.sample);
+		state.u8(self.output);
+		state.u8(self.pins);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.sample = state.u16()?;
+		self.output = state.u8()?;
+		self.pins = state.u8()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::rtc::tests::fixed_host;
+	use super::*;
+
+	fn read_sample(gyro: &mut Gyro, host: &Host) -> u16 {
+		gyro.write_pins(SAMPLE | CLOCK, host);
+		(0..16).fold(0, |sample, _| {
+			gyro.write_pins(CLOCK, host);
+			gyro.write_pins(0, host);
+			sample << 1 | (gyro.read_pins() & DATA > 0) as u16
+		})
+	}
+
+	#[test]
+	fn rotation() {
+		let mut gyro = Gyro::init();
+		let mut host = fixed_host(0);
+
+		assert_eq!(read_sample(&mut gyro, &host), 0x6c0);
+		host.rotation = 0.5;
+		assert_eq!(read_sample(&mut gyro, &host), 0x8c0);
+		host.rotation = -2.0;
+		assert_eq!(read_sample(&mut gyro, &host), 0x2c0);
+	}
+}
@@ -0,0 +1,546 @@
+//! The Seiko S-3511 real-time clock. Games talk to it one bit at a time over
+//! three of the GPIO pins: a clock, a data line, and a chip select. Each request
+//! starts with a command byte, followed by the bytes of the register that the
+//! command reads or writes. Everything is sent least significant bit first, and
+//! the date and time are stored as BCD.
+
+use super::{Host, Peripheral};
+use crate::state::{StateError, StateReader, StateWriter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// GPIO pins
+const SCK: u8 = 1 << 0;
+const SIO: u8 = 1 << 1;
+const CS: u8 = 1 << 2;
+
+/// Every command byte has this in its bottom 4 bits.
+const COMMAND_MAGIC: u8 = 0b0110;
+/// Set in a command byte to read the register rather than write it.
+const COMMAND_READ: u8 = 1 << 7;
+
+// Commands, as they are numbered in the datasheet
+const RESET: u8 = 0;
+const STATUS: u8 = 1;
+const DATE_TIME: u8 = 2;
+const TIME: u8 = 3;
+const ALARM: u8 = 4;
+
+// Bits of the status register
+const STATUS_WRITABLE: u8 = 0b0110_1010;
+const STATUS_24_HOUR: u8 = 1 << 6;
+
+/// The bit in the hour register that is set in the afternoon, when using a
+/// 12 hour clock.
+const PM: u8 = 1 << 7;
+
+/// Somewhere to get the current time from. Times are in seconds since the
+/// start of 1970, in whatever time zone the clock should show.
+pub trait Clock {
+	fn now(&self) -> i64;
+}
+
+/// Uses the time from the operating system. This is always in UTC, because
+/// there isn't a way to find out the local time zone from the standard library.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> i64 {
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |duration| duration.as_secs() as i64)
+	}
+}
+
+/// Always returns the same time. Tests use this so that they don't depend on
+/// when they are run, and movies so that they play back the same every time.
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+	fn now(&self) -> i64 {
+		self.0
+	}
+}
+
+/// The parts of a date and time, the way the clock's registers store them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DateTime {
+	/// From 2000 to 2099
+	pub year: i64,
+	pub month: u8,
+	pub day: u8,
+	/// 0 is Sunday.
+	pub weekday: u8,
+	pub hour: u8,
+	pub minute: u8,
+	pub second: u8,
+}
+
+impl DateTime {
+	pub fn from_timestamp(timestamp: i64) -> Self {
+		let days = timestamp.div_euclid(86400);
+		let seconds = timestamp.rem_euclid(86400);
+		let (year, month, day) = civil_from_days(days);
+
+		Self {
+			year,
+			month,
+			day,
+			// 1970 started on a Thursday
+			weekday: (days + 4).rem_euclid(7) as u8,
+			hour: (seconds / 3600) as u8,
+			minute: (seconds / 60 % 60) as u8,
+			second: (seconds % 60) as u8,
+		}
+	}
+
+	pub fn timestamp(&self) -> i64 {
+		let days = days_from_civil(self.year, self.month, self.day);
+		days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+	}
+}
+
+/// Works out the year, month and day from a number of days since 1970, using
+/// Howard Hinnant's algorithm.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+	let days = days + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days.rem_euclid(146097);
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+	let month = (if month_index < 10 {
+		month_index + 3
+	} else {
+		month_index - 9
+	}) as u8;
+	let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+	(year, month, day)
+}
+
+/// The opposite of `civil_from_days`.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+	let year = year - (month <= 2) as i64;
+	let era = year.div_euclid(400);
+	let year_of_era = year.rem_euclid(400);
+	let month = month as i64;
+	let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+	era * 146097 + day_of_era - 719468
+}
+
+/// The number of bytes in the register that each command reads or writes.
+fn register_size(command: u8) -> usize {
+	match command {
+		STATUS => 1,
+		DATE_TIME => 7,
+		TIME => 3,
+		ALARM => 2,
+		_ => 0,
+	}
+}
+
+fn to_bcd(value: u8) -> u8 {
+	((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+	(value >> 4) * 10 + (value & 0xf)
+}
+
+/// What the clock is doing with the bits it's being sent.
+#[derive(Clone, Debug, PartialEq)]
+enum Transfer {
+	/// Waiting for a command byte.
+	Command,
+	/// Receiving the bytes of a register, which will be written once they've
+	/// all arrived.
+	Write { command: u8, received: Vec<u8> },
+	/// Sending the bytes of a register back to the game.
+	Read { bytes: Vec<u8>, position: usize },
+}
+
+pub struct Rtc {
+	/// How far ahead of the clock the game has set the time, in seconds.
+	pub offset: i64,
+	pub status: u8,
+	/// The hour and minute that the alarm goes off.
+	pub alarm: [u8; 2],
+
+	/// The pins that were last written.
+	pins: u8,
+	/// The bit that the clock is putting on the data line.
+	output: u8,
+	transfer: Transfer,
+	/// The bits of the current byte received so far.
+	bits: u8,
+	bit_count: u8,
+}
+
+impl Rtc {
+	pub fn init() -> Self {
+		Self {
+			offset: 0,
+			status: STATUS_24_HOUR,
+			alarm: [0; 2],
+			pins: 0,
+			output: 0,
+			transfer: Transfer::Command,
+			bits: 0,
+			bit_count: 0,
+		}
+	}
+
+	fn update_pins(&mut self, pins: u8, now: i64) {
+		let previous = std::mem::replace(&mut self.pins, pins);
+
+		// Dropping chip select ends the request, and lets go of the data line
+		if pins & CS == 0 {
+			self.transfer = Transfer::Command;
+			self.output = 0;
+			self.bits = 0;
+			self.bit_count = 0;
+			return;
+		}
+
+		// Everything happens when the clock line rises
+		if previous & SCK > 0 || pins & SCK == 0 {
+			return;
+		}
+
+		if let Transfer::Read { bytes, position } = &mut self.transfer {
+			let bit = bytes
+				.get(*position / 8)
+				.map_or(0, |byte| byte >> (*position % 8) & 1);
+			self.output = bit << 1;
+			*position += 1;
+			return;
+		}
+
+		self.bits |= (pins & SIO) >> 1 << self.bit_count;
+		self.bit_count += 1;
+
+		if self.bit_count == 8 {
+			let byte = std::mem::take(&mut self.bits);
+			self.bit_count = 0;
+			self.byte_received(byte, now);
+		}
+	}
+
+	fn byte_received(&mut self, byte: u8, now: i64) {
+		match &mut self.transfer {
+			Transfer::Command => {
+				if byte & 0xf != COMMAND_MAGIC {
+					return;
+				}
+
+				// The command number is sent most significant bit first, unlike
+				// everything else.
+				let command = (byte >> 6 & 1) | (byte >> 4 & 1) << 2 | (byte >> 5 & 1) << 1;
+
+				if command == RESET {
+					self.reset(now);
+				} else if byte & COMMAND_READ > 0 {
+					self.transfer = Transfer::Read {
+						bytes: self.register(command, now),
+						position: 0,
+					};
+				} else if register_size(command) > 0 {
+					self.transfer = Transfer::Write {
+						command,
+						received: Vec::new(),
+					};
+				}
+			}
+			Transfer::Write { command, received } => {
+				received.push(byte);
+
+				if received.len() == register_size(*command) {
+					let (command, received) = (*command, std::mem::take(received));
+					self.write_register(command, &received, now);
+					self.transfer = Transfer::Command;
+				}
+			}
+			Transfer::Read { .. } => (),
+		}
+	}
+
+	/// Clears all of the settings, and sets the time back to the start of 2000.
+	fn reset(&mut self, now: i64) {
+		self.status = 0;
+		self.alarm = [0; 2];
+		self.offset = DateTime {
+			year: 2000,
+			month: 1,
+			day: 1,
+			weekday: 6,
+			hour: 0,
+			minute: 0,
+			second: 0,
+		}
+		.timestamp()
+			- now;
+	}
+
+	/// The date and time shown by the clock, as the game has set it, when the
+	/// host's clock says `now`.
+	pub fn date_time(&self, now: i64) -> DateTime {
+		DateTime::from_timestamp(now + self.offset)
+	}
+
+	/// Returns the contents of a register, in the order that they are sent.
+	fn register(&self, command: u8, now: i64) -> Vec<u8> {
+		let date_time = self.date_time(now);
+
+		let hour = if self.status & STATUS_24_HOUR > 0 {
+			to_bcd(date_time.hour)
+		} else {
+			to_bcd(date_time.hour % 12) | if date_time.hour >= 12 { PM } else { 0 }
+		};
+		let time = [hour, to_bcd(date_time.minute), to_bcd(date_time.second)];
+
+		match command {
+			STATUS => vec![self.status],
+			DATE_TIME => [
+				to_bcd(date_time.year.rem_euclid(100) as u8),
+				to_bcd(date_time.month),
+				to_bcd(date_time.day),
+				date_time.weekday,
+			]
+			.into_iter()
+			.chain(time)
+			.collect(),
+			TIME => time.to_vec(),
+			ALARM => self.alarm.to_vec(),
+			_ => Vec::new(),
+		}
+	}
+
+	fn write_register(&mut self, command: u8, bytes: &[u8], now: i64) {
+		let hour = |byte: u8| {
+			let hour = from_bcd(byte & 0x3f);
+			if self.status & STATUS_24_HOUR == 0 && byte & PM > 0 {
+				hour % 12 + 12
+			} else {
+				hour
+			}
+		};
+
+		let date_time = self.date_time(now);
+		let set = match command {
+			STATUS => {
+				self.status = bytes[0] & STATUS_WRITABLE;
+				return;
+			}
+			ALARM => {
+				self.alarm.copy_from_slice(bytes);
+				return;
+			}
+			DATE_TIME => DateTime {
+				year: 2000 + from_bcd(bytes[0]) as i64,
+				month: from_bcd(bytes[1]).clamp(1, 12),
+				day: from_bcd(bytes[2]).clamp(1, 31),
+				weekday: 0,
+				hour: hour(bytes[4]),
+				minute: from_bcd(bytes[5]),
+				second: from_bcd(bytes[6]),
+			},
+			TIME => DateTime {
+				hour: hour(bytes[0]),
+				minute: from_bcd(bytes[1]),
+				second: from_bcd(bytes[2]),
+				..date_time
+			},
+			_ => return,
+		};
+
+		self.offset = set.timestamp() - now;
+	}
+}
+
+impl Peripheral for Rtc {
+	fn read_pins(&self) -> u8 {
+		self.output
+	}
+
+	fn write_pins(&mut self, pins: u8, host: &Host) {
+		self.update_pins(pins, host.clock.now());
+	}
+
+	fn write_state(&self, state: &mut StateWriter) {
+		state.i64(self.offset);
+		state.u8(self.status);
+		state.bytes(&self.alarm);
+		state.u8(self.pins);
+		state.u8(self.output);
+		state.u8(self.bits);
+		state.u8(self.bit_count);
+
+		match &self.transfer {
+			Transfer::Command => state.u8(0),
+			Transfer::Write { command, received } => {
+				state.u8(1);
+				state.u8(*command);
+				state.bytes(received);
+			}
+			Transfer::Read { bytes, position } => {
+				state.u8(2);
+				state.bytes(bytes);
+				state.u32(*position as u32);
+			}
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.offset = state.i64()?;
+		self.status = state.u8()?;
+		state.fill(&mut self.alarm)?;
+		self.pins = state.u8()?;
+		self.output = state.u8()?;
+		self.bits = state.u8()?;
+		self.bit_count = state.u8()?;
+
+		self.transfer = match state.u8()? {
+			0 => Transfer::Command,
+			1 => Transfer::Write {
+				command: state.u8()?,
+				received: state.bytes()?.to_vec(),
+			},
+			2 => Transfer::Read {
+				bytes: state.bytes()?.to_vec(),
+				position: state.u32()? as usize,
+			},
+			_ => return Err(StateError::Invalid),
+		};
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+
+	/// 2004-09-30 15:45:10, a Thursday
+	const TIMESTAMP: i64 = 1096559110;
+
+	pub fn fixed_host(time: i64) -> Host {
+		let mut host = Host::init();
+		host.clock = Box::new(FixedClock(time));
+		host
+	}
+
+	fn send_byte(rtc: &mut Rtc, host: &Host, byte: u8) {
+		for bit in 0..8 {
+			let sio = (byte >> bit & 1) << 1;
+			rtc.write_pins(CS | sio, host);
+			rtc.write_pins(CS | SCK | sio, host);
+		}
+	}
+
+	fn read_bytes(rtc: &mut Rtc, host: &Host, count: usize) -> Vec<u8> {
+		(0..count)
+			.map(|_| {
+				(0..8).fold(0, |byte, bit| {
+					rtc.write_pins(CS, host);
+					rtc.write_pins(CS | SCK, host);
+					byte | (rtc.read_pins() & SIO) >> 1 << bit
+				})
+			})
+			.collect()
+	}
+
+	/// Builds a command byte the way that games send them.
+	fn command(command: u8, read: bool) -> u8 {
+		let reversed = (command & 1) << 2 | (command & 2) | (command >> 2 & 1);
+		COMMAND_MAGIC | reversed << 4 | if read { COMMAND_READ } else { 0 }
+	}
+
+	fn request(rtc: &mut Rtc, host: &Host, byte: u8) {
+		rtc.write_pins(0, host);
+		rtc.write_pins(CS, host);
+		send_byte(rtc, host, byte);
+	}
+
+	#[test]
+	fn calendar() {
+		let date = DateTime::from_timestamp(TIMESTAMP);
+		assert_eq!(
+			date,
+			DateTime {
+				year: 2004,
+				month: 9,
+				day: 30,
+				weekday: 4,
+				hour: 15,
+				minute: 45,
+				second: 10,
+			}
+		);
+		assert_eq!(date.timestamp(), TIMESTAMP);
+		assert_eq!(DateTime::from_timestamp(951782400).day, 29);
+	}
+
+	#[test]
+	fn read_date_time() {
+		let host = fixed_host(TIMESTAMP);
+		let mut rtc = Rtc::init();
+
+		request(&mut rtc, &host, command(DATE_TIME, true));
+		assert_eq!(
+			read_bytes(&mut rtc, &host, 7),
+			[0x04, 0x09, 0x30, 4, 0x15, 0x45, 0x10]
+		);
+
+		// With a 12 hour clock
+		request(&mut rtc, &host, command(STATUS, false));
+		send_byte(&mut rtc, &host, 0);
+		request(&mut rtc, &host, command(TIME, true));
+		assert_eq!(read_bytes(&mut rtc, &host, 3), [PM | 0x03, 0x45, 0x10]);
+	}
+
+	#[test]
+	fn set_time() {
+		let host = fixed_host(TIMESTAMP);
+		let mut rtc = Rtc::init();
+
+		request(&mut rtc, &host, command(DATE_TIME, false));
+		for byte in [0x23, 0x12, 0x25, 0, 0x08, 0x30, 0x00] {
+			send_byte(&mut rtc, &host, byte);
+		}
+
+		let now = rtc.date_time(TIMESTAMP);
+		assert_eq!((now.year, now.month, now.day), (2023, 12, 25));
+		assert_eq!((now.hour, now.minute, now.second), (8, 30, 0));
+		assert_eq!(now.weekday, 1);
+
+		// The clock keeps running from the time that was set
+		let host = fixed_host(TIMESTAMP + 90);
+		request(&mut rtc, &host, command(TIME, true));
+		assert_eq!(read_bytes(&mut rtc, &host, 3), [0x08, 0x31, 0x30]);
+	}
+
+	#[test]
+	fn status_and_alarm() {
+		let host = fixed_host(TIMESTAMP);
+		let mut rtc = Rtc::init();
+
+		request(&mut rtc, &host, command(STATUS, false));
+		send_byte(&mut rtc, &host, 0xff);
+		request(&mut rtc, &host, command(STATUS, true));
+		assert_eq!(read_bytes(&mut rtc, &host, 1), [STATUS_WRITABLE]);
+
+		request(&mut rtc, &host, command(ALARM, false));
+		send_byte(&mut rtc, &host, 0x07);
+		send_byte(&mut rtc, &host, 0x30);
+		request(&mut rtc, &host, command(ALARM, true));
+		assert_eq!(read_bytes(&mut rtc, &host, 2), [0x07, 0x30]);
+
+		request(&mut rtc, &host, command(RESET, false));
+		assert_eq!(rtc.status, 0);
+		assert_eq!(rtc.date_time(TIMESTAMP).year, 2000);
+	}
+}
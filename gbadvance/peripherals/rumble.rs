@@ -0,0 +1,36 @@
+//! A motor inside the cartridge that makes it shake, which the game switches on
+//! and off with GPIO pin 3.
+
+use super::{Host, Peripheral};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const MOTOR: u8 = 1 << 3;
+
+pub struct Rumble {
+	pub active: bool,
+}
+
+impl Rumble {
+	pub fn init() -> Self {
+		Self { active: false }
+	}
+}
+
+impl Peripheral for Rumble {
+	fn write_pins(&mut self, pins: u8, _host: &Host) {
+		self.active = pins & MOTOR > 0;
+	}
+
+	fn is_rumbling(&self) -> bool {
+		self.active
+	}
+
+	fn write_state(&self, state: &mut StateWriter) {
+		state.bool(self.active);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.active = state.bool()?;
+		Ok(())
+	}
+}
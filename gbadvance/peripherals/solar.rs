@@ -0,0 +1,113 @@
+//! The solar sensor in the Boktai games, which measures how much sunlight is
+//! reaching the cartridge. The game resets a counter inside the sensor, then
+//! clocks it upwards until the sensor raises a flag to say that the count has
+//! passed the light level. The brighter the light, the sooner that happens.
+
+use super::{Host, Peripheral};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// GPIO pins
+const CLOCK: u8 = 1 << 0;
+const RESET: u8 = 1 << 1;
+/// The chip select of the real-time clock, which shares the port. The sensor
+/// ignores the other pins while the clock is being talked to.
+const RTC_SELECT: u8 = 1 << 2;
+const FLAG: u8 = 1 << 3;
+
+pub struct SolarSensor {
+	counter: u8,
+	/// The count that raises the flag, sampled from the light level whenever
+	/// the counter is reset.
+	threshold: u8,
+	/// The pins that were last written.
+	pins: u8,
+}
+
+impl SolarSensor {
+	pub fn init() -> Self {
+		Self {
+			counter: 0,
+			threshold: u8::MAX,
+			pins: 0,
+		}
+	}
+}
+
+impl Peripheral for SolarSensor {
+	fn read_pins(&self) -> u8 {
+		if self.counter >= self.threshold {
+			FLAG
+		} else {
+			0
+		}
+	}
+
+	fn write_pins(&mut self, pins: u8, host: &Host) {
+		if pins & RTC_SELECT > 0 {
+			return;
+		}
+
+		let previous = std::mem::replace(&mut self.pins, pins);
+
+		if pins & RESET > 0 {
+			self.counter = 0;
+			self.threshold = u8::MAX - host.light;
+		}
+
+		// The counter goes up when the clock line rises
+		if pins & CLOCK > 0 && previous & CLOCK == 0 {
+			self.counter = self.counter.saturating_add(1);
+		}
+	}
+
+	fn write_state(&self, state: &mut StateWriter) {
+		state.u8(self.counter);
+		state.u8(self.threshold);
+		state.u8(self.pins);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.counter = state.u8()?;
+		self.threshold = state.u8()?;
+		self.pins = state.u8()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::rtc::tests::fixed_host;
+	use super::*;
+
+	/// Counts how many clocks it takes for the flag to be raised.
+	fn measure(sensor: &mut SolarSensor, host: &Host) -> usize {
+		sensor.write_pins(RESET, host);
+		sensor.write_pins(0, host);
+
+		(0..=u8::MAX as usize)
+			.find(|_| {
+				sensor.write_pins(CLOCK, host);
+				sensor.write_pins(0, host);
+				sensor.read_pins() & FLAG > 0
+			})
+			.map_or(usize::MAX, |clocks| clocks + 1)
+	}
+
+	#[test]
+	fn brighter_light_raises_the_flag_sooner() {
+		let mut sensor = SolarSensor::init();
+		let mut host = fixed_host(0);
+
+		host.light = 0;
+		assert_eq!(measure(&mut sensor, &host), 255);
+		host.light = 200;
+		assert_eq!(measure(&mut sensor, &host), 55);
+
+		// Nothing happens while the clock is selected
+		sensor.write_pins(RESET, &host);
+		host.light = 250;
+		sensor.write_pins(RTC_SELECT | RESET, &host);
+		sensor.write_pins(RTC_SELECT | CLOCK, &host);
+		assert_eq!(sensor.read_pins(), 0);
+	}
+}
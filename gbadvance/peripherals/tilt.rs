@@ -0,0 +1,120 @@
+//! The tilt sensor in Yoshi Topsy-Turvy and Koro Koro Puzzle, which measures
+//! which way the cartridge is leaning on two axes. Rather than using the GPIO
+//! port, it sits on the cartridge bus where SRAM would usually be. The game
+//! writes two magic values to take a sample, and then reads each axis out as a
+//! 12-bit value split over two bytes.
+
+use super::{Host, Peripheral};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Writing 0x55 here gets the sensor ready to take a sample.
+const PREPARE: u32 = 0x0e00_8000;
+/// Writing 0xaa here after preparing takes the sample.
+const SAMPLE: u32 = 0x0e00_8100;
+const X_LOW: u32 = 0x0e00_8200;
+const X_HIGH: u32 = 0x0e00_8300;
+const Y_LOW: u32 = 0x0e00_8400;
+const Y_HIGH: u32 = 0x0e00_8500;
+
+/// Set in the high byte of the x axis to say that the sample is ready.
+const READY: u8 = 0x80;
+
+/// The reading when the cartridge is held flat.
+const CENTER: f32 = 0x3a0 as f32;
+/// How far the reading moves from the center when fully tilted.
+const RANGE: f32 = 0x200 as f32;
+
+pub struct TiltSensor {
+	prepared: bool,
+	x: u16,
+	y: u16,
+}
+
+impl TiltSensor {
+	pub fn init() -> Self {
+		Self {
+			prepared: false,
+			x: CENTER as u16,
+			y: CENTER as u16,
+		}
+	}
+}
+
+fn reading(tilt: f32) -> u16 {
+	(CENTER - tilt.clamp(-1.0, 1.0) * RANGE) as u16
+}
+
+impl Peripheral for TiltSensor {
+	fn uses_gpio(&self) -> bool {
+		false
+	}
+
+	fn read_bus(&self, address: u32) -> Option<u8> {
+		match address {
+			X_LOW => Some(self.x as u8),
+			X_HIGH => Some((self.x >> 8) as u8 | READY),
+			Y_LOW => Some(self.y as u8),
+			Y_HIGH => Some((self.y >> 8) as u8),
+			_ => None,
+		}
+	}
+
+	fn write_bus(&mut self, address: u32, value: u8, host: &Host) -> bool {
+		match (address, value) {
+			(PREPARE, 0x55) => self.prepared = true,
+			(SAMPLE, 0xaa) if self.prepared => {
+				self.x = reading(host.tilt[0]);
+				self.y = reading(host.tilt[1]);
+				self.prepared = false;
+			}
+			(PREPARE | SAMPLE, _) => (),
+			_ => return false,
+		}
+
+		true
+	}
+
+	fn write_state(&self, state: &mut StateWriter) {
+		state.bool(self.prepared);
+		state.u16(self.x);
+		state.u16(self.y);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.prepared = state.bool()?;
+		self.x = state.u16()?;
+		self.y = state.u16()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::Memory;
+
+	#[test]
+	fn sampling() {
+		let mut memory = Memory::init();
+		memory
+			.peripherals
+			.devices
+			.push(Box::new(TiltSensor::init()));
+		memory.peripherals.host.tilt = [0.5, -1.0];
+
+		// Nothing changes until both magic values are written
+		memory.write_byte(SAMPLE, 0xaa);
+		assert_eq!(memory.read_byte(X_LOW), 0xa0);
+
+		memory.write_byte(PREPARE, 0x55);
+		memory.write_byte(SAMPLE, 0xaa);
+		let x = memory.read_byte(X_LOW) as u16 | (memory.read_byte(X_HIGH) as u16 & 0xf) << 8;
+		let y = memory.read_byte(Y_LOW) as u16 | (memory.read_byte(Y_HIGH) as u16) << 8;
+		assert_eq!((x, y), (0x2a0, 0x5a0));
+		assert_eq!(memory.read_byte(X_HIGH) & READY, READY);
+
+		// The rest of the region is still save memory
+		memory.write_byte(0x0e00_0010, 0x12);
+		assert_eq!(memory.read_byte(0x0e00_0010), 0x12);
+	}
+}
@@ -0,0 +1,773 @@
+//! The picture processing unit, which turns the contents of VRAM, palette
+//! memory and the display IO registers into an image. Rendering happens one
+//! scanline at a time, the same way that the hardware draws to the LCD, so that
+//! games which change registers in the middle of a frame look correct.
+
+/// Text and affine background layers, as well as the bitmap modes.
+pub mod background;
+/// Alpha blending and brightness effects.
+pub mod blend;
+/// Sprites, which the hardware refers to as objects.
+pub mod object;
+/// Windows, which control where each layer is visible.
+pub mod window;
+
+use crate::dma::Timing;
+use crate::interrupts::Interrupt;
+use crate::memory::Memory;
+use crate::scheduler::Event;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use blend::{Blend, LayerPixel, BACKDROP_LAYER, OBJECT_LAYER};
+use object::ObjectLine;
+use window::WindowLine;
+
+pub const SCREEN_WIDTH: usize = 240;
+pub const SCREEN_HEIGHT: usize = 160;
+
+pub const DISPCNT: u32 = 0x0400_0000;
+pub const DISPSTAT: u32 = 0x0400_0004;
+pub const VCOUNT: u32 = 0x0400_0006;
+pub const BG0CNT: u32 = 0x0400_0008;
+pub const BG0HOFS: u32 = 0x0400_0010;
+pub const BG0VOFS: u32 = 0x0400_0012;
+pub const BG2PA: u32 = 0x0400_0020;
+pub const BG2PB: u32 = 0x0400_0022;
+pub const BG2PC: u32 = 0x0400_0024;
+pub const BG2PD: u32 = 0x0400_0026;
+pub const BG2X: u32 = 0x0400_0028;
+pub const BG2Y: u32 = 0x0400_002c;
+pub const BG3X: u32 = 0x0400_0038;
+pub const MOSAIC: u32 = 0x0400_004c;
+
+/// The number of cycles spent drawing the visible part of each line.
+pub const HDRAW_CYCLES: u64 = 960;
+/// The number of cycles in each line, including H-Blank.
+pub const LINE_CYCLES: u64 = 1232;
+/// The number of lines in each frame, including the ones in V-Blank.
+pub const TOTAL_LINES: usize = 228;
+
+/// Bit of DISPCNT which turns the display off, so that VRAM can be accessed
+/// freely.
+const FORCED_BLANK: u16 = 1 << 7;
+
+// Bits of DISPSTAT
+const VBLANK_FLAG: u16 = 1 << 0;
+const HBLANK_FLAG: u16 = 1 << 1;
+const VCOUNT_FLAG: u16 = 1 << 2;
+const VBLANK_IRQ: u16 = 1 << 3;
+const HBLANK_IRQ: u16 = 1 << 4;
+const VCOUNT_IRQ: u16 = 1 << 5;
+
+/// A single row of pixels belonging to one layer. Each pixel is either a 15-bit
+/// color, or `None` if the layer is transparent at that point.
+pub type Line = [Option<u16>; SCREEN_WIDTH];
+
+pub struct Ppu {
+	/// The most recently rendered image, stored as 8-bit RGBA so that it can be
+	/// handed to a canvas (or an image encoder) without any further conversion.
+	pub framebuffer: Vec<u8>,
+	/// The internal reference points used by the affine backgrounds (BG2 and
+	/// BG3). These are copied from the BGxX/BGxY registers at the start of
+	/// V-Blank and whenever the registers are written to, and then advanced by
+	/// the dmx/dmy parameters after every scanline.
+	pub affine_reference: [(i32, i32); 2],
+	/// The first line of the current vertical mosaic block, for backgrounds
+	/// and objects respectively. The hardware counts lines since the block
+	/// started and compares that against the size in the MOSAIC register,
+	/// rather than dividing the line number, so changing the size in the middle
+	/// of a frame is relative to the block in progress.
+	pub mosaic_line: [usize; 2],
+	/// The affine reference points as they were at the start of the current
+	/// vertical mosaic block.
+	mosaic_reference: [(i32, i32); 2],
+	/// Scratch space for each background layer of the current scanline.
+	backgrounds: [Line; 4],
+	/// Scratch space for the object layer of the current scanline.
+	objects: ObjectLine,
+	/// Scratch space for the pixels covered by the object window.
+	object_window: [bool; SCREEN_WIDTH],
+	/// Scratch space for the window mask of the current scanline.
+	window: WindowLine,
+	/// When set, lines drawn with forced blank enabled come out white like they
+	/// do on real hardware. Turning it off draws them as usual instead, which
+	/// can be handy for seeing what a game is loading while the screen is off.
+	pub show_forced_blank: bool,
+	/// Whether colors are adjusted to look like they did on the original LCD.
+	color_correction: bool,
+	/// Converts each 15-bit color into the RGBA value written to the
+	/// framebuffer. Built ahead of time, since color correction is too slow to
+	/// do for every pixel.
+	colors: Vec<[u8; 4]>,
+}
+
+impl Ppu {
+	pub fn init() -> Self {
+		Self {
+			framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+			affine_reference: [(0, 0); 2],
+			mosaic_line: [0; 2],
+			mosaic_reference: [(0, 0); 2],
+			backgrounds: [[None; SCREEN_WIDTH]; 4],
+			objects: [None; SCREEN_WIDTH],
+			object_window: [false; SCREEN_WIDTH],
+			window: [0; SCREEN_WIDTH],
+			show_forced_blank: true,
+			color_correction: false,
+			colors: (0..0x8000).map(to_rgba).collect(),
+		}
+	}
+
+	/// Switches LCD color correction on or off.
+	pub fn set_color_correction(&mut self, enabled: bool) {
+		if enabled == self.color_correction {
+			return;
+		}
+
+		let convert = if enabled { to_lcd_rgba } else { to_rgba };
+		self.color_correction = enabled;
+		self.colors = (0..0x8000).map(convert).collect();
+	}
+
+	/// Checks if LCD color correction is turned on.
+	pub fn color_correction(&self) -> bool {
+		self.color_correction
+	}
+
+	/// Starts drawing from the top of the screen, and schedules the events for
+	/// the first line.
+	pub fn reset(&mut self, memory: &mut Memory) {
+		memory.set_io_register(VCOUNT, 0);
+		memory.set_io_register(DISPSTAT, memory.read_half_word(DISPSTAT) & !0b111);
+		memory.scheduler.schedule(HDRAW_CYCLES, Event::HBlank);
+		memory.scheduler.schedule(LINE_CYCLES, Event::LineEnd);
+	}
+
+	/// Called when the visible part of a line has been drawn. On real hardware
+	/// pixels are drawn as the line progresses, but drawing the whole line at
+	/// once here is close enough for almost every game.
+	pub fn hblank(&mut self, memory: &mut Memory, time: u64) {
+		let line = memory.read_half_word(VCOUNT) as usize;
+
+		if line < SCREEN_HEIGHT {
+			for index in 0..2 {
+				if memory.affine_reference_written[index] {
+					self.latch_background_reference(memory, index);
+					memory.affine_reference_written[index] = false;
+				}
+			}
+
+			self.render_scanline(memory, line);
+			memory.trigger_dma(Timing::HBlank);
+		}
+
+		if (2..SCREEN_HEIGHT + 2).contains(&line) {
+			memory.trigger_video_capture();
+		}
+
+		let status = memory.read_half_word(DISPSTAT) | HBLANK_FLAG;
+		memory.set_io_register(DISPSTAT, status);
+
+		if status & HBLANK_IRQ > 0 {
+			memory.request_interrupt(Interrupt::HBlank);
+		}
+
+		memory
+			.scheduler
+			.schedule_at(time + LINE_CYCLES, Event::HBlank);
+	}
+
+	/// Called at the end of each line to move on to the next one. Returns true
+	/// if V-Blank has just started, which means that the frame is finished.
+	pub fn end_line(&mut self, memory: &mut Memory, time: u64) -> bool {
+		let line = (memory.read_half_word(VCOUNT) as usize + 1) % TOTAL_LINES;
+		let mut status = memory.read_half_word(DISPSTAT) & !HBLANK_FLAG;
+
+		memory.set_io_register(VCOUNT, line as u16);
+
+		// The V-Blank flag is cleared one line early, on the last line of the
+		// frame, even though that line is still in V-Blank.
+		if line == SCREEN_HEIGHT {
+			status |= VBLANK_FLAG;
+			self.latch_affine_reference(memory);
+			memory.affine_reference_written = [false; 2];
+			memory.trigger_dma(Timing::VBlank);
+
+			if status & VBLANK_IRQ > 0 {
+				memory.request_interrupt(Interrupt::VBlank);
+			}
+		} else if line == SCREEN_HEIGHT + 2 {
+			memory.stop_video_capture();
+		} else if line == TOTAL_LINES - 1 {
+			status &= !VBLANK_FLAG;
+		}
+
+		if line == (status >> 8) as usize {
+			status |= VCOUNT_FLAG;
+
+			if status & VCOUNT_IRQ > 0 {
+				memory.request_interrupt(Interrupt::VCount);
+			}
+		} else {
+			status &= !VCOUNT_FLAG;
+		}
+
+		memory.set_io_register(DISPSTAT, status);
+		memory
+			.scheduler
+			.schedule_at(time + LINE_CYCLES, Event::LineEnd);
+
+		line == SCREEN_HEIGHT
+	}
+
+	/// Render every scanline of a frame in one go, without any timing.
+	pub fn render_frame(&mut self, memory: &Memory) {
+		self.latch_affine_reference(memory);
+
+		for line in 0..SCREEN_HEIGHT {
+			self.render_scanline(memory, line);
+		}
+	}
+
+	/// Copies the BG2X/BG2Y and BG3X/BG3Y registers into the internal reference
+	/// points. The registers are 28-bit signed fixed point numbers, so they need
+	/// to be sign extended.
+	pub fn latch_affine_reference(&mut self, memory: &Memory) {
+		for index in 0..2 {
+			self.latch_background_reference(memory, index);
+		}
+	}
+
+	/// Does the same as `latch_affine_reference`, but for just one background.
+	fn latch_background_reference(&mut self, memory: &Memory, index: usize) {
+		let offset = index as u32 * 0x10;
+		let x = memory.read_word(BG2X + offset);
+		let y = memory.read_word(BG2Y + offset);
+
+		self.affine_reference[index] = (((x << 4) as i32) >> 4, ((y << 4) as i32) >> 4);
+	}
+
+	/// Draws a single line of the screen into the framebuffer.
+	pub fn render_scanline(&mut self, memory: &Memory, line: usize) {
+		let control = memory.read_half_word(DISPCNT);
+		let mode = control & 7;
+
+		if control & FORCED_BLANK > 0 && self.show_forced_blank {
+			let white = self.colors[0x7fff];
+			let row = &mut self.framebuffer[line * SCREEN_WIDTH * 4..(line + 1) * SCREEN_WIDTH * 4];
+
+			for pixel in row.chunks_exact_mut(4) {
+				pixel.copy_from_slice(&white);
+			}
+
+			self.advance_affine_reference(memory);
+			return;
+		}
+
+		// Which backgrounds are available depends on the display mode, but even
+		// available backgrounds still need to be enabled in DISPCNT.
+		let available: &[usize] = match mode {
+			0 => &[0, 1, 2, 3],
+			1 => &[0, 1, 2],
+			2 => &[2, 3],
+			3..=5 => &[2],
+			_ => &[],
+		};
+		let enabled = available
+			.iter()
+			.copied()
+			.filter(|bg| control >> (8 + bg) & 1 > 0)
+			.collect::<Vec<_>>();
+
+		let mosaic = memory.read_half_word(MOSAIC);
+		self.update_mosaic(mosaic, line);
+
+		for &bg in &enabled {
+			let layer = &mut self.backgrounds[bg];
+			layer.fill(None);
+
+			// Mosaic backgrounds keep drawing the first line of the mosaic block,
+			// and then stretch pixels horizontally once the line is drawn.
+			let mosaic_enabled = background::is_mosaic(memory, bg);
+			let (source_line, reference) = if mosaic_enabled {
+				(self.mosaic_line[0], &self.mosaic_reference)
+			} else {
+				(line, &self.affine_reference)
+			};
+
+			match (mode, bg) {
+				(0, _) | (1, 0) | (1, 1) => {
+					background::render_text_line(memory, bg, source_line, layer)
+				}
+				(1, _) | (2, _) => {
+					background::render_affine_line(memory, bg, reference[bg - 2], layer)
+				}
+				_ => background::render_bitmap_line(memory, mode, source_line, layer),
+			}
+
+			if mosaic_enabled {
+				background::apply_horizontal_mosaic(layer, (mosaic & 0xf) as usize + 1);
+			}
+		}
+
+		self.advance_affine_reference(memory);
+
+		if control >> 12 & 1 > 0 {
+			object::render_object_line(
+				memory,
+				line,
+				self.mosaic_line[1],
+				&mut self.objects,
+				&mut self.object_window,
+			);
+		} else {
+			self.objects.fill(None);
+			self.object_window.fill(false);
+		}
+
+		window::render_window_line(memory, line, &self.object_window, &mut self.window);
+
+		// Layers with a lower priority value are drawn on top. When two layers
+		// have the same priority, the one with the lower number wins.
+		let mut enabled = enabled
+			.into_iter()
+			.map(|bg| (background::priority(memory, bg), bg))
+			.collect::<Vec<_>>();
+		enabled.sort();
+
+		let blend = Blend::read(memory);
+		let backdrop = LayerPixel {
+			layer: BACKDROP_LAYER,
+			color: palette_color(memory, 0),
+		};
+		let row = &mut self.framebuffer[line * SCREEN_WIDTH * 4..(line + 1) * SCREEN_WIDTH * 4];
+
+		for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+			let mask = self.window[x];
+			let mut object = self.objects[x].filter(|_| mask & window::OBJECT_ENABLE > 0);
+			let semi_transparent = object.is_some_and(|object| object.semi_transparent);
+
+			// Find the two topmost visible pixels, since color special effects
+			// need to know what is underneath. Objects are drawn in front of
+			// backgrounds with the same priority.
+			let mut layers = [backdrop; 2];
+			let mut found = 0;
+			for &(priority, bg) in &enabled {
+				if let Some(visible) = object.filter(|object| object.priority <= priority) {
+					layers[found] = LayerPixel {
+						layer: OBJECT_LAYER,
+						color: visible.color,
+					};
+					found += 1;
+					object = None;
+				}
+				if found == 2 {
+					break;
+				}
+
+				if let Some(color) = self.backgrounds[bg][x].filter(|_| mask >> bg & 1 > 0) {
+					layers[found] = LayerPixel { layer: bg, color };
+					found += 1;
+				}
+				if found == 2 {
+					break;
+				}
+			}
+			if let Some(object) = object.filter(|_| found < 2) {
+				layers[found] = LayerPixel {
+					layer: OBJECT_LAYER,
+					color: object.color,
+				};
+			}
+
+			// Only objects that actually ended up on top get to be semi-transparent
+			let semi_transparent = semi_transparent && layers[0].layer == OBJECT_LAYER;
+			let effects = mask & window::EFFECTS_ENABLE > 0;
+			let color = blend.apply(layers[0], layers[1], semi_transparent, effects);
+
+			pixel.copy_from_slice(&self.colors[color as usize & 0x7fff]);
+		}
+	}
+
+	/// Starts a new vertical mosaic block for backgrounds and objects whenever
+	/// the previous one has reached its full height. Blocks always restart at
+	/// the top of the screen.
+	fn update_mosaic(&mut self, mosaic: u16, line: usize) {
+		let heights = [
+			(mosaic >> 4 & 0xf) as usize + 1,
+			(mosaic >> 12) as usize + 1,
+		];
+
+		for (start, height) in self.mosaic_line.iter_mut().zip(heights) {
+			if line <= *start || line - *start >= height {
+				*start = line;
+			}
+		}
+
+		if self.mosaic_line[0] == line {
+			self.mosaic_reference = self.affine_reference;
+		}
+	}
+
+	/// Moves the internal affine reference points down by one line.
+	fn advance_affine_reference(&mut self, memory: &Memory) {
+		for (index, reference) in self.affine_reference.iter_mut().enumerate() {
+			let offset = index as u32 * 0x10;
+			let dmx = memory.read_half_word(BG2PB + offset) as i16 as i32;
+			let dmy = memory.read_half_word(BG2PD + offset) as i16 as i32;
+
+			reference.0 = reference.0.wrapping_add(dmx);
+			reference.1 = reference.1.wrapping_add(dmy);
+		}
+	}
+}
+
+/// Reads a color from the background half of palette memory.
+pub fn palette_color(memory: &Memory, index: usize) -> u16 {
+	u16::from_le_bytes([memory.palette[index * 2], memory.palette[index * 2 + 1]])
+}
+
+/// Expands a 15-bit BGR color into 8-bit RGBA. The low bits are filled in with
+/// the high bits so that white is actually white, rather than slightly grey.
+pub fn to_rgba(color: u16) -> [u8; 4] {
+	let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+
+	[
+		expand(color & 0x1f),
+		expand(color >> 5 & 0x1f),
+		expand(color >> 10 & 0x1f),
+		0xff,
+	]
+}
+
+/// Approximates how a color looked on the GBA's own screen, which was much
+/// darker and less saturated than a modern display. The LCD is treated as having
+/// a gamma of 4, with each channel bleeding a little into the others, and then
+/// the result is converted back to the usual gamma of 2.2.
+pub fn to_lcd_rgba(color: u16) -> [u8; 4] {
+	let channel = |shift: u16| ((color >> shift & 0x1f) as f64 / 31.0).powf(4.0);
+	let (r, g, b) = (channel(0), channel(5), channel(10));
+	let mix =
+		|r: f64, g: f64, b: f64| ((r + g + b) / 255.0).powf(1.0 / 2.2) * 255.0 * 255.0 / 280.0;
+
+	[
+		mix(255.0 * r, 50.0 * g, 0.0) as u8,
+		mix(10.0 * r, 230.0 * g, 30.0 * b) as u8,
+		mix(50.0 * r, 10.0 * g, 220.0 * b) as u8,
+		0xff,
+	]
+}
+
+/// Settings like color correction belong to the player rather than the game, so
+/// they aren't included.
+impl Snapshot for Ppu {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.bytes(&self.framebuffer);
+
+		for background in 0..2 {
+			let (x, y) = self.affine_reference[background];
+			state.i32(x);
+			state.i32(y);
+			let (x, y) = self.mosaic_reference[background];
+			state.i32(x);
+			state.i32(y);
+			state.u32(self.mosaic_line[background] as u32);
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		state.fill(&mut self.framebuffer)?;
+
+		for background in 0..2 {
+			self.affine_reference[background] = (state.i32()?, state.i32()?);
+			self.mosaic_reference[background] = (state.i32()?, state.i32()?);
+			self.mosaic_line[background] = state.u32()? as usize;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::{OBJECT_ATTRIBUTE_START, PALETTE_START, VRAM_START};
+
+	fn pixel(ppu: &Ppu, x: usize, y: usize) -> [u8; 4] {
+		let i = (y * SCREEN_WIDTH + x) * 4;
+		ppu.framebuffer[i..i + 4].try_into().unwrap()
+	}
+
+	#[test]
+	fn backdrop() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		memory.write_half_word(PALETTE_START as u32, 0x001f);
+		ppu.render_frame(&memory);
+
+		assert_eq!(pixel(&ppu, 0, 0), [0xff, 0, 0, 0xff]);
+		assert_eq!(pixel(&ppu, 239, 159), [0xff, 0, 0, 0xff]);
+	}
+
+	#[test]
+	fn text_background_priority() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		// Mode 0 with BG0 and BG1 enabled. BG0 uses screen block 1, BG1 uses
+		// screen block 2, and both use character block 0.
+		memory.write_half_word(DISPCNT, 0x0300);
+		memory.write_half_word(BG0CNT, 0x0101);
+		memory.write_half_word(BG0CNT + 2, 0x0200);
+
+		// Tile 1 is solid color 1, tile 2 is solid color 2
+		for i in 0..32 {
+			memory.write_byte(VRAM_START as u32 + 32 + i, 0x11);
+			memory.write_byte(VRAM_START as u32 + 64 + i, 0x22);
+		}
+		memory.write_half_word(PALETTE_START as u32 + 2, 0x001f);
+		memory.write_half_word(PALETTE_START as u32 + 4, 0x03e0);
+
+		// BG0 draws tile 1 in the top left corner, and BG1 draws tile 2 in the
+		// top left two tiles
+		memory.write_half_word(VRAM_START as u32 + 0x800, 1);
+		memory.write_half_word(VRAM_START as u32 + 0x1000, 2);
+		memory.write_half_word(VRAM_START as u32 + 0x1002, 2);
+
+		ppu.render_frame(&memory);
+
+		// BG1 has a higher priority (lower value) so it should be on top
+		assert_eq!(pixel(&ppu, 0, 0), [0, 0xff, 0, 0xff]);
+
+		// Swap the priorities, and then BG0 should be on top where it is opaque
+		memory.write_half_word(BG0CNT, 0x0100);
+		memory.write_half_word(BG0CNT + 2, 0x0201);
+		ppu.render_frame(&memory);
+
+		assert_eq!(pixel(&ppu, 0, 0), [0xff, 0, 0, 0xff]);
+		assert_eq!(pixel(&ppu, 8, 0), [0, 0xff, 0, 0xff]);
+		assert_eq!(pixel(&ppu, 16, 0), [0, 0, 0, 0xff]);
+	}
+
+	#[test]
+	fn objects_in_front_of_backgrounds() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		// Mode 0 with BG0 and objects enabled. BG0 is filled with tile 1 from
+		// screen block 1, and has priority 1.
+		memory.write_half_word(DISPCNT, 0x1140);
+		memory.write_half_word(BG0CNT, 0x0101);
+		for i in 0..32 {
+			memory.write_byte(VRAM_START as u32 + 32 + i, 0x11);
+			memory.write_byte(VRAM_START as u32 + 0x1_0020 + i, 0x11);
+		}
+		for i in 0..32 * 32 {
+			memory.write_half_word(VRAM_START as u32 + 0x800 + i * 2, 1);
+		}
+		memory.write_half_word(PALETTE_START as u32 + 2, 0x001f);
+		memory.write_half_word(PALETTE_START as u32 + 0x202, 0x7c00);
+
+		// An 8x8 object at (0, 0) using tile 1, with priority 1. Every other
+		// object is hidden.
+		for index in 1..128 {
+			memory.write_half_word(OBJECT_ATTRIBUTE_START as u32 + index * 8, 0x0200);
+		}
+		memory.write_half_word(OBJECT_ATTRIBUTE_START as u32 + 4, 0x0401);
+		ppu.render_frame(&memory);
+
+		assert_eq!(pixel(&ppu, 0, 0), [0, 0, 0xff, 0xff]);
+		assert_eq!(pixel(&ppu, 8, 0), [0xff, 0, 0, 0xff]);
+
+		// Objects with a lower priority go behind the background
+		memory.write_half_word(OBJECT_ATTRIBUTE_START as u32 + 4, 0x0801);
+		ppu.render_frame(&memory);
+
+		assert_eq!(pixel(&ppu, 0, 0), [0xff, 0, 0, 0xff]);
+	}
+
+	#[test]
+	fn windows_and_blending() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		// Mode 3 with BG2 enabled, filled with red, and a green backdrop
+		memory.write_half_word(DISPCNT, 0x0403);
+		for i in 0..SCREEN_WIDTH * SCREEN_HEIGHT {
+			memory.write_half_word(VRAM_START as u32 + i as u32 * 2, 0x001f);
+		}
+		memory.write_half_word(PALETTE_START as u32, 0x03e0);
+
+		// Blend BG2 with the backdrop at half strength
+		memory.write_half_word(blend::BLDCNT, 0x2044);
+		memory.write_half_word(blend::BLDALPHA, 0x0808);
+		ppu.render_frame(&memory);
+		assert_eq!(pixel(&ppu, 0, 0), [0x7b, 0x7b, 0, 0xff]);
+
+		// Enable window 0 on the left half of the screen, where BG2 is hidden.
+		// Outside of the window BG2 is visible, but effects are disabled.
+		memory.write_half_word(DISPCNT, 0x2403);
+		memory.write_half_word(window::WIN0H, 120);
+		memory.write_half_word(window::WIN0V, 160);
+		memory.write_half_word(window::WININ, 0x0020);
+		memory.write_half_word(window::WINOUT, 0x0004);
+		ppu.render_frame(&memory);
+		assert_eq!(pixel(&ppu, 0, 0), [0, 0xff, 0, 0xff]);
+		assert_eq!(pixel(&ppu, 120, 0), [0xff, 0, 0, 0xff]);
+	}
+
+	// The mosaic tests here and in the layers check the behavior that GBATEK
+	// describes, and haven't been compared against screenshots of a test ROM.
+	// The CPU can't run a test ROM far enough to draw anything yet, since most
+	// of the ARM instructions are still stubs.
+	#[test]
+	fn background_mosaic() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		// Mode 3 where every pixel has a unique color based on its position
+		memory.write_half_word(DISPCNT, 0x0403);
+		for y in 0..SCREEN_HEIGHT {
+			for x in 0..SCREEN_WIDTH {
+				let color = (x as u16 & 0x1f) | (y as u16 & 0x1f) << 5;
+				memory
+					.write_half_word(VRAM_START as u32 + (y * SCREEN_WIDTH + x) as u32 * 2, color);
+			}
+		}
+
+		// 3x5 mosaic blocks on BG2
+		memory.write_half_word(BG0CNT + 4, 0x0040);
+		memory.write_half_word(MOSAIC, 0x0042);
+		ppu.render_frame(&memory);
+
+		// Every pixel should take its color from the top left of its block
+		for y in 0..SCREEN_HEIGHT {
+			for x in 0..SCREEN_WIDTH {
+				let expected = ((x - x % 3) as u16 & 0x1f) | ((y - y % 5) as u16 & 0x1f) << 5;
+				assert_eq!(pixel(&ppu, x, y), to_rgba(expected), "pixel ({}, {})", x, y);
+			}
+		}
+	}
+
+	#[test]
+	fn mosaic_size_changes_after_block() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		memory.write_half_word(DISPCNT, 0x0403);
+		for y in 0..SCREEN_HEIGHT {
+			memory.write_half_word(VRAM_START as u32 + (y * SCREEN_WIDTH) as u32 * 2, y as u16);
+		}
+		memory.write_half_word(BG0CNT + 4, 0x0040);
+
+		// Start with blocks that are 4 lines tall, and then switch to 2 lines
+		// on line 5. The new blocks should line up with the block that started
+		// on line 4, rather than with multiples of 2 from the top of the screen.
+		memory.write_half_word(MOSAIC, 0x0030);
+		ppu.latch_affine_reference(&memory);
+		for line in 0..5 {
+			ppu.render_scanline(&memory, line);
+		}
+		memory.write_half_word(MOSAIC, 0x0010);
+		for line in 5..10 {
+			ppu.render_scanline(&memory, line);
+		}
+
+		let lines = (0..10).map(|y| pixel(&ppu, 0, y)).collect::<Vec<_>>();
+		let expected = [0, 0, 0, 0, 4, 4, 6, 6, 8, 8]
+			.iter()
+			.map(|&y| to_rgba(y))
+			.collect::<Vec<_>>();
+		assert_eq!(lines, expected);
+	}
+
+	#[test]
+	fn bitmap_mode_3() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		memory.write_half_word(DISPCNT, 0x0403);
+		memory.write_half_word(VRAM_START as u32 + (10 + 20 * 240) * 2, 0x7c00);
+		ppu.render_frame(&memory);
+
+		assert_eq!(pixel(&ppu, 10, 20), [0, 0, 0xff, 0xff]);
+		assert_eq!(pixel(&ppu, 11, 20), [0, 0, 0, 0xff]);
+	}
+
+	#[test]
+	fn display_status() {
+		use crate::interrupts::IF;
+
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		// Match line 5, and enable H-Blank and V-Count interrupts
+		memory.write_half_word(DISPSTAT, 5 << 8 | 0b11_0000);
+		ppu.reset(&mut memory);
+
+		ppu.hblank(&mut memory, HDRAW_CYCLES);
+		assert_eq!(memory.read_half_word(DISPSTAT) & 0b111, 0b010);
+		assert_eq!(memory.read_half_word(IF), 0b010);
+
+		// The status bits can't be changed by the CPU
+		memory.write_half_word(DISPSTAT, 5 << 8 | 0b10_0000);
+		assert_eq!(memory.read_half_word(DISPSTAT), 5 << 8 | 0b10_0010);
+		memory.write_half_word(IF, 0xffff);
+
+		for line in 1..=5 {
+			ppu.end_line(&mut memory, line * LINE_CYCLES);
+		}
+		assert_eq!(memory.read_half_word(VCOUNT), 5);
+		assert_eq!(memory.read_half_word(DISPSTAT) & 0b111, 0b100);
+		assert_eq!(memory.read_half_word(IF), 0b100);
+
+		for line in 6..=SCREEN_HEIGHT as u64 {
+			ppu.end_line(&mut memory, line * LINE_CYCLES);
+		}
+		assert_eq!(memory.read_half_word(DISPSTAT) & 0b111, 0b001);
+
+		// The V-Blank flag is cleared on the last line, and then the count wraps
+		for line in SCREEN_HEIGHT as u64 + 1..TOTAL_LINES as u64 {
+			ppu.end_line(&mut memory, line * LINE_CYCLES);
+		}
+		assert_eq!(memory.read_half_word(VCOUNT), 227);
+		assert_eq!(memory.read_half_word(DISPSTAT) & 0b111, 0b000);
+
+		assert!(!ppu.end_line(&mut memory, 0));
+		assert_eq!(memory.read_half_word(VCOUNT), 0);
+	}
+
+	#[test]
+	fn forced_blank() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		memory.write_half_word(DISPCNT, 0x0080);
+		ppu.render_scanline(&memory, 0);
+		assert_eq!(pixel(&ppu, 0, 0), [0xff; 4]);
+
+		// With forced blank hidden, the line should be drawn as normal
+		ppu.show_forced_blank = false;
+		ppu.render_scanline(&memory, 0);
+		assert_eq!(pixel(&ppu, 0, 0), [0, 0, 0, 0xff]);
+	}
+
+	#[test]
+	fn color_correction() {
+		let mut memory = Memory::init();
+		let mut ppu = Ppu::init();
+
+		memory.write_half_word(PALETTE_START as u32, 0x001f);
+		ppu.set_color_correction(true);
+		ppu.render_scanline(&memory, 0);
+
+		// Pure red should come out darker and bleed into the other channels
+		let [r, g, b, a] = pixel(&ppu, 0, 0);
+		assert!(r < 0xff && g > 0 && b > 0 && a == 0xff);
+		assert_eq!(to_lcd_rgba(0), [0, 0, 0, 0xff]);
+
+		ppu.set_color_correction(false);
+		ppu.render_scanline(&memory, 0);
+		assert_eq!(pixel(&ppu, 0, 0), [0xff, 0, 0, 0xff]);
+	}
+}
@@ -0,0 +1,277 @@
+use super::{palette_color, Line, BG0CNT, BG0HOFS, BG0VOFS, BG2PA, BG2PC, DISPCNT, SCREEN_WIDTH};
+use crate::memory::Memory;
+
+/// Backgrounds can only read tile data from the first 64KB of VRAM. The rest is
+/// reserved for objects.
+const BACKGROUND_VRAM_SIZE: usize = 0x1_0000;
+
+/// Returns the priority of a background, where 0 is drawn on top of everything.
+pub fn priority(memory: &Memory, bg: usize) -> u16 {
+	memory.read_half_word(BG0CNT + bg as u32 * 2) & 3
+}
+
+/// Checks if the mosaic effect is enabled for a background.
+pub fn is_mosaic(memory: &Memory, bg: usize) -> bool {
+	memory.read_half_word(BG0CNT + bg as u32 * 2) >> 6 & 1 > 0
+}
+
+/// Stretches the first pixel of each horizontal mosaic block across the whole
+/// block. The blocks always start from the left edge of the screen, regardless
+/// of how the background is scrolled.
+pub fn apply_horizontal_mosaic(line: &mut Line, size: usize) {
+	if size <= 1 {
+		return;
+	}
+
+	for block in line.chunks_mut(size) {
+		let first = block[0];
+		block.fill(first);
+	}
+}
+
+/// Reads the color index of a pixel within a tile. Returns `None` if the pixel
+/// is transparent, which is always the case for color 0.
+fn tile_pixel(memory: &Memory, address: usize, x: usize, full_color: bool) -> Option<usize> {
+	if address >= BACKGROUND_VRAM_SIZE {
+		return None;
+	}
+
+	let index = if full_color {
+		memory.vram[address] as usize
+	} else {
+		// Two pixels are packed into each byte, with the left pixel in the low
+		// nibble.
+		(memory.vram[address] >> ((x & 1) * 4) & 0xf) as usize
+	};
+
+	Some(index).filter(|&index| index != 0)
+}
+
+/// Renders one line of a text (regular, non-affine) background. Text
+/// backgrounds are made up of 32x32 tile screen blocks, and can be 256 or 512
+/// pixels wide and tall. They scroll using the BGxHOFS and BGxVOFS registers,
+/// and wrap around at the edges.
+pub fn render_text_line(memory: &Memory, bg: usize, line: usize, out: &mut Line) {
+	let control = memory.read_half_word(BG0CNT + bg as u32 * 2) as usize;
+	let horizontal_offset = memory.read_half_word(BG0HOFS + bg as u32 * 4) as usize & 0x1ff;
+	let vertical_offset = memory.read_half_word(BG0VOFS + bg as u32 * 4) as usize & 0x1ff;
+
+	let character_base = (control >> 2 & 3) * 0x4000;
+	let screen_base = (control >> 8 & 0x1f) * 0x800;
+	let full_color = control >> 7 & 1 > 0;
+	let (width, height) = match control >> 14 {
+		0 => (256, 256),
+		1 => (512, 256),
+		2 => (256, 512),
+		_ => (512, 512),
+	};
+
+	let y = (line + vertical_offset) % height;
+
+	for (screen_x, pixel) in out.iter_mut().enumerate() {
+		let x = (screen_x + horizontal_offset) % width;
+
+		// Each screen block is 256x256 pixels, and they are laid out left to
+		// right, then top to bottom.
+		let block = x / 256 + y / 256 * (width / 256);
+		let entry_address = screen_base + block * 0x800 + (y % 256 / 8 * 32 + x % 256 / 8) * 2;
+		if entry_address >= BACKGROUND_VRAM_SIZE {
+			continue;
+		}
+
+		let entry = u16::from_le_bytes([memory.vram[entry_address], memory.vram[entry_address + 1]])
+			as usize;
+		let tile = entry & 0x3ff;
+		let horizontal_flip = entry >> 10 & 1 > 0;
+		let vertical_flip = entry >> 11 & 1 > 0;
+		let palette_bank = entry >> 12;
+
+		let tile_x = if horizontal_flip { 7 - x % 8 } else { x % 8 };
+		let tile_y = if vertical_flip { 7 - y % 8 } else { y % 8 };
+
+		*pixel = if full_color {
+			let address = character_base + tile * 64 + tile_y * 8 + tile_x;
+			tile_pixel(memory, address, tile_x, true).map(|index| palette_color(memory, index))
+		} else {
+			let address = character_base + tile * 32 + tile_y * 4 + tile_x / 2;
+			tile_pixel(memory, address, tile_x, false)
+				.map(|index| palette_color(memory, palette_bank * 16 + index))
+		};
+	}
+}
+
+/// Renders one line of an affine (rotation/scaling) background. Each pixel on
+/// the screen is mapped back onto the background by the matrix in the
+/// BGxPA-BGxPD registers, starting from the current internal reference point.
+/// Affine backgrounds always use 256 color tiles, and their map entries are a
+/// single byte containing only the tile number.
+pub fn render_affine_line(memory: &Memory, bg: usize, reference: (i32, i32), out: &mut Line) {
+	let control = memory.read_half_word(BG0CNT + bg as u32 * 2) as usize;
+	let parameters = BG2PA + (bg as u32 - 2) * 0x10;
+	let dx = memory.read_half_word(parameters) as i16 as i32;
+	let dy = memory.read_half_word(parameters + (BG2PC - BG2PA)) as i16 as i32;
+
+	let character_base = (control >> 2 & 3) * 0x4000;
+	let screen_base = (control >> 8 & 0x1f) * 0x800;
+	let wrap = control >> 13 & 1 > 0;
+	let size = 128 << (control >> 14);
+
+	let (mut texture_x, mut texture_y) = reference;
+
+	for pixel in out.iter_mut() {
+		// The reference point is fixed point with 8 fractional bits
+		let x = texture_x >> 8;
+		let y = texture_y >> 8;
+		texture_x = texture_x.wrapping_add(dx);
+		texture_y = texture_y.wrapping_add(dy);
+
+		let (x, y) = if wrap {
+			(x.rem_euclid(size) as usize, y.rem_euclid(size) as usize)
+		} else if (0..size).contains(&x) && (0..size).contains(&y) {
+			(x as usize, y as usize)
+		} else {
+			continue;
+		};
+
+		let entry_address = screen_base + y / 8 * (size as usize / 8) + x / 8;
+		if entry_address >= BACKGROUND_VRAM_SIZE {
+			continue;
+		}
+
+		let tile = memory.vram[entry_address] as usize;
+		let address = character_base + tile * 64 + y % 8 * 8 + x % 8;
+		*pixel = tile_pixel(memory, address, x, true).map(|index| palette_color(memory, index));
+	}
+}
+
+/// Renders one line of BG2 in one of the bitmap modes (3, 4 or 5). Modes 4 and
+/// 5 have two pages, and DISPCNT selects which one is displayed.
+pub fn render_bitmap_line(memory: &Memory, mode: u16, line: usize, out: &mut Line) {
+	let page = if memory.read_half_word(DISPCNT) >> 4 & 1 > 0 {
+		0xa000
+	} else {
+		0
+	};
+
+	for (x, pixel) in out.iter_mut().enumerate() {
+		*pixel = match mode {
+			3 => {
+				let address = (line * SCREEN_WIDTH + x) * 2;
+				Some(u16::from_le_bytes([
+					memory.vram[address],
+					memory.vram[address + 1],
+				]))
+			}
+			4 => {
+				let index = memory.vram[page + line * SCREEN_WIDTH + x] as usize;
+				Some(index)
+					.filter(|&index| index != 0)
+					.map(|index| palette_color(memory, index))
+			}
+			// Mode 5 is only 160x128, and the rest of the screen shows the
+			// backdrop.
+			5 if x < 160 && line < 128 => {
+				let address = page + (line * 160 + x) * 2;
+				Some(u16::from_le_bytes([
+					memory.vram[address],
+					memory.vram[address + 1],
+				]))
+			}
+			_ => None,
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::VRAM_START;
+	use crate::ppu::SCREEN_WIDTH;
+
+	/// Fills tile `tile` of character block 0 with 4-bit pixels, where the value
+	/// of each pixel is its column within the tile plus one.
+	fn write_gradient_tile(memory: &mut Memory, tile: u32) {
+		for row in 0..8 {
+			for pair in 0..4 {
+				let value = (pair * 2 + 1) | (pair * 2 + 2) << 4;
+				memory.write_byte(VRAM_START as u32 + tile * 32 + row * 4 + pair, value as u8);
+			}
+		}
+	}
+
+	#[test]
+	fn text_flip_and_palette_bank() {
+		let mut memory = Memory::init();
+		let mut line = [None; SCREEN_WIDTH];
+
+		// BG0 with screen block 1
+		memory.write_half_word(BG0CNT, 0x0100);
+		write_gradient_tile(&mut memory, 1);
+		for color in 0..32 {
+			memory.write_half_word(0x0500_0000 + color * 2, color as u16);
+		}
+
+		// Tile 1 normally, then horizontally flipped using palette bank 1
+		memory.write_half_word(VRAM_START as u32 + 0x800, 0x0001);
+		memory.write_half_word(VRAM_START as u32 + 0x802, 0x1401);
+
+		render_text_line(&memory, 0, 0, &mut line);
+
+		assert_eq!(line[0], Some(1));
+		assert_eq!(line[7], Some(8));
+		assert_eq!(line[8], Some(16 + 8));
+		assert_eq!(line[15], Some(16 + 1));
+		// Tile 0 is empty, and so should be transparent
+		assert_eq!(line[16], None);
+	}
+
+	#[test]
+	fn text_scrolling_wraps() {
+		let mut memory = Memory::init();
+		let mut line = [None; SCREEN_WIDTH];
+
+		memory.write_half_word(BG0CNT, 0x0100);
+		write_gradient_tile(&mut memory, 1);
+		for color in 0..16 {
+			memory.write_half_word(0x0500_0000 + color * 2, color as u16);
+		}
+
+		// Put the tile in the last column of the screen block and scroll so that
+		// it is partially visible on the left edge.
+		memory.write_half_word(VRAM_START as u32 + 0x800 + 31 * 2, 1);
+		memory.write_half_word(BG0HOFS, 252);
+
+		render_text_line(&memory, 0, 0, &mut line);
+
+		assert_eq!(line[0], Some(5));
+		assert_eq!(line[3], Some(8));
+		assert_eq!(line[4], None);
+		assert_eq!(line[239], None);
+	}
+
+	#[test]
+	fn affine_wraparound() {
+		let mut memory = Memory::init();
+		let mut line = [None; SCREEN_WIDTH];
+
+		// 128x128 affine BG2 using screen block 1, with an identity matrix
+		memory.write_half_word(BG0CNT + 4, 0x0100);
+		memory.write_half_word(BG2PA, 0x0100);
+		memory.write_half_word(BG2PC, 0);
+		memory.write_byte(VRAM_START as u32 + 0x800, 1);
+		for i in 0..64 {
+			memory.write_byte(VRAM_START as u32 + 64 + i, 3);
+		}
+		memory.write_half_word(0x0500_0006, 0x1234);
+
+		render_affine_line(&memory, 2, (0, 0), &mut line);
+		assert_eq!(line[0], Some(0x1234));
+		assert_eq!(line[8], None);
+		assert_eq!(line[128], None);
+
+		// With wraparound enabled the tile should repeat every 128 pixels
+		memory.write_half_word(BG0CNT + 4, 0x2100);
+		render_affine_line(&memory, 2, (0, 0), &mut line);
+		assert_eq!(line[128], Some(0x1234));
+	}
+}
@@ -0,0 +1,173 @@
+use crate::memory::Memory;
+
+pub const BLDCNT: u32 = 0x0400_0050;
+pub const BLDALPHA: u32 = 0x0400_0052;
+pub const BLDY: u32 = 0x0400_0054;
+
+/// The layer number used by the object layer in BLDCNT. Layers 0-3 are the
+/// backgrounds.
+pub const OBJECT_LAYER: usize = 4;
+/// The layer number used by the backdrop in BLDCNT.
+pub const BACKDROP_LAYER: usize = 5;
+
+/// One of the two topmost visible pixels at a point on the screen, along with
+/// the layer it came from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LayerPixel {
+	pub layer: usize,
+	pub color: u16,
+}
+
+/// The color special effect settings for a scanline, decoded from BLDCNT,
+/// BLDALPHA and BLDY.
+pub struct Blend {
+	first_targets: u16,
+	second_targets: u16,
+	mode: u16,
+	first_weight: u16,
+	second_weight: u16,
+	brightness: u16,
+}
+
+impl Blend {
+	pub fn read(memory: &Memory) -> Self {
+		let control = memory.read_half_word(BLDCNT);
+		let alpha = memory.read_half_word(BLDALPHA);
+
+		// Coefficients are in sixteenths, and anything above 16 acts like 16
+		Self {
+			first_targets: control & 0x3f,
+			second_targets: control >> 8 & 0x3f,
+			mode: control >> 6 & 3,
+			first_weight: (alpha & 0x1f).min(16),
+			second_weight: (alpha >> 8 & 0x1f).min(16),
+			brightness: (memory.read_half_word(BLDY) & 0x1f).min(16),
+		}
+	}
+
+	fn is_first_target(&self, layer: usize) -> bool {
+		self.first_targets >> layer & 1 > 0
+	}
+
+	fn is_second_target(&self, layer: usize) -> bool {
+		self.second_targets >> layer & 1 > 0
+	}
+
+	/// Produces the final color of a pixel from the two topmost layers.
+	/// `semi_transparent` is set when the top pixel belongs to a semi-transparent
+	/// object, which forces alpha blending regardless of BLDCNT as long as the
+	/// pixel underneath is a second target. `effects` comes from the window
+	/// mask, and turns off all special effects when it isn't set.
+	pub fn apply(
+		&self,
+		top: LayerPixel,
+		below: LayerPixel,
+		semi_transparent: bool,
+		effects: bool,
+	) -> u16 {
+		if !effects {
+			return top.color;
+		}
+
+		if semi_transparent && self.is_second_target(below.layer) {
+			return self.alpha(top.color, below.color);
+		}
+
+		if !self.is_first_target(top.layer) {
+			return top.color;
+		}
+
+		match self.mode {
+			1 if self.is_second_target(below.layer) => self.alpha(top.color, below.color),
+			2 => map_channels(top.color, |c| c + (31 - c) * self.brightness / 16),
+			3 => map_channels(top.color, |c| c - c * self.brightness / 16),
+			_ => top.color,
+		}
+	}
+
+	fn alpha(&self, first: u16, second: u16) -> u16 {
+		let channel = |color: u16, shift: u16| color >> shift & 0x1f;
+
+		[0, 5, 10].iter().fold(0, |result, &shift| {
+			let mixed = (channel(first, shift) * self.first_weight
+				+ channel(second, shift) * self.second_weight)
+				/ 16;
+			result | mixed.min(31) << shift
+		})
+	}
+}
+
+/// Applies `map` to each of the red, green and blue channels of a 15-bit color.
+fn map_channels<F>(color: u16, map: F) -> u16
+where
+	F: Fn(u16) -> u16,
+{
+	[0, 5, 10].iter().fold(0, |result, &shift| {
+		result | map(color >> shift & 0x1f).min(31) << shift
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pixel(layer: usize, color: u16) -> LayerPixel {
+		LayerPixel { layer, color }
+	}
+
+	#[test]
+	fn alpha_blending() {
+		let mut memory = Memory::init();
+
+		// BG0 is the first target and the backdrop is the second, both at half
+		// strength
+		memory.write_half_word(BLDCNT, 0x2041);
+		memory.write_half_word(BLDALPHA, 0x0808);
+		let blend = Blend::read(&memory);
+
+		let top = pixel(0, 0x001f);
+		let backdrop = pixel(BACKDROP_LAYER, 0x03e0);
+		assert_eq!(blend.apply(top, backdrop, false, true), 0x01ef);
+
+		// BG1 isn't a second target, so nothing should happen
+		assert_eq!(blend.apply(top, pixel(1, 0x03e0), false, true), 0x001f);
+
+		// Effects disabled by the window
+		assert_eq!(blend.apply(top, backdrop, false, false), 0x001f);
+	}
+
+	#[test]
+	fn brightness() {
+		let mut memory = Memory::init();
+
+		memory.write_half_word(BLDCNT, 0x0081);
+		memory.write_half_word(BLDY, 8);
+		let blend = Blend::read(&memory);
+		assert_eq!(blend.apply(pixel(0, 0), pixel(1, 0), false, true), 0x3def);
+
+		memory.write_half_word(BLDCNT, 0x00c1);
+		memory.write_half_word(BLDY, 31);
+		let blend = Blend::read(&memory);
+		assert_eq!(blend.apply(pixel(0, 0x7fff), pixel(1, 0), false, true), 0);
+	}
+
+	#[test]
+	fn semi_transparent_objects() {
+		let mut memory = Memory::init();
+
+		// Brighten objects, with BG1 as a second target
+		memory.write_half_word(BLDCNT, 0x0290);
+		memory.write_half_word(BLDALPHA, 0x1010);
+		memory.write_half_word(BLDY, 16);
+		let blend = Blend::read(&memory);
+
+		let object = pixel(OBJECT_LAYER, 0x0001);
+
+		// Semi-transparent objects blend with second targets even though the
+		// blending mode is brightness
+		assert_eq!(blend.apply(object, pixel(1, 0x0002), true, true), 0x0003);
+
+		// If there is no second target underneath, the regular effect applies
+		assert_eq!(blend.apply(object, pixel(2, 0x0002), true, true), 0x7fff);
+	}
+}
@@ -0,0 +1,542 @@
+use super::{palette_color, DISPCNT, MOSAIC, SCREEN_WIDTH};
+use crate::memory::Memory;
+
+/// Object tiles live in the upper 32KB of VRAM.
+const OBJECT_VRAM_START: usize = 0x1_0000;
+
+/// One pixel of the object layer. Unlike backgrounds, the priority of the
+/// object layer can change from pixel to pixel, so it needs to be tracked along
+/// with the color.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ObjectPixel {
+	pub color: u16,
+	pub priority: u16,
+	/// Set for objects in semi-transparent mode, which are always alpha blended
+	/// with whatever is underneath them.
+	pub semi_transparent: bool,
+}
+
+/// A single row of the object layer.
+pub type ObjectLine = [Option<ObjectPixel>; SCREEN_WIDTH];
+
+/// The width and height of an object, indexed by shape and then by size.
+const OBJECT_SIZES: [[(i32, i32); 4]; 3] = [
+	// Square
+	[(8, 8), (16, 16), (32, 32), (64, 64)],
+	// Horizontal
+	[(16, 8), (32, 8), (32, 16), (64, 32)],
+	// Vertical
+	[(8, 16), (8, 32), (16, 32), (32, 64)],
+];
+
+/// The decoded attributes of a single entry in object attribute memory.
+#[derive(Copy, Clone, Debug)]
+pub struct Object {
+	pub x: i32,
+	pub y: i32,
+	pub width: i32,
+	pub height: i32,
+	/// Only present for affine objects. The index of the rotation/scaling
+	/// parameters to use.
+	pub affine: Option<usize>,
+	/// Affine objects can be drawn into a bounding box that is twice as large
+	/// as the object itself, so that rotated corners don't get clipped.
+	pub double_size: bool,
+	pub mode: u16,
+	pub mosaic: bool,
+	pub full_color: bool,
+	pub horizontal_flip: bool,
+	pub vertical_flip: bool,
+	pub tile: usize,
+	pub priority: u16,
+	pub palette_bank: usize,
+}
+
+impl Object {
+	/// Decodes the object with the given index from OAM, or returns `None` if
+	/// the object is disabled.
+	pub fn read(memory: &Memory, index: usize) -> Option<Self> {
+		let attribute = |n: usize| {
+			let offset = index * 8 + n * 2;
+			u16::from_le_bytes([memory.object[offset], memory.object[offset + 1]])
+		};
+		let (attr0, attr1, attr2) = (attribute(0), attribute(1), attribute(2));
+
+		let affine = attr0 >> 8 & 1 > 0;
+		let shape = (attr0 >> 14) as usize;
+
+		// For regular objects, bit 9 hides the object entirely. Shape 3 is
+		// prohibited, and doesn't display anything either.
+		if (!affine && attr0 >> 9 & 1 > 0) || shape == 3 {
+			return None;
+		}
+
+		let (width, height) = OBJECT_SIZES[shape][(attr1 >> 14) as usize];
+
+		Some(Self {
+			x: ((attr1 as i32 & 0x1ff) << 23) >> 23,
+			y: attr0 as i32 & 0xff,
+			width,
+			height,
+			affine: affine.then_some((attr1 >> 9 & 0x1f) as usize),
+			double_size: affine && attr0 >> 9 & 1 > 0,
+			mode: attr0 >> 10 & 3,
+			mosaic: attr0 >> 12 & 1 > 0,
+			full_color: attr0 >> 13 & 1 > 0,
+			horizontal_flip: !affine && attr1 >> 12 & 1 > 0,
+			vertical_flip: !affine && attr1 >> 13 & 1 > 0,
+			tile: attr2 as usize & 0x3ff,
+			priority: attr2 >> 10 & 3,
+			palette_bank: (attr2 >> 12) as usize,
+		})
+	}
+
+	/// The size of the area that the object is drawn into on the screen.
+	pub fn bounds(&self) -> (i32, i32) {
+		if self.double_size {
+			(self.width * 2, self.height * 2)
+		} else {
+			(self.width, self.height)
+		}
+	}
+
+	/// The number of rendering cycles that drawing one line of the object
+	/// takes out of the per-scanline budget.
+	pub fn cycles(&self) -> i32 {
+		match self.affine {
+			Some(_) => 10 + self.bounds().0 * 2,
+			None => self.width,
+		}
+	}
+}
+
+/// Reads one of the 32 groups of rotation/scaling parameters. These are spread
+/// out across the unused fourth attribute of four consecutive objects.
+fn affine_parameters(memory: &Memory, group: usize) -> [i32; 4] {
+	let parameter = |n: usize| {
+		let offset = group * 32 + n * 8 + 6;
+		i16::from_le_bytes([memory.object[offset], memory.object[offset + 1]]) as i32
+	};
+
+	[parameter(0), parameter(1), parameter(2), parameter(3)]
+}
+
+/// Renders every object that appears on the given line into `out`. Objects are
+/// drawn in OAM order, and a pixel from an object with a lower index is only
+/// replaced by a later object with a strictly higher priority. Objects in
+/// window mode aren't visible, and instead mark the pixels they cover in
+/// `window`. `mosaic_line` is the first line of the current vertical mosaic
+/// block, which is used in place of `line` by objects with mosaic enabled.
+pub fn render_object_line(
+	memory: &Memory,
+	line: usize,
+	mosaic_line: usize,
+	out: &mut ObjectLine,
+	window: &mut [bool; SCREEN_WIDTH],
+) {
+	out.fill(None);
+	window.fill(false);
+
+	let control = memory.read_half_word(DISPCNT);
+	let one_dimensional = control >> 6 & 1 > 0;
+	let bitmap_mode = (3..=5).contains(&(control & 7));
+	let mosaic_width = (memory.read_half_word(MOSAIC) >> 8 & 0xf) as i32 + 1;
+
+	// The hardware only has so much time to draw objects on each line. If
+	// "H-Blank interval free" is set then it gets less, so that OAM can be
+	// accessed during H-Blank.
+	let mut budget = if control >> 5 & 1 > 0 { 954 } else { 1210 };
+
+	for index in 0..128 {
+		let object = match Object::read(memory, index) {
+			Some(object) => object,
+			None => continue,
+		};
+		let (bounds_width, bounds_height) = object.bounds();
+
+		// The y coordinate wraps around at 256, so objects near the bottom of
+		// that range appear at the top of the screen.
+		let row = (line as i32 - object.y) & 0xff;
+		if row >= bounds_height {
+			continue;
+		}
+
+		budget -= object.cycles();
+		if budget < 0 {
+			break;
+		}
+
+		// Mode 3 is prohibited
+		if object.mode == 3 {
+			continue;
+		}
+
+		// Objects can't use the tiles that overlap with the bitmap in modes 3-5
+		if bitmap_mode && object.tile < 512 {
+			continue;
+		}
+
+		// Mosaic objects keep drawing the first line of each mosaic block. If
+		// the block started above the object, the object's first row is used.
+		let row = if object.mosaic {
+			(row - (line - mosaic_line) as i32).max(0)
+		} else {
+			row
+		};
+
+		let parameters = object.affine.map(|group| affine_parameters(memory, group));
+
+		for column in 0..bounds_width {
+			let screen_x = object.x + column;
+			if !(0..SCREEN_WIDTH as i32).contains(&screen_x) {
+				continue;
+			}
+
+			// Horizontal mosaic blocks line up with the left edge of the screen
+			// rather than the object, so they can be cut off at the object's left
+			// edge.
+			let column = if object.mosaic {
+				(screen_x - screen_x % mosaic_width - object.x).max(0)
+			} else {
+				column
+			};
+
+			let (texture_x, texture_y) = match parameters {
+				Some([pa, pb, pc, pd]) => {
+					// Affine objects rotate around the center of their bounds
+					let x = column - bounds_width / 2;
+					let y = row - bounds_height / 2;
+					(
+						((pa * x + pb * y) >> 8) + object.width / 2,
+						((pc * x + pd * y) >> 8) + object.height / 2,
+					)
+				}
+				None => (
+					if object.horizontal_flip {
+						object.width - 1 - column
+					} else {
+						column
+					},
+					if object.vertical_flip {
+						object.height - 1 - row
+					} else {
+						row
+					},
+				),
+			};
+
+			if !(0..object.width).contains(&texture_x) || !(0..object.height).contains(&texture_y) {
+				continue;
+			}
+
+			let existing = &mut out[screen_x as usize];
+			if object.mode != 2
+				&& matches!(existing, Some(pixel) if pixel.priority <= object.priority)
+			{
+				continue;
+			}
+
+			let color = object_pixel(
+				memory,
+				&object,
+				texture_x as usize,
+				texture_y as usize,
+				one_dimensional,
+			);
+
+			if let Some(color) = color {
+				// The object window is made up of every opaque pixel of every
+				// window object
+				if object.mode == 2 {
+					window[screen_x as usize] = true;
+					continue;
+				}
+
+				*existing = Some(ObjectPixel {
+					color,
+					priority: object.priority,
+					semi_transparent: object.mode == 1,
+				});
+			}
+		}
+	}
+}
+
+/// Looks up the color of a single pixel of an object, or `None` if the pixel
+/// is transparent.
+fn object_pixel(
+	memory: &Memory,
+	object: &Object,
+	x: usize,
+	y: usize,
+	one_dimensional: bool,
+) -> Option<u16> {
+	// Tile numbers always count in 32 byte steps, so 256 color tiles take up
+	// two tile numbers each.
+	let tile_size = if object.full_color { 2 } else { 1 };
+
+	// In 1D mapping the tiles of an object are stored one after another. In 2D
+	// mapping, VRAM is treated as a 32x32 grid of tiles, and each row of the
+	// object starts 32 tiles after the previous one.
+	let row_stride = if one_dimensional {
+		object.width as usize / 8 * tile_size
+	} else {
+		32
+	};
+	let tile = (object.tile + y / 8 * row_stride + x / 8 * tile_size) & 0x3ff;
+	let address = OBJECT_VRAM_START + tile * 32;
+
+	let index = if object.full_color {
+		memory.vram[address + y % 8 * 8 + x % 8] as usize
+	} else {
+		let byte = memory.vram[address + y % 8 * 4 + x % 8 / 2];
+		(byte >> ((x & 1) * 4) & 0xf) as usize
+	};
+
+	if index == 0 {
+		return None;
+	}
+
+	// Objects use the second half of palette memory
+	if object.full_color {
+		Some(palette_color(memory, 256 + index))
+	} else {
+		Some(palette_color(
+			memory,
+			256 + object.palette_bank * 16 + index,
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::{OBJECT_ATTRIBUTE_START, PALETTE_START, VRAM_START};
+
+	const OAM: u32 = OBJECT_ATTRIBUTE_START as u32;
+
+	/// Writes a 4-bit tile into object VRAM where the value of every pixel is
+	/// its column plus one.
+	fn write_gradient_tile(memory: &mut Memory, tile: u32) {
+		for row in 0..8 {
+			for pair in 0..4 {
+				let value = (pair * 2 + 1) | (pair * 2 + 2) << 4;
+				memory.write_byte(
+					VRAM_START as u32 + 0x1_0000 + tile * 32 + row * 4 + pair,
+					value as u8,
+				);
+			}
+		}
+	}
+
+	fn setup() -> Memory {
+		let mut memory = Memory::init();
+		memory.write_half_word(DISPCNT, 0x1040);
+		for color in 0..16 {
+			memory.write_half_word(PALETTE_START as u32 + 0x200 + color * 2, color as u16);
+		}
+		// Hide every object by default
+		for index in 0..128 {
+			memory.write_half_word(OAM + index * 8, 0x0200);
+		}
+		memory
+	}
+
+	#[test]
+	fn regular_object() {
+		let mut memory = setup();
+		let mut line = [None; SCREEN_WIDTH];
+		let mut window = [false; SCREEN_WIDTH];
+		write_gradient_tile(&mut memory, 1);
+
+		// 8x8 object at (10, 20) using tile 1, with priority 2
+		memory.write_half_word(OAM, 20);
+		memory.write_half_word(OAM + 2, 10);
+		memory.write_half_word(OAM + 4, 0x0801);
+
+		render_object_line(&memory, 19, 19, &mut line, &mut window);
+		assert!(line.iter().all(|pixel| pixel.is_none()));
+
+		render_object_line(&memory, 20, 20, &mut line, &mut window);
+		assert_eq!(line[9], None);
+		assert_eq!(
+			line[10],
+			Some(ObjectPixel {
+				color: 1,
+				priority: 2,
+				semi_transparent: false
+			})
+		);
+		assert_eq!(line[17].map(|pixel| pixel.color), Some(8));
+		assert_eq!(line[18], None);
+
+		// Flip it horizontally
+		memory.write_half_word(OAM + 2, 0x1000 | 10);
+		render_object_line(&memory, 27, 27, &mut line, &mut window);
+		assert_eq!(line[10].map(|pixel| pixel.color), Some(8));
+		assert_eq!(line[17].map(|pixel| pixel.color), Some(1));
+	}
+
+	#[test]
+	fn negative_coordinates_wrap() {
+		let mut memory = setup();
+		let mut line = [None; SCREEN_WIDTH];
+		let mut window = [false; SCREEN_WIDTH];
+		write_gradient_tile(&mut memory, 1);
+
+		// x = -4 and y = 252 should place the object partially in the top left
+		memory.write_half_word(OAM, 252);
+		memory.write_half_word(OAM + 2, 0x1fc);
+		memory.write_half_word(OAM + 4, 1);
+
+		render_object_line(&memory, 0, 0, &mut line, &mut window);
+		assert_eq!(line[0].map(|pixel| pixel.color), Some(5));
+		assert_eq!(line[3].map(|pixel| pixel.color), Some(8));
+		assert_eq!(line[4], None);
+	}
+
+	#[test]
+	fn two_dimensional_mapping() {
+		let mut memory = setup();
+		let mut line = [None; SCREEN_WIDTH];
+		let mut window = [false; SCREEN_WIDTH];
+
+		// A 16x16 object starting at tile 0. In 1D mapping the second row of
+		// tiles starts at tile 2, and in 2D mapping it starts at tile 32.
+		write_gradient_tile(&mut memory, 2);
+		memory.write_half_word(OAM, 0);
+		memory.write_half_word(OAM + 2, 0x4000);
+		memory.write_half_word(OAM + 4, 0);
+
+		render_object_line(&memory, 8, 8, &mut line, &mut window);
+		assert_eq!(line[0].map(|pixel| pixel.color), Some(1));
+
+		memory.write_half_word(DISPCNT, 0x1000);
+		render_object_line(&memory, 8, 8, &mut line, &mut window);
+		assert_eq!(line[0], None);
+	}
+
+	#[test]
+	fn affine_double_size() {
+		let mut memory = setup();
+		let mut line = [None; SCREEN_WIDTH];
+		let mut window = [false; SCREEN_WIDTH];
+		write_gradient_tile(&mut memory, 1);
+
+		// Affine 8x8 object using parameter group 0 with an identity matrix,
+		// drawn in a 16x16 double size box at (0, 0)
+		memory.write_half_word(OAM, 0x0300);
+		memory.write_half_word(OAM + 2, 0);
+		memory.write_half_word(OAM + 4, 1);
+		memory.write_half_word(OAM + 6, 0x0100);
+		memory.write_half_word(OAM + 14, 0);
+		memory.write_half_word(OAM + 22, 0);
+		memory.write_half_word(OAM + 30, 0x0100);
+
+		// The object should be centered within its bounds
+		render_object_line(&memory, 4, 4, &mut line, &mut window);
+		assert_eq!(line[3], None);
+		assert_eq!(line[4].map(|pixel| pixel.color), Some(1));
+		assert_eq!(line[11].map(|pixel| pixel.color), Some(8));
+		assert_eq!(line[12], None);
+
+		// Scale it up to twice the size
+		memory.write_half_word(OAM + 6, 0x0080);
+		memory.write_half_word(OAM + 30, 0x0080);
+		render_object_line(&memory, 4, 4, &mut line, &mut window);
+		assert_eq!(line[0].map(|pixel| pixel.color), Some(1));
+		assert_eq!(line[15].map(|pixel| pixel.color), Some(8));
+	}
+
+	#[test]
+	fn object_window() {
+		let mut memory = setup();
+		let mut line = [None; SCREEN_WIDTH];
+		let mut window = [false; SCREEN_WIDTH];
+		write_gradient_tile(&mut memory, 1);
+
+		// Object 0 is a window object at (0, 0), and object 1 is a regular
+		// object at (4, 0)
+		memory.write_half_word(OAM, 0x0800);
+		memory.write_half_word(OAM + 2, 0);
+		memory.write_half_word(OAM + 4, 1);
+		memory.write_half_word(OAM + 8, 0);
+		memory.write_half_word(OAM + 10, 4);
+		memory.write_half_word(OAM + 12, 1);
+
+		render_object_line(&memory, 0, 0, &mut line, &mut window);
+		assert!(window[0..8].iter().all(|&inside| inside));
+		assert!(!window[8]);
+
+		// Window objects don't draw anything or hide other objects
+		assert_eq!(line[0], None);
+		assert_eq!(line[4].map(|pixel| pixel.color), Some(1));
+	}
+
+	#[test]
+	fn mosaic() {
+		let mut memory = setup();
+		let mut line = [None; SCREEN_WIDTH];
+		let mut window = [false; SCREEN_WIDTH];
+		write_gradient_tile(&mut memory, 1);
+
+		// A mosaic 8x8 object at (3, 2), with 4x4 mosaic blocks
+		memory.write_half_word(MOSAIC, 0x3300);
+		memory.write_half_word(OAM, 0x1002);
+		memory.write_half_word(OAM + 2, 3);
+		memory.write_half_word(OAM + 4, 1);
+
+		// The block starting at x 0 is cut off by the left edge of the object,
+		// so the object's first column is used until the next block at x 4.
+		render_object_line(&memory, 2, 0, &mut line, &mut window);
+		assert_eq!(line[2], None);
+		assert_eq!(line[3].map(|pixel| pixel.color), Some(1));
+		assert_eq!(line[4].map(|pixel| pixel.color), Some(2));
+		assert_eq!(line[7].map(|pixel| pixel.color), Some(2));
+		assert_eq!(line[8].map(|pixel| pixel.color), Some(6));
+		assert_eq!(line[10].map(|pixel| pixel.color), Some(6));
+		assert_eq!(line[11], None);
+
+		// Make the object's second row a different tile, and check that the
+		// object keeps drawing the first line of the mosaic block
+		for i in 0..4 {
+			memory.write_byte(VRAM_START as u32 + 0x1_0000 + 32 + 4 + i, 0x77);
+		}
+		render_object_line(&memory, 3, 0, &mut line, &mut window);
+		assert_eq!(line[3].map(|pixel| pixel.color), Some(1));
+		render_object_line(&memory, 4, 4, &mut line, &mut window);
+		assert_eq!(line[3].map(|pixel| pixel.color), Some(1));
+
+		memory.write_half_word(MOSAIC, 0);
+		render_object_line(&memory, 3, 3, &mut line, &mut window);
+		assert_eq!(line[3].map(|pixel| pixel.color), Some(7));
+	}
+
+	#[test]
+	fn cycle_budget() {
+		let mut memory = setup();
+		let mut line = [None; SCREEN_WIDTH];
+		let mut window = [false; SCREEN_WIDTH];
+		write_gradient_tile(&mut memory, 1);
+
+		// Fill a line with 64x64 objects, each of which costs 64 cycles. Only
+		// the first 18 fit into the budget of 1210 cycles. Each object only has
+		// pixels in its first tile, so they don't cover each other up.
+		for index in 0..30 {
+			memory.write_half_word(OAM + index * 8, 0);
+			memory.write_half_word(OAM + index * 8 + 2, 0xc000 | (index * 8) as u16);
+			memory.write_half_word(OAM + index * 8 + 4, 1);
+		}
+
+		render_object_line(&memory, 0, 0, &mut line, &mut window);
+		assert!(line[17 * 8].is_some());
+		assert!(line[18 * 8].is_none());
+
+		// With "H-Blank interval free" only 954 cycles are available, or 14
+		// objects worth.
+		memory.write_half_word(DISPCNT, 0x1060);
+		render_object_line(&memory, 0, 0, &mut line, &mut window);
+		assert!(line[13 * 8].is_some());
+		assert!(line[14 * 8].is_none());
+	}
+}
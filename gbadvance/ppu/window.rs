@@ -0,0 +1,149 @@
+use super::{DISPCNT, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::memory::Memory;
+
+pub const WIN0H: u32 = 0x0400_0040;
+pub const WIN0V: u32 = 0x0400_0044;
+pub const WININ: u32 = 0x0400_0048;
+pub const WINOUT: u32 = 0x0400_004a;
+
+/// Bit of a window mask which allows the object layer to be displayed. Bits 0-3
+/// do the same for each of the backgrounds.
+pub const OBJECT_ENABLE: u8 = 1 << 4;
+/// Bit of a window mask which allows color special effects to be applied.
+pub const EFFECTS_ENABLE: u8 = 1 << 5;
+/// The mask used when no windows are enabled at all, which allows everything.
+pub const ALL_ENABLED: u8 = 0x3f;
+
+/// The layer mask of every pixel along a single line.
+pub type WindowLine = [u8; SCREEN_WIDTH];
+
+/// Checks if `position` is within the range described by a window dimension
+/// register, where the high byte is the start and the low byte is the end
+/// (exclusive). If the start is past the end, the window wraps around the edge
+/// of the screen.
+fn contains(register: u16, position: usize, limit: usize) -> bool {
+	let start = (register >> 8) as usize;
+	let end = ((register & 0xff) as usize).min(limit);
+
+	if start <= end {
+		(start..end).contains(&position)
+	} else {
+		position >= start || position < end
+	}
+}
+
+/// Works out which layers are visible at each pixel along a line, and whether
+/// color special effects are allowed there. Window 0 takes precedence over
+/// window 1, which takes precedence over the object window, and anything not in
+/// a window uses the "outside" mask from WINOUT.
+pub fn render_window_line(
+	memory: &Memory,
+	line: usize,
+	object_window: &[bool; SCREEN_WIDTH],
+	out: &mut WindowLine,
+) {
+	let control = memory.read_half_word(DISPCNT);
+
+	if control >> 13 & 7 == 0 {
+		out.fill(ALL_ENABLED);
+		return;
+	}
+
+	let inside = memory.read_half_word(WININ);
+	let outside = memory.read_half_word(WINOUT);
+
+	out.fill(outside as u8 & 0x3f);
+
+	// Draw from the lowest precedence to the highest so that each window can
+	// simply overwrite the previous ones.
+	if control >> 15 & 1 > 0 {
+		for (mask, &inside_object) in out.iter_mut().zip(object_window.iter()) {
+			if inside_object {
+				*mask = (outside >> 8) as u8 & 0x3f;
+			}
+		}
+	}
+
+	for window in [1, 0] {
+		if control >> (13 + window) & 1 == 0 {
+			continue;
+		}
+
+		let horizontal = memory.read_half_word(WIN0H + window as u32 * 2);
+		let vertical = memory.read_half_word(WIN0V + window as u32 * 2);
+		if !contains(vertical, line, SCREEN_HEIGHT) {
+			continue;
+		}
+
+		let window_mask = (inside >> (window * 8)) as u8 & 0x3f;
+		for (x, mask) in out.iter_mut().enumerate() {
+			if contains(horizontal, x, SCREEN_WIDTH) {
+				*mask = window_mask;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn no_windows() {
+		let memory = Memory::init();
+		let mut line = [0; SCREEN_WIDTH];
+
+		render_window_line(&memory, 0, &[false; SCREEN_WIDTH], &mut line);
+		assert!(line.iter().all(|&mask| mask == ALL_ENABLED));
+	}
+
+	#[test]
+	fn window_precedence() {
+		let mut memory = Memory::init();
+		let mut line = [0; SCREEN_WIDTH];
+
+		// Enable all three windows. Window 0 covers x 10-19, window 1 covers
+		// x 15-29, and the object window covers x 25-34.
+		memory.write_half_word(DISPCNT, 0xe000);
+		memory.write_half_word(WIN0H, 10 << 8 | 20);
+		memory.write_half_word(WIN0H + 2, 15 << 8 | 30);
+		memory.write_half_word(WIN0V, 160);
+		memory.write_half_word(WIN0V + 2, 160);
+		memory.write_half_word(WININ, 0x0201);
+		memory.write_half_word(WINOUT, 0x0408);
+
+		let mut object_window = [false; SCREEN_WIDTH];
+		object_window[25..35].fill(true);
+
+		render_window_line(&memory, 0, &object_window, &mut line);
+		assert_eq!(line[9], 0x08);
+		assert_eq!(line[10], 0x01);
+		assert_eq!(line[19], 0x01);
+		assert_eq!(line[20], 0x02);
+		assert_eq!(line[29], 0x02);
+		assert_eq!(line[30], 0x04);
+		assert_eq!(line[34], 0x04);
+		assert_eq!(line[35], 0x08);
+	}
+
+	#[test]
+	fn window_wraps_around() {
+		let mut memory = Memory::init();
+		let mut line = [0; SCREEN_WIDTH];
+
+		// Window 0 from x 230 to x 10, and y 150 to y 10
+		memory.write_half_word(DISPCNT, 0x2000);
+		memory.write_half_word(WIN0H, 230 << 8 | 10);
+		memory.write_half_word(WIN0V, 150 << 8 | 10);
+		memory.write_half_word(WININ, 0x0001);
+
+		render_window_line(&memory, 5, &[false; SCREEN_WIDTH], &mut line);
+		assert_eq!(line[0], 0x01);
+		assert_eq!(line[10], 0x00);
+		assert_eq!(line[229], 0x00);
+		assert_eq!(line[239], 0x01);
+
+		render_window_line(&memory, 80, &[false; SCREEN_WIDTH], &mut line);
+		assert_eq!(line[0], 0x00);
+	}
+}
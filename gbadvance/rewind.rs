@@ -0,0 +1,288 @@
+//! Rewinding keeps a history of save states, taken every few frames, so that
+//! the player can go back in time. Save states are big, and most of each one is
+//! the same as the one before it, so only the newest is kept in full. Each
+//! older state is stored as the XOR of itself with the state after it, with the
+//! runs of zeros squashed down, which is usually tiny. When the history goes
+//! over its memory budget, the oldest states are forgotten.
+
+use crate::emulator::Emulator;
+use std::collections::VecDeque;
+
+pub struct Rewind {
+	/// How many frames to wait between snapshots.
+	pub interval: u32,
+	/// The most memory that the snapshots can use, in bytes.
+	pub budget: usize,
+	/// The most recent snapshot, in full.
+	latest: Vec<u8>,
+	/// The changes needed to turn each snapshot into the one before it, with
+	/// the oldest first.
+	deltas: VecDeque<Vec<u8>>,
+	/// The total size of the snapshots.
+	used: usize,
+	/// The number of frames since the latest snapshot was taken.
+	frames: u32,
+}
+
+impl Rewind {
+	pub fn init(interval: u32, budget: usize) -> Self {
+		Self {
+			interval: interval.max(1),
+			budget,
+			latest: Vec::new(),
+			deltas: VecDeque::new(),
+			used: 0,
+			frames: 0,
+		}
+	}
+
+	/// Forgets every snapshot, such as when a different game is inserted.
+	pub fn clear(&mut self) {
+		self.latest.clear();
+		self.deltas.clear();
+		self.used = 0;
+		self.frames = 0;
+	}
+
+	/// The number of frames that can currently be rewound.
+	pub fn available_frames(&self) -> u32 {
+		if self.latest.is_empty() {
+			return 0;
+		}
+
+		self.frames + self.deltas.len() as u32 * self.interval
+	}
+
+	/// Called at the end of every frame, and takes a snapshot if it's time.
+	pub fn frame_finished(&mut self, emulator: &Emulator) {
+		self.frames += 1;
+		if !self.latest.is_empty() && self.frames < self.interval {
+			return;
+		}
+
+		let state = emulator.save_state();
+		if !self.latest.is_empty() {
+			let delta = encode_delta(&self.latest, &state);
+			self.used += delta.len();
+			self.deltas.push_back(delta);
+		}
+
+		self.used = self.used + state.len() - self.latest.len();
+		self.latest = state;
+		self.frames = 0;
+
+		while self.used > self.budget {
+			match self.deltas.pop_front() {
+				Some(delta) => self.used -= delta.len(),
+				None => break,
+			}
+		}
+	}
+
+	/// Goes back to the newest snapshot that is at least `frames` old, or the
+	/// oldest one if there isn't one that old. Returns the number of frames
+	/// that were actually rewound.
+	pub fn rewind(&mut self, emulator: &mut Emulator, frames: u32) -> u32 {
+		if self.latest.is_empty() {
+			return 0;
+		}
+
+		let mut rewound = self.frames;
+		while rewound < frames {
+			let Some(delta) = self.deltas.pop_back() else {
+				break;
+			};
+
+			self.used -= delta.len();
+			self.used -= self.latest.len();
+			self.latest = decode_delta(&self.latest, &delta);
+			self.used += self.latest.len();
+			rewound += self.interval;
+		}
+
+		emulator
+			.load_state(&self.latest)
+			.expect("rewind snapshots should always load");
+		self.frames = 0;
+		rewound
+	}
+}
+
+impl Emulator {
+	/// Goes back in time by about `frames` frames, if rewinding is switched on.
+	/// Returns the number of frames that were actually rewound.
+	pub fn rewind(&mut self, frames: u32) -> u32 {
+		let Some(mut rewind) = self.rewind.take() else {
+			return 0;
+		};
+
+		let rewound = rewind.rewind(self, frames);
+		self.rewind = Some(rewind);
+		rewound
+	}
+}
+
+/// Works out the changes that turn `new` back into `old`. The delta starts with
+/// the length of `old`, followed by pairs of runs: a run of bytes that are the
+/// same in both, and then a run of bytes to XOR with `new`.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+	let length = old.len().max(new.len());
+	let difference =
+		|index: usize| old.get(index).copied().unwrap_or(0) ^ new.get(index).copied().unwrap_or(0);
+
+	let mut delta = Vec::new();
+	write_length(&mut delta, old.len());
+
+	let mut index = 0;
+	while index < length {
+		let start = index;
+		while index < length && difference(index) == 0 {
+			index += 1;
+		}
+		write_length(&mut delta, index - start);
+
+		// Short runs of matching bytes are cheaper to leave in the changes than
+		// to start a new pair for
+		let start = index;
+		while index < length {
+			let matching = (index..length)
+				.take(4)
+				.take_while(|&index| difference(index) == 0)
+				.count();
+			if matching == 4 || index + matching == length {
+				break;
+			}
+			index += matching + 1;
+		}
+		write_length(&mut delta, index - start);
+		delta.extend((start..index).map(difference));
+	}
+
+	delta
+}
+
+/// Applies a delta from `encode_delta` to `new`, giving back `old`.
+fn decode_delta(new: &[u8], delta: &[u8]) -> Vec<u8> {
+	let mut position = 0;
+	let old_length = read_length(delta, &mut position);
+
+	let mut old = new.to_vec();
+	old.resize(old_length.max(new.len()), 0);
+
+	let mut index = 0;
+	while position < delta.len() {
+		index += read_length(delta, &mut position);
+		let changes = read_length(delta, &mut position);
+
+		for &change in &delta[position..position + changes] {
+			old[index] ^= change;
+			index += 1;
+		}
+		position += changes;
+	}
+
+	old.truncate(old_length);
+	old
+}
+
+/// Writes a length as a LEB128 number, 7 bits at a time.
+fn write_length(data: &mut Vec<u8>, mut length: usize) {
+	loop {
+		let byte = (length & 0x7f) as u8;
+		length >>= 7;
+
+		if length == 0 {
+			data.push(byte);
+			return;
+		}
+		data.push(byte | 0x80);
+	}
+}
+
+fn read_length(data: &[u8], position: &mut usize) -> usize {
+	let mut length = 0;
+	let mut shift = 0;
+
+	loop {
+		let byte = data[*position];
+		*position += 1;
+		length |= ((byte & 0x7f) as usize) << shift;
+		shift += 7;
+
+		if byte & 0x80 == 0 {
+			return length;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::{EXT_START, RAM_START};
+
+	#[test]
+	fn deltas() {
+		let old = b"the quick brown fox jumps over the lazy dog".to_vec();
+		let mut new = old.clone();
+		new[4..9].copy_from_slice(b"QUICK");
+		new[40] = b'!';
+
+		let delta = encode_delta(&old, &new);
+		assert!(delta.len() < 16);
+		assert_eq!(decode_delta(&new, &delta), old);
+
+		// The states can change size when queues fill up and empty
+		let longer = [new.as_slice(), b" again"].concat();
+		assert_eq!(decode_delta(&longer, &encode_delta(&old, &longer)), old);
+		assert_eq!(decode_delta(&old, &encode_delta(&longer, &old)), longer);
+		assert_eq!(decode_delta(&old, &encode_delta(&old, &old)), old);
+	}
+
+	#[test]
+	fn rewinding() {
+		let mut emulator = Emulator::new_idle();
+		emulator.rewind = Some(Rewind::init(2, usize::MAX));
+
+		let mut states = Vec::new();
+		for frame in 0..10 {
+			emulator.memory.write_word(RAM_START as u32, frame);
+			emulator.step_frame();
+			states.push(emulator.save_state());
+		}
+
+		// Snapshots were taken after frames 0, 2, 4, 6 and 8, and frame 9 has
+		// just finished
+		assert_eq!(emulator.rewind(3), 3);
+		assert_eq!(emulator.save_state(), states[6]);
+		assert_eq!(emulator.rewind(1), 2);
+		assert_eq!(emulator.save_state(), states[4]);
+
+		// Going back further than the history only goes back to the start
+		assert_eq!(emulator.rewind(100), 4);
+		assert_eq!(emulator.save_state(), states[0]);
+	}
+
+	#[test]
+	fn budget() {
+		let mut emulator = Emulator::new_idle();
+		let size = emulator.save_state().len();
+		emulator.rewind = Some(Rewind::init(1, size * 2));
+
+		for frame in 0..100 {
+			emulator.cpu.registers.r15 = EXT_START as u32;
+
+			// Fill a different part of work RAM every frame, so that none of
+			// the deltas are small
+			for offset in (0..0x4000).step_by(4) {
+				emulator
+					.memory
+					.write_word(RAM_START as u32 + offset, frame + offset);
+			}
+			emulator.step_frame();
+		}
+
+		let rewind = emulator.rewind.as_ref().unwrap();
+		assert!(rewind.used <= size * 2);
+		assert!(rewind.available_frames() < 100);
+	}
+}
@@ -0,0 +1,462 @@
+//! The battery backed memory in a cartridge, which games use to save progress.
+//! There are a few different kinds, and nothing in the header says which one a
+//! game uses, so it is worked out by looking for the ID strings that Nintendo's
+//! save libraries leave in the ROM.
+
+pub mod eeprom;
+pub mod flash;
+
+use crate::memory::Memory;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use eeprom::{Eeprom, EepromSize};
+use flash::{Flash, FlashChip};
+
+/// The size of SRAM. It is mirrored across the rest of the save region.
+pub const SRAM_SIZE: usize = 0x8000;
+
+/// The ID strings left by each save library, and the kind of save memory that
+/// the library talks to. Longer strings come first so that `FLASH_V` doesn't
+/// match before `FLASH512_V` has had a chance to.
+const LIBRARY_IDS: [(&[u8], SaveType); 6] = [
+	(b"EEPROM_V", SaveType::Eeprom8k),
+	(b"SRAM_F_V", SaveType::Sram),
+	(b"SRAM_V", SaveType::Sram),
+	(b"FLASH1M_V", SaveType::Flash128),
+	(b"FLASH512_V", SaveType::Flash64),
+	(b"FLASH_V", SaveType::Flash64),
+];
+
+/// Games that need a particular kind of save memory no matter what their ID
+/// strings say, by game code.
+const OVERRIDES: [(&str, SaveType); 8] = [
+	// Pokémon Ruby and Sapphire
+	("AXVE", SaveType::Flash128),
+	("AXPE", SaveType::Flash128),
+	// Pokémon Emerald
+	("BPEE", SaveType::Flash128),
+	// Pokémon FireRed and LeafGreen
+	("BPRE", SaveType::Flash128),
+	("BPGE", SaveType::Flash128),
+	// Super Mario Advance 4
+	("AX4E", SaveType::Flash128),
+	// Iridion II doesn't have any save memory
+	("AI2E", SaveType::None),
+	("AI2P", SaveType::None),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SaveType {
+	None,
+	Sram,
+	Flash64,
+	Flash128,
+	Eeprom512,
+	Eeprom8k,
+}
+
+impl SaveType {
+	/// Works out which kind of save memory a game uses. Games that aren't
+	/// recognized get SRAM, since homebrew often uses it without including
+	/// any of the libraries, and it doesn't need any setup from the game.
+	pub fn detect(rom: &[u8], game_code: Option<&str>) -> Self {
+		if let Some(&(_, kind)) = OVERRIDES.iter().find(|(code, _)| Some(*code) == game_code) {
+			return kind;
+		}
+
+		// The strings are always word aligned
+		for offset in (0..rom.len()).step_by(4) {
+			for &(id, kind) in &LIBRARY_IDS {
+				if rom[offset..].starts_with(id) {
+					return kind;
+				}
+			}
+		}
+
+		SaveType::Sram
+	}
+}
+
+/// The chip that holds the save data, along with whatever state it needs to
+/// talk to the game.
+pub enum Backend {
+	None,
+	Sram(Vec<u8>),
+	Flash(Flash),
+	Eeprom(Eeprom),
+}
+
+/// The save memory inside of the cartridge.
+pub struct Save {
+	pub backend: Backend,
+	/// Set whenever the game changes the save data, so that the frontend knows
+	/// when it needs to write it out again.
+	dirty: bool,
+}
+
+impl Save {
+	pub fn init(kind: SaveType) -> Self {
+		let backend = match kind {
+			SaveType::None => Backend::None,
+			SaveType::Sram => Backend::Sram(vec![0xff; SRAM_SIZE]),
+			// Unless we know better, use the chips that most games shipped with
+			SaveType::Flash64 => Backend::Flash(Flash::init(FlashChip::Panasonic)),
+			SaveType::Flash128 => Backend::Flash(Flash::init(FlashChip::Macronix128)),
+			SaveType::Eeprom512 => Backend::Eeprom(Eeprom::init(EepromSize::Small)),
+			SaveType::Eeprom8k => Backend::Eeprom(Eeprom::init(EepromSize::Large)),
+		};
+
+		Self {
+			backend,
+			dirty: false,
+		}
+	}
+
+	pub fn kind(&self) -> SaveType {
+		match &self.backend {
+			Backend::None => SaveType::None,
+			Backend::Sram(_) => SaveType::Sram,
+			Backend::Flash(flash) if flash.chip.size() > flash::BANK_SIZE => SaveType::Flash128,
+			Backend::Flash(_) => SaveType::Flash64,
+			Backend::Eeprom(eeprom) if eeprom.size == EepromSize::Small => SaveType::Eeprom512,
+			Backend::Eeprom(_) => SaveType::Eeprom8k,
+		}
+	}
+
+	pub fn is_eeprom(&self) -> bool {
+		matches!(self.backend, Backend::Eeprom(_))
+	}
+
+	/// Whether the save data has changed since it was last marked as clean.
+	pub fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	pub fn mark_clean(&mut self) {
+		self.dirty = false;
+	}
+
+	/// Reads a byte from the save region at 0x0e000000. EEPROM isn't mapped
+	/// here, and reads as all ones like an empty bus.
+	pub fn read(&self, offset: usize) -> u8 {
+		match &self.backend {
+			Backend::Sram(sram) => sram[offset % SRAM_SIZE],
+			Backend::Flash(flash) => flash.read(offset),
+			Backend::None | Backend::Eeprom(_) => 0xff,
+		}
+	}
+
+	/// Writes a byte to the save region at 0x0e000000.
+	pub fn write(&mut self, offset: usize, value: u8) {
+		let changed = match &mut self.backend {
+			Backend::Sram(sram) => {
+				let previous = std::mem::replace(&mut sram[offset % SRAM_SIZE], value);
+				previous != value
+			}
+			Backend::Flash(flash) => flash.write(offset, value),
+			Backend::None | Backend::Eeprom(_) => false,
+		};
+
+		self.dirty |= changed;
+	}
+
+	pub fn read_eeprom(&self) -> u16 {
+		match &self.backend {
+			Backend::Eeprom(eeprom) => eeprom.read(),
+			_ => 0,
+		}
+	}
+
+	pub fn write_eeprom(&mut self, value: u16) {
+		if let Backend::Eeprom(eeprom) = &mut self.backend {
+			self.dirty |= eeprom.write(value);
+		}
+	}
+
+	/// Called when a DMA transfer to the EEPROM starts, which is how the size
+	/// of the EEPROM is worked out.
+	pub fn eeprom_transfer(&mut self, length: usize) {
+		if let Backend::Eeprom(eeprom) = &mut self.backend {
+			if let Some(size) = EepromSize::from_transfer_length(length) {
+				if size != eeprom.size {
+					eeprom.set_size(size);
+				}
+			}
+		}
+	}
+
+	/// Copies out the save data in the same format as a .sav file, which is
+	/// just the contents of the chip. EEPROM data is stored in the order that it
+	/// is sent to the game, so the first bit of each block is the top bit of its
+	/// first byte, the same as other emulators and flash carts use.
+	pub fn export(&self) -> Vec<u8> {
+		match &self.backend {
+			Backend::None => Vec::new(),
+			Backend::Sram(sram) => sram.clone(),
+			Backend::Flash(flash) => flash.data.clone(),
+			Backend::Eeprom(eeprom) => eeprom.data.clone(),
+		}
+	}
+
+	/// Loads save data from a .sav file. The size of the file is trusted over
+	/// the size that was detected, as long as it is the same kind of chip.
+	/// Returns `false` if the file doesn't fit this kind of save memory.
+	pub fn import(&mut self, data: &[u8]) -> bool {
+		let size = data.len();
+
+		match &mut self.backend {
+			// Some emulators pad SRAM out to 64K
+			Backend::Sram(sram) if size == SRAM_SIZE || size == SRAM_SIZE * 2 => {
+				sram.copy_from_slice(&data[..SRAM_SIZE]);
+			}
+			Backend::Flash(flash) if size == flash.chip.size() => {
+				flash.data.copy_from_slice(data);
+			}
+			Backend::Flash(flash) if size == flash::BANK_SIZE || size == flash::BANK_SIZE * 2 => {
+				let kind = if size == flash::BANK_SIZE {
+					SaveType::Flash64
+				} else {
+					SaveType::Flash128
+				};
+				*self = Save::init(kind);
+				return self.import(data);
+			}
+			Backend::Eeprom(eeprom) => {
+				let size = match size {
+					0x200 => EepromSize::Small,
+					0x2000 => EepromSize::Large,
+					_ => return false,
+				};
+				eeprom.set_size(size);
+				eeprom.data.copy_from_slice(data);
+			}
+			_ => return false,
+		}
+
+		self.dirty = false;
+		true
+	}
+}
+
+impl Snapshot for Save {
+	fn write_state(&self, state: &mut StateWriter) {
+		match &self.backend {
+			Backend::None => state.u8(0),
+			Backend::Sram(sram) => {
+				state.u8(1);
+				state.bytes(sram);
+			}
+			Backend::Flash(flash) => {
+				state.u8(2);
+				flash.write_state(state);
+			}
+			Backend::Eeprom(eeprom) => {
+				state.u8(3);
+				eeprom.write_state(state);
+			}
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.backend = match state.u8()? {
+			0 => Backend::None,
+			1 => {
+				let mut sram = vec![0; SRAM_SIZE];
+				state.fill(&mut sram)?;
+				Backend::Sram(sram)
+			}
+			2 => {
+				let mut flash = Flash::init(FlashChip::Panasonic);
+				flash.read_state(state)?;
+				Backend::Flash(flash)
+			}
+			3 => {
+				let mut eeprom = Eeprom::init(EepromSize::Small);
+				eeprom.read_state(state)?;
+				Backend::Eeprom(eeprom)
+			}
+			_ => return Err(StateError::Invalid),
+		};
+
+		// The save data has probably gone back to how it was when the state was
+		// made, which won't match what was last exported
+		self.dirty = true;
+		Ok(())
+	}
+}
+
+impl Memory {
+	/// EEPROM takes over the top of the ROM region. Cartridges with more than
+	/// 16MB of ROM need most of that space, so only the last 256 bytes go to the
+	/// EEPROM on those.
+	pub fn is_eeprom_address(&self, address: u32) -> bool {
+		if !self.save.is_eeprom() {
+			return false;
+		}
+
+		if self.rom.len() > 0x100_0000 {
+			(0x0dff_ff00..=0x0dff_ffff).contains(&address)
+		} else {
+			(0x0d00_0000..=0x0dff_ffff).contains(&address)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn detection() {
+		let mut rom = vec![0; 0x400];
+		assert_eq!(SaveType::detect(&rom, None), SaveType::Sram);
+
+		rom[0x200..0x20b].copy_from_slice(b"FLASH512_V1");
+		assert_eq!(SaveType::detect(&rom, None), SaveType::Flash64);
+
+		rom[0x200..0x20b].copy_from_slice(b"FLASH1M_V10");
+		assert_eq!(SaveType::detect(&rom, None), SaveType::Flash128);
+
+		// Strings that aren't word aligned don't count
+		rom[0x200..0x20b].fill(0);
+		rom[0x301..0x309].copy_from_slice(b"EEPROM_V");
+		assert_eq!(SaveType::detect(&rom, None), SaveType::Sram);
+		rom[0x300..0x308].copy_from_slice(b"EEPROM_V");
+		assert_eq!(SaveType::detect(&rom, None), SaveType::Eeprom8k);
+
+		assert_eq!(SaveType::detect(&rom, Some("AI2E")), SaveType::None);
+	}
+
+	#[test]
+	fn sram_mirroring() {
+		let mut save = Save::init(SaveType::Sram);
+
+		save.write(0x8001, 0x42);
+		assert_eq!(save.read(1), 0x42);
+		assert_eq!(save.read(0x1_8001), 0x42);
+	}
+
+	#[test]
+	fn eeprom_size_from_dma() {
+		let mut save = Save::init(SaveType::detect(b"EEPROM_V", None));
+		assert_eq!(save.kind(), SaveType::Eeprom8k);
+
+		save.eeprom_transfer(68);
+		assert_eq!(save.kind(), SaveType::Eeprom8k);
+		save.eeprom_transfer(9);
+		assert_eq!(save.kind(), SaveType::Eeprom512);
+	}
+
+	#[test]
+	fn dirty_flag() {
+		let mut save = Save::init(SaveType::Sram);
+		assert!(!save.is_dirty());
+
+		// Writing the same value again doesn't change anything
+		save.write(0, 0xff);
+		assert!(!save.is_dirty());
+		save.write(0, 0x12);
+		assert!(save.is_dirty());
+
+		save.mark_clean();
+		assert!(!save.is_dirty());
+	}
+
+	#[test]
+	fn import_and_export() {
+		let mut save = Save::init(SaveType::Sram);
+		let mut data = vec![0; SRAM_SIZE * 2];
+		data[5] = 5;
+		assert!(save.import(&data));
+		assert_eq!(save.read(5), 5);
+		assert_eq!(save.export().len(), SRAM_SIZE);
+		assert!(!save.import(&[0; 100]));
+
+		// A 128K file switches a 64K flash chip for a 128K one
+		let mut save = Save::init(SaveType::Flash64);
+		let data: Vec<u8> = (0..flash::BANK_SIZE * 2).map(|i| i as u8).collect();
+		assert!(save.import(&data));
+		assert_eq!(save.kind(), SaveType::Flash128);
+		assert_eq!(save.export(), data);
+
+		let mut save = Save::init(SaveType::Eeprom8k);
+		assert!(save.import(&[0x42; 0x200]));
+		assert_eq!(save.kind(), SaveType::Eeprom512);
+		assert!(!save.import(&[0; 0x1000]));
+		assert!(!Save::init(SaveType::None).import(&[0; 0x200]));
+	}
+
+	#[test]
+	fn eeprom_over_dma() {
+		use crate::dma::{CHANNEL_SIZE, DMA0CNT_H, DMA0CNT_L, DMA0DAD, DMA0SAD};
+		use crate::memory::RAM_START;
+
+		let mut memory = Memory::init();
+		memory.save = Save::init(SaveType::Eeprom8k);
+		let dma3 = 3 * CHANNEL_SIZE;
+		let transfer = |memory: &mut Memory, source: u32, destination: u32, count: u16| {
+			memory.write_word(DMA0SAD + dma3, source);
+			memory.write_word(DMA0DAD + dma3, destination);
+			memory.write_half_word(DMA0CNT_L + dma3, count);
+			memory.write_half_word(DMA0CNT_H + dma3, 0x8000);
+			while memory.run_dma().is_some() {}
+		};
+
+		// Write 0xff00...00 to block 5 of a 512 byte EEPROM
+		let request = [1, 0]
+			.into_iter()
+			.chain((0..6).rev().map(|bit| 5 >> bit & 1))
+			.chain((0..64).map(|bit| (bit < 8) as u16))
+			.chain([0]);
+		for (index, bit) in request.enumerate() {
+			memory.write_half_word(RAM_START as u32 + index as u32 * 2, bit);
+		}
+		transfer(&mut memory, RAM_START as u32, 0x0d00_0000, 73);
+		assert_eq!(memory.save.kind(), SaveType::Eeprom512);
+
+		// Read it back
+		let request = [1, 1]
+			.into_iter()
+			.chain((0..6).rev().map(|bit| 5 >> bit & 1))
+			.chain([0]);
+		for (index, bit) in request.enumerate() {
+			memory.write_half_word(RAM_START as u32 + index as u32 * 2, bit);
+		}
+		transfer(&mut memory, RAM_START as u32, 0x0d00_0000, 9);
+		transfer(&mut memory, 0x0d00_0000, RAM_START as u32 + 0x200, 68);
+
+		let bits: Vec<u16> = (0..68)
+			.map(|index| memory.read_half_word(RAM_START as u32 + 0x200 + index * 2) & 1)
+			.collect();
+		assert_eq!(bits[..4], [0; 4]);
+		assert_eq!(bits[4..12], [1; 8]);
+		assert_eq!(bits[12..], [0; 56]);
+	}
+
+	#[test]
+	fn eeprom_word_reads() {
+		let mut memory = Memory::init();
+		memory.save = Save::init(SaveType::Eeprom8k);
+		let mut data = vec![0; 0x200];
+		data[5 * 8] = 0xff;
+		assert!(memory.save.import(&data));
+
+		// Ask for block 5
+		let request = [1, 1]
+			.into_iter()
+			.chain((0..6).rev().map(|bit| 5 >> bit & 1))
+			.chain([0]);
+		for bit in request {
+			memory.write_half_word(0x0d00_0000, bit);
+		}
+
+		let bits: Vec<u16> = (0..34)
+			.flat_map(|_| {
+				let word = memory.read_word(0x0d00_0000);
+				[word as u16 & 1, (word >> 16) as u16 & 1]
+			})
+			.collect();
+		assert_eq!(bits[..4], [0; 4]);
+		assert_eq!(bits[4..12], [1; 8]);
+		assert_eq!(bits[12..], [0; 56]);
+	}
+}
@@ -0,0 +1,294 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::cell::Cell;
+
+/// EEPROM is read and written in blocks of 8 bytes.
+const BLOCK_SIZE: usize = 8;
+/// Reads start with 4 bits that don't mean anything, followed by the 64 bits
+/// of the block.
+const READ_BITS: usize = 4 + BLOCK_SIZE * 8;
+
+/// The two sizes of EEPROM, which use a different number of bits for the
+/// address of each block.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EepromSize {
+	/// 512 bytes, with 6 bit addresses.
+	Small,
+	/// 8K, with 14 bit addresses. Only the bottom 10 bits are actually used.
+	Large,
+}
+
+impl EepromSize {
+	pub fn bytes(self) -> usize {
+		match self {
+			EepromSize::Small => 0x200,
+			EepromSize::Large => 0x2000,
+		}
+	}
+
+	fn address_bits(self) -> usize {
+		match self {
+			EepromSize::Small => 6,
+			EepromSize::Large => 14,
+		}
+	}
+
+	/// Works out the size of the EEPROM from the length of a DMA transfer to it.
+	/// Games always send a whole request at once, and the requests are a
+	/// different length for each size. Returns `None` for transfers that aren't
+	/// requests.
+	pub fn from_transfer_length(length: usize) -> Option<Self> {
+		match length {
+			// Read requests are 2 bits, the address, and a 0. Write requests are
+			// 2 bits, the address, 64 bits of data, and a 0.
+			9 | 73 => Some(EepromSize::Small),
+			17 | 81 => Some(EepromSize::Large),
+			_ => None,
+		}
+	}
+}
+
+/// Where the EEPROM is in a request. Requests are sent one bit at a time, in
+/// bit 0 of each half word written to it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+	/// Waiting for the 2 bits saying whether this is a read or a write.
+	Command,
+	ReadAddress,
+	WriteAddress,
+	WriteData,
+	/// Waiting for the 0 bit at the end of a request.
+	End,
+}
+
+pub struct Eeprom {
+	pub size: EepromSize,
+	pub data: Vec<u8>,
+	state: State,
+	/// The bits received so far in the current part of the request.
+	bits: u64,
+	count: usize,
+	/// The block being read or written.
+	block: usize,
+	/// Whether the request that is ending is a read.
+	reading: bool,
+	/// How many bits of the requested block have been read out, if a read has
+	/// been requested. Reading has to move this along even though reads don't
+	/// otherwise change anything.
+	read_position: Cell<Option<usize>>,
+}
+
+impl Eeprom {
+	pub fn init(size: EepromSize) -> Self {
+		Self {
+			size,
+			// Erased EEPROM reads as all ones
+			data: vec![0xff; size.bytes()],
+			state: State::Command,
+			bits: 0,
+			count: 0,
+			block: 0,
+			reading: false,
+			read_position: Cell::new(None),
+		}
+	}
+
+	/// Changes the size of the EEPROM, once the game has shown which size it
+	/// expects.
+	pub fn set_size(&mut self, size: EepromSize) {
+		self.size = size;
+		self.data.resize(size.bytes(), 0xff);
+	}
+
+	/// Reads the next bit of the block that was requested. When there's no read
+	/// going on, this returns 1 to say that the EEPROM is ready for more.
+	pub fn read(&self) -> u16 {
+		let position = match self.read_position.get() {
+			Some(position) => position,
+			None => return 1,
+		};
+
+		self.read_position
+			.set(Some(position + 1).filter(|&next| next < READ_BITS));
+
+		if position < 4 {
+			return 0;
+		}
+
+		let bit = position - 4;
+		let byte = self.data[self.block * BLOCK_SIZE + bit / 8];
+		(byte >> (7 - bit % 8) & 1) as u16
+	}
+
+	/// Receives the next bit of a request, and returns whether it finished
+	/// writing a block.
+	pub fn write(&mut self, bit: u16) -> bool {
+		self.bits = self.bits << 1 | (bit & 1) as u64;
+		self.count += 1;
+
+		let address_bits = self.size.address_bits();
+
+		match self.state {
+			State::Command if self.count == 2 => {
+				self.state = match self.bits {
+					0b11 => State::ReadAddress,
+					0b10 => State::WriteAddress,
+					_ => State::Command,
+				};
+				self.read_position.set(None);
+				self.reset_bits();
+			}
+			State::ReadAddress | State::WriteAddress if self.count == address_bits => {
+				// Large EEPROMs only have 1024 blocks
+				self.block = (self.bits & 0x3ff) as usize % (self.data.len() / BLOCK_SIZE);
+				self.reading = self.state == State::ReadAddress;
+				self.state = if self.reading {
+					State::End
+				} else {
+					State::WriteData
+				};
+				self.reset_bits();
+			}
+			State::WriteData if self.count == 64 => {
+				let start = self.block * BLOCK_SIZE;
+				self.data[start..start + BLOCK_SIZE].copy_from_slice(&self.bits.to_be_bytes());
+				self.state = State::End;
+				self.reset_bits();
+				return true;
+			}
+			State::End => {
+				if self.reading {
+					self.read_position.set(Some(0));
+				}
+				self.state = State::Command;
+				self.reset_bits();
+			}
+			_ => (),
+		}
+
+		false
+	}
+
+	fn reset_bits(&mut self) {
+		self.bits = 0;
+		self.count = 0;
+	}
+}
+
+impl Snapshot for Eeprom {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.bool(self.size == EepromSize::Large);
+		state.bytes(&self.data);
+
+		let kind = match self.state {
+			State::Command => 0,
+			State::ReadAddress => 1,
+			State::WriteAddress => 2,
+			State::WriteData => 3,
+			State::End => 4,
+		};
+		state.u8(kind);
+		state.u64(self.bits);
+		state.u32(self.count as u32);
+		state.u32(self.block as u32);
+		state.bool(self.reading);
+		// Stored one higher, so that 0 can mean that there isn't a read
+		state.u32(
+			self.read_position
+				.get()
+				.map_or(0, |position| position as u32 + 1),
+		);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.size = if state.bool()? {
+			EepromSize::Large
+		} else {
+			EepromSize::Small
+		};
+		self.data = vec![0; self.size.bytes()];
+		state.fill(&mut self.data)?;
+
+		self.state = match state.u8()? {
+			0 => State::Command,
+			1 => State::ReadAddress,
+			2 => State::WriteAddress,
+			3 => State::WriteData,
+			4 => State::End,
+			_ => return Err(StateError::Invalid),
+		};
+		self.bits = state.u64()?;
+		self.count = state.u32()? as usize;
+		self.block = state.u32()? as usize;
+		self.reading = state.bool()?;
+		let position = state.u32()?;
+		self.read_position
+			.set(position.checked_sub(1).map(|position| position as usize));
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn send(eeprom: &mut Eeprom, value: u64, bits: usize) {
+		for bit in (0..bits).rev() {
+			eeprom.write((value >> bit & 1) as u16);
+		}
+	}
+
+	fn read_block(eeprom: &mut Eeprom, block: u64, address_bits: usize) -> u64 {
+		send(eeprom, 0b11, 2);
+		send(eeprom, block, address_bits);
+		send(eeprom, 0, 1);
+
+		let ignored: Vec<u16> = (0..4).map(|_| eeprom.read()).collect();
+		assert_eq!(ignored, [0, 0, 0, 0]);
+		(0..64).fold(0, |value, _| value << 1 | eeprom.read() as u64)
+	}
+
+	#[test]
+	fn read_and_write() {
+		let mut eeprom = Eeprom::init(EepromSize::Small);
+		assert_eq!(eeprom.read(), 1);
+
+		send(&mut eeprom, 0b10, 2);
+		send(&mut eeprom, 3, 6);
+		send(&mut eeprom, 0x0123_4567_89ab_cdef, 64);
+		send(&mut eeprom, 0, 1);
+
+		assert_eq!(
+			eeprom.data[24..32],
+			[0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]
+		);
+		assert_eq!(read_block(&mut eeprom, 3, 6), 0x0123_4567_89ab_cdef);
+		assert_eq!(read_block(&mut eeprom, 4, 6), u64::MAX);
+
+		// Ready again once the whole block has been read
+		assert_eq!(eeprom.read(), 1);
+	}
+
+	#[test]
+	fn large_addresses() {
+		let mut eeprom = Eeprom::init(EepromSize::Large);
+
+		send(&mut eeprom, 0b10, 2);
+		send(&mut eeprom, 0x3ff, 14);
+		send(&mut eeprom, 42, 64);
+		send(&mut eeprom, 0, 1);
+
+		assert_eq!(eeprom.data[0x1fff], 42);
+		assert_eq!(read_block(&mut eeprom, 0x3ff, 14), 42);
+	}
+
+	#[test]
+	fn transfer_length() {
+		assert_eq!(EepromSize::from_transfer_length(9), Some(EepromSize::Small));
+		assert_eq!(
+			EepromSize::from_transfer_length(81),
+			Some(EepromSize::Large)
+		);
+		assert_eq!(EepromSize::from_transfer_length(68), None);
+	}
+}
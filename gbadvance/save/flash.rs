@@ -0,0 +1,302 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Flash chips are split into 64K banks, and the 128K chips need a command to
+/// switch between them.
+pub const BANK_SIZE: usize = 0x1_0000;
+/// The erase sector command clears this many bytes at a time.
+const SECTOR_SIZE: usize = 0x1000;
+/// Atmel chips are written a page at a time rather than a byte at a time.
+const ATMEL_PAGE_SIZE: usize = 128;
+
+// Commands, which are written to 0x5555 after the unlock sequence
+const ENTER_ID_MODE: u8 = 0x90;
+const EXIT_ID_MODE: u8 = 0xf0;
+const PREPARE_ERASE: u8 = 0x80;
+const ERASE_CHIP: u8 = 0x10;
+const ERASE_SECTOR: u8 = 0x30;
+const PREPARE_WRITE: u8 = 0xa0;
+const SWITCH_BANK: u8 = 0xb0;
+
+/// The flash chips that were used in cartridges. Games check the ID of the chip
+/// to work out how to talk to it, so each one has to report the right ID.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FlashChip {
+	/// AT29LV512, 64K
+	Atmel,
+	/// MX29L512, 64K
+	Macronix64,
+	/// MX29L010, 128K
+	Macronix128,
+	/// MN63F805MNP, 64K
+	Panasonic,
+	/// LE26FV10N1TS, 128K
+	Sanyo,
+	/// SST39VF512, 64K
+	Sst,
+}
+
+impl FlashChip {
+	/// The manufacturer and device IDs reported in ID mode.
+	pub fn id(self) -> (u8, u8) {
+		match self {
+			FlashChip::Atmel => (0x1f, 0x3d),
+			FlashChip::Macronix64 => (0xc2, 0x1c),
+			FlashChip::Macronix128 => (0xc2, 0x09),
+			FlashChip::Panasonic => (0x32, 0x1b),
+			FlashChip::Sanyo => (0x62, 0x13),
+			FlashChip::Sst => (0xbf, 0xd4),
+		}
+	}
+
+	pub fn size(self) -> usize {
+		match self {
+			FlashChip::Macronix128 | FlashChip::Sanyo => BANK_SIZE * 2,
+			_ => BANK_SIZE,
+		}
+	}
+}
+
+/// Every kind of chip, in the order that they're numbered in save states.
+const CHIPS: [FlashChip; 6] = [
+	FlashChip::Atmel,
+	FlashChip::Macronix64,
+	FlashChip::Macronix128,
+	FlashChip::Panasonic,
+	FlashChip::Sanyo,
+	FlashChip::Sst,
+];
+
+/// Where the chip is in a command sequence. Every command starts by writing
+/// 0xaa to 0x5555 and then 0x55 to 0x2aaa.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+	Ready,
+	Unlocking,
+	Unlocked,
+	/// Waiting for the byte to write. Atmel chips take a whole page, so this
+	/// counts how many bytes are left.
+	Write(usize),
+	SwitchBank,
+}
+
+pub struct Flash {
+	pub chip: FlashChip,
+	pub data: Vec<u8>,
+	state: State,
+	/// Set after the prepare erase command, so that the next command erases.
+	erasing: bool,
+	/// Reads of the first two bytes return the chip's ID instead of data.
+	id_mode: bool,
+	bank: usize,
+}
+
+impl Flash {
+	pub fn init(chip: FlashChip) -> Self {
+		Self {
+			chip,
+			// Erased flash reads as all ones
+			data: vec![0xff; chip.size()],
+			state: State::Ready,
+			erasing: false,
+			id_mode: false,
+			bank: 0,
+		}
+	}
+
+	pub fn read(&self, offset: usize) -> u8 {
+		let offset = offset & 0xffff;
+
+		if self.id_mode && offset < 2 {
+			let (manufacturer, device) = self.chip.id();
+			return if offset == 0 { manufacturer } else { device };
+		}
+
+		self.data[self.bank * BANK_SIZE + offset]
+	}
+
+	/// Handles a write from the game, and returns whether it changed any of
+	/// the saved data.
+	pub fn write(&mut self, offset: usize, value: u8) -> bool {
+		let offset = offset & 0xffff;
+		let mut changed = false;
+
+		self.state = match (self.state, offset, value) {
+			(State::Write(remaining), ..) => {
+				self.data[self.bank * BANK_SIZE + offset] = value;
+				changed = true;
+				if remaining > 1 {
+					State::Write(remaining - 1)
+				} else {
+					State::Ready
+				}
+			}
+			(State::SwitchBank, 0, _) => {
+				if self.chip.size() > BANK_SIZE {
+					self.bank = value as usize & 1;
+				}
+				State::Ready
+			}
+			(State::Ready, 0x5555, 0xaa) => State::Unlocking,
+			(State::Unlocking, 0x2aaa, 0x55) => State::Unlocked,
+			(State::Unlocked, 0x5555, command) => {
+				changed = command == ERASE_CHIP && self.erasing;
+				self.command(command)
+			}
+			// Sectors are erased by writing the command to the sector itself
+			(State::Unlocked, _, ERASE_SECTOR) if self.erasing => {
+				let start = self.bank * BANK_SIZE + (offset & !(SECTOR_SIZE - 1));
+				self.data[start..start + SECTOR_SIZE].fill(0xff);
+				self.erasing = false;
+				changed = true;
+				State::Ready
+			}
+			_ => State::Ready,
+		};
+
+		changed
+	}
+
+	fn command(&mut self, command: u8) -> State {
+		match command {
+			ENTER_ID_MODE => self.id_mode = true,
+			EXIT_ID_MODE => self.id_mode = false,
+			PREPARE_ERASE => {
+				self.erasing = true;
+				return State::Ready;
+			}
+			ERASE_CHIP if self.erasing => self.data.fill(0xff),
+			PREPARE_WRITE if self.chip == FlashChip::Atmel => {
+				return State::Write(ATMEL_PAGE_SIZE);
+			}
+			PREPARE_WRITE => return State::Write(1),
+			SWITCH_BANK => return State::SwitchBank,
+			_ => (),
+		}
+
+		self.erasing = false;
+		State::Ready
+	}
+}
+
+impl Snapshot for Flash {
+	fn write_state(&self, state: &mut StateWriter) {
+		let chip = CHIPS.iter().position(|&chip| chip == self.chip).unwrap();
+		state.u8(chip as u8);
+		state.bytes(&self.data);
+
+		let (kind, remaining) = match self.state {
+			State::Ready => (0, 0),
+			State::Unlocking => (1, 0),
+			State::Unlocked => (2, 0),
+			State::Write(remaining) => (3, remaining),
+			State::SwitchBank => (4, 0),
+		};
+		state.u8(kind);
+		state.u32(remaining as u32);
+		state.bool(self.erasing);
+		state.bool(self.id_mode);
+		state.u8(self.bank as u8);
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		self.chip = *CHIPS.get(state.u8()? as usize).ok_or(StateError::Invalid)?;
+		self.data = vec![0; self.chip.size()];
+		state.fill(&mut self.data)?;
+
+		self.state = match (state.u8()?, state.u32()? as usize) {
+			(0, _) => State::Ready,
+			(1, _) => State::Unlocking,
+			(2, _) => State::Unlocked,
+			(3, remaining) => State::Write(remaining),
+			(4, _) => State::SwitchBank,
+			_ => return Err(StateError::Invalid),
+		};
+		self.erasing = state.bool()?;
+		self.id_mode = state.bool()?;
+		self.bank = state.u8()? as usize;
+		if self.bank * BANK_SIZE >= self.data.len() {
+			return Err(StateError::Invalid);
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn unlock(flash: &mut Flash, command: u8) {
+		flash.write(0x5555, 0xaa);
+		flash.write(0x2aaa, 0x55);
+		flash.write(0x5555, command);
+	}
+
+	#[test]
+	fn chip_id() {
+		let mut flash = Flash::init(FlashChip::Sanyo);
+
+		unlock(&mut flash, ENTER_ID_MODE);
+		assert_eq!(flash.read(0), 0x62);
+		assert_eq!(flash.read(1), 0x13);
+
+		unlock(&mut flash, EXIT_ID_MODE);
+		assert_eq!(flash.read(0), 0xff);
+	}
+
+	#[test]
+	fn write_and_erase() {
+		let mut flash = Flash::init(FlashChip::Macronix64);
+
+		// Writes without a command are ignored
+		flash.write(0x1234, 0x12);
+		assert_eq!(flash.read(0x1234), 0xff);
+
+		unlock(&mut flash, PREPARE_WRITE);
+		flash.write(0x1234, 0x12);
+		unlock(&mut flash, PREPARE_WRITE);
+		flash.write(0x2000, 0x20);
+		assert_eq!(flash.read(0x1234), 0x12);
+		assert_eq!(flash.read(0x2000), 0x20);
+
+		unlock(&mut flash, PREPARE_ERASE);
+		flash.write(0x5555, 0xaa);
+		flash.write(0x2aaa, 0x55);
+		flash.write(0x1000, ERASE_SECTOR);
+		assert_eq!(flash.read(0x1234), 0xff);
+		assert_eq!(flash.read(0x2000), 0x20);
+
+		unlock(&mut flash, PREPARE_ERASE);
+		unlock(&mut flash, ERASE_CHIP);
+		assert_eq!(flash.read(0x2000), 0xff);
+	}
+
+	#[test]
+	fn atmel_pages() {
+		let mut flash = Flash::init(FlashChip::Atmel);
+
+		unlock(&mut flash, PREPARE_WRITE);
+		for offset in 0..ATMEL_PAGE_SIZE {
+			flash.write(0x100 + offset, offset as u8);
+		}
+		flash.write(0x200, 0x55);
+
+		assert_eq!(flash.read(0x17f), 0x7f);
+		assert_eq!(flash.read(0x200), 0xff);
+	}
+
+	#[test]
+	fn bank_switching() {
+		let mut flash = Flash::init(FlashChip::Macronix128);
+
+		unlock(&mut flash, SWITCH_BANK);
+		flash.write(0, 1);
+		unlock(&mut flash, PREPARE_WRITE);
+		flash.write(0x10, 0x42);
+		assert_eq!(flash.data[BANK_SIZE + 0x10], 0x42);
+
+		unlock(&mut flash, SWITCH_BANK);
+		flash.write(0, 0);
+		assert_eq!(flash.read(0x10), 0xff);
+	}
+}
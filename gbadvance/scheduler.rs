@@ -0,0 +1,168 @@
+//! Hardware other than the CPU mostly does things at fixed points in time, like
+//! the PPU finishing a line. Rather than checking every piece of hardware after
+//! every instruction, each one schedules an event for when it next needs to do
+//! something, and the emulator handles events as the clock passes them.
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Something that needs to happen at a certain point in time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+	/// The PPU has finished drawing the visible part of the current line.
+	HBlank,
+	/// The PPU has reached the end of the current line, including H-Blank.
+	LineEnd,
+	/// One of the timers has counted past 0xffff.
+	TimerOverflow(usize),
+	/// It's time to mix the next audio sample.
+	AudioSample,
+	/// The next step of the PSG's frame sequencer.
+	FrameSequencer,
+}
+
+pub struct Scheduler {
+	/// The number of cycles that have passed since the emulator was started.
+	pub now: u64,
+	/// Events that haven't happened yet, along with the time they should happen
+	/// at. Sorted so that the next event is at the end.
+	events: Vec<(u64, Event)>,
+}
+
+impl Scheduler {
+	pub fn init() -> Self {
+		Self {
+			now: 0,
+			events: Vec::new(),
+		}
+	}
+
+	/// Schedules an event to happen `delay` cycles from now.
+	pub fn schedule(&mut self, delay: u64, event: Event) {
+		self.schedule_at(self.now + delay, event);
+	}
+
+	/// Schedules an event to happen at an exact time. Events scheduled for the
+	/// same time happen in the order that they were scheduled.
+	pub fn schedule_at(&mut self, time: u64, event: Event) {
+		let index = self.events.partition_point(|&(other, _)| other > time);
+		self.events.insert(index, (time, event));
+	}
+
+	/// Removes any pending occurrences of an event.
+	pub fn cancel(&mut self, event: Event) {
+		self.events.retain(|&(_, other)| other != event);
+	}
+
+	/// Moves the clock forward.
+	pub fn advance(&mut self, cycles: u64) {
+		self.now += cycles;
+	}
+
+	/// Removes and returns the next event if its time has come, along with the
+	/// time that it was scheduled for. That time may be a little in the past,
+	/// because instructions can take more than one cycle.
+	pub fn pop_due(&mut self) -> Option<(u64, Event)> {
+		match self.events.last() {
+			Some(&(time, _)) if time <= self.now => self.events.pop(),
+			_ => None,
+		}
+	}
+
+	/// Returns when the next event will happen, if there is one.
+	pub fn next_event_time(&self) -> Option<u64> {
+		self.events.last().map(|&(time, _)| time)
+	}
+}
+
+impl Snapshot for Scheduler {
+	fn write_state(&self, state: &mut StateWriter) {
+		state.u64(self.now);
+		state.u32(self.events.len() as u32);
+
+		for &(time, event) in &self.events {
+			let (kind, timer) = match event {
+				Event::HBlank => (0, 0),
+				Event::LineEnd => (1, 0),
+				Event::TimerOverflow(timer) => (2, timer as u8),
+				Event::AudioSample => (3, 0),
+				Event::FrameSequencer => (4, 0),
+			};
+			state.u64(time);
+			state.u8(kind);
+			state.u8(timer);
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		let now = state.u64()?;
+		let count = state.u32()?;
+		let mut events = Vec::new();
+
+		for _ in 0..count {
+			let time = state.u64()?;
+			let event = match (state.u8()?, state.u8()?) {
+				(0, _) => Event::HBlank,
+				(1, _) => Event::LineEnd,
+				(2, timer @ 0..=3) => Event::TimerOverflow(timer as usize),
+				(3, _) => Event::AudioSample,
+				(4, _) => Event::FrameSequencer,
+				_ => return Err(StateError::Invalid),
+			};
+			events.push((time, event));
+		}
+
+		// The events were saved in the order that they're kept in, so events at
+		// the same time still happen in the same order
+		self.now = now;
+		self.events = events;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn events_happen_in_order() {
+		let mut scheduler = Scheduler::init();
+
+		scheduler.schedule(20, Event::LineEnd);
+		scheduler.schedule(10, Event::HBlank);
+		assert_eq!(scheduler.next_event_time(), Some(10));
+		assert_eq!(scheduler.pop_due(), None);
+
+		scheduler.advance(15);
+		assert_eq!(scheduler.pop_due(), Some((10, Event::HBlank)));
+		assert_eq!(scheduler.pop_due(), None);
+
+		scheduler.advance(15);
+		assert_eq!(scheduler.pop_due(), Some((20, Event::LineEnd)));
+		assert_eq!(scheduler.next_event_time(), None);
+	}
+
+	#[test]
+	fn simultaneous_events_keep_their_order() {
+		let mut scheduler = Scheduler::init();
+
+		scheduler.schedule(10, Event::LineEnd);
+		scheduler.schedule(10, Event::HBlank);
+		scheduler.advance(10);
+
+		assert_eq!(scheduler.pop_due(), Some((10, Event::LineEnd)));
+		assert_eq!(scheduler.pop_due(), Some((10, Event::HBlank)));
+	}
+
+	#[test]
+	fn cancel() {
+		let mut scheduler = Scheduler::init();
+
+		scheduler.schedule(10, Event::LineEnd);
+		scheduler.schedule(10, Event::HBlank);
+		scheduler.cancel(Event::LineEnd);
+		scheduler.advance(10);
+
+		assert_eq!(scheduler.pop_due(), Some((10, Event::HBlank)));
+		assert_eq!(scheduler.pop_due(), None);
+	}
+}
@@ -0,0 +1,448 @@
+//! Save states capture everything about the running emulator, so that it can be
+//! put back exactly where it was later. The cartridge's ROM isn't included, so
+//! a state can only be loaded while the same game is inserted.
+//!
+//! A state starts with a magic number and a version, followed by a list of
+//! sections, one for each part of the hardware. Every section has a tag and a
+//! length, so that sections which a version doesn't know about can be skipped.
+//! New fields are always added to the end of a section, and anything left over
+//! once the known fields have been read is ignored, which lets older versions
+//! load states from newer ones. The version only changes when the existing
+//! layout does, and states with a version that isn't understood are rejected.
+
+use crate::emulator::Emulator;
+use lavender_armv4t::registers::RegisterSet;
+
+/// Every save state starts with this.
+pub const MAGIC: [u8; 4] = *b"LVST";
+/// The version of the layout that is written.
+pub const VERSION: u32 = 1;
+
+/// The part of the cartridge header with the title, game code, version and
+/// checksum, which is used to make sure that states are loaded into the game
+/// that they came from.
+const CARTRIDGE_ID: std::ops::Range<usize> = 0xa0..0xbe;
+
+// Section tags
+const CARTRIDGE: [u8; 4] = *b"CART";
+const CPU: [u8; 4] = *b"CPU ";
+const EMULATOR: [u8; 4] = *b"EMU ";
+const MEMORY: [u8; 4] = *b"MEM ";
+const SCHEDULER: [u8; 4] = *b"SCHD";
+const DMA: [u8; 4] = *b"DMA ";
+const TIMERS: [u8; 4] = *b"TIMR";
+const APU: [u8; 4] = *b"APU ";
+const PPU: [u8; 4] = *b"PPU ";
+const SAVE: [u8; 4] = *b"SAVE";
+const PERIPHERALS: [u8; 4] = *b"PERI";
+
+/// The reasons that a save state can be rejected.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StateError {
+	/// The data doesn't start with the magic number, so it isn't a save state.
+	NotAState,
+	/// The state has a layout that this version doesn't understand.
+	UnsupportedVersion(u32),
+	/// The state was made while a different game was inserted.
+	WrongGame,
+	/// A section ended before all of its fields had been read.
+	Truncated,
+	/// A field has a value that can't be right, like a memory region of the
+	/// wrong size.
+	Invalid,
+}
+
+/// Something that can be written into a save state, and restored from one.
+pub trait Snapshot {
+	fn write_state(&self, state: &mut StateWriter);
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Builds up a save state. Everything is little endian.
+pub struct StateWriter {
+	data: Vec<u8>,
+}
+
+impl StateWriter {
+	pub fn init() -> Self {
+		Self { data: Vec::new() }
+	}
+
+	pub fn finish(self) -> Vec<u8> {
+		self.data
+	}
+
+	pub fn u8(&mut self, value: u8) {
+		self.data.push(value);
+	}
+
+	pub fn u16(&mut self, value: u16) {
+		self.data.extend(value.to_le_bytes());
+	}
+
+	pub fn u32(&mut self, value: u32) {
+		self.data.extend(value.to_le_bytes());
+	}
+
+	pub fn u64(&mut self, value: u64) {
+		self.data.extend(value.to_le_bytes());
+	}
+
+	pub fn i32(&mut self, value: i32) {
+		self.u32(value as u32);
+	}
+
+	pub fn i64(&mut self, value: i64) {
+		self.u64(value as u64);
+	}
+
+	pub fn bool(&mut self, value: bool) {
+		self.u8(value as u8);
+	}
+
+	/// Writes a block of bytes, along with its length.
+	pub fn bytes(&mut self, value: &[u8]) {
+		self.u32(value.len() as u32);
+		self.data.extend(value);
+	}
+
+	/// Writes a section, filling in its length once everything in it has been
+	/// written.
+	pub fn section(&mut self, tag: [u8; 4], write: impl FnOnce(&mut Self)) {
+		self.data.extend(tag);
+		let start = self.data.len();
+		self.u32(0);
+
+		write(self);
+
+		let length = (self.data.len() - start - 4) as u32;
+		self.data[start..start + 4].copy_from_slice(&length.to_le_bytes());
+	}
+}
+
+/// Reads the fields of a save state back out, in the same order that they
+/// were written.
+pub struct StateReader<'a> {
+	data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+	pub fn init(data: &'a [u8]) -> Self {
+		Self { data }
+	}
+
+	fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+		let bytes = self.data.get(..N).ok_or(StateError::Truncated)?;
+		self.data = &self.data[N..];
+		Ok(bytes.try_into().unwrap())
+	}
+
+	pub fn u8(&mut self) -> Result<u8, StateError> {
+		Ok(self.take::<1>()?[0])
+	}
+
+	pub fn u16(&mut self) -> Result<u16, StateError> {
+		Ok(u16::from_le_bytes(self.take()?))
+	}
+
+	pub fn u32(&mut self) -> Result<u32, StateError> {
+		Ok(u32::from_le_bytes(self.take()?))
+	}
+
+	pub fn u64(&mut self) -> Result<u64, StateError> {
+		Ok(u64::from_le_bytes(self.take()?))
+	}
+
+	pub fn i32(&mut self) -> Result<i32, StateError> {
+		Ok(self.u32()? as i32)
+	}
+
+	pub fn i64(&mut self) -> Result<i64, StateError> {
+		Ok(self.u64()? as i64)
+	}
+
+	pub fn bool(&mut self) -> Result<bool, StateError> {
+		Ok(self.u8()? > 0)
+	}
+
+	/// Reads a block of bytes written by `StateWriter::bytes`.
+	pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+		let length = self.u32()? as usize;
+		let bytes = self.data.get(..length).ok_or(StateError::Truncated)?;
+		self.data = &self.data[length..];
+		Ok(bytes)
+	}
+
+	/// Reads a block of bytes into a buffer, which has to be exactly the same
+	/// size as the block.
+	pub fn fill(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+		let bytes = self.bytes()?;
+		if bytes.len() != buffer.len() {
+			return Err(StateError::Invalid);
+		}
+
+		buffer.copy_from_slice(bytes);
+		Ok(())
+	}
+
+	/// Reads the next section, returning its tag and a reader for its contents.
+	fn section(&mut self) -> Result<([u8; 4], StateReader<'a>), StateError> {
+		let tag = self.take()?;
+		let contents = self.bytes()?;
+		Ok((tag, StateReader::init(contents)))
+	}
+}
+
+impl Snapshot for RegisterSet {
+	fn write_state(&self, state: &mut StateWriter) {
+		for value in [
+			self.r0,
+			self.r1,
+			self.r2,
+			self.r3,
+			self.r4,
+			self.r5,
+			self.r6,
+			self.r7,
+			self.r8,
+			self.r8_fiq,
+			self.r9,
+			self.r9_fiq,
+			self.r10,
+			self.r10_fiq,
+			self.r11,
+			self.r11_fiq,
+			self.r12,
+			self.r12_fiq,
+			self.r13,
+			self.r13_fiq,
+			self.r13_svc,
+			self.r13_abt,
+			self.r13_irq,
+			self.r13_und,
+			self.r14,
+			self.r14_fiq,
+			self.r14_svc,
+			self.r14_abt,
+			self.r14_irq,
+			self.r14_und,
+			self.r15,
+			self.cpsr,
+			self.spsr_fiq,
+			self.spsr_svc,
+			self.spsr_abt,
+			self.spsr_irq,
+			self.spsr_und,
+		] {
+			state.u32(value);
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		for value in [
+			&mut self.r0,
+			&mut self.r1,
+			&mut self.r2,
+			&mut self.r3,
+			&mut self.r4,
+			&mut self.r5,
+			&mut self.r6,
+			&mut self.r7,
+			&mut self.r8,
+			&mut self.r8_fiq,
+			&mut self.r9,
+			&mut self.r9_fiq,
+			&mut self.r10,
+			&mut self.r10_fiq,
+			&mut self.r11,
+			&mut self.r11_fiq,
+			&mut self.r12,
+			&mut self.r12_fiq,
+			&mut self.r13,
+			&mut self.r13_fiq,
+			&mut self.r13_svc,
+			&mut self.r13_abt,
+			&mut self.r13_irq,
+			&mut self.r13_und,
+			&mut self.r14,
+			&mut self.r14_fiq,
+			&mut self.r14_svc,
+			&mut self.r14_abt,
+			&mut self.r14_irq,
+			&mut self.r14_und,
+			&mut self.r15,
+			&mut self.cpsr,
+			&mut self.spsr_fiq,
+			&mut self.spsr_svc,
+			&mut self.spsr_abt,
+			&mut self.spsr_irq,
+			&mut self.spsr_und,
+		] {
+			*value = state.u32()?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Emulator {
+	/// Captures the whole state of the emulator.
+	pub fn save_state(&self) -> Vec<u8> {
+		let mut state = StateWriter::init();
+		state.data.extend(MAGIC);
+		state.u32(VERSION);
+
+		state.section(CARTRIDGE, |state| state.bytes(&self.cartridge_id()));
+		state.section(CPU, |state| self.cpu.registers.write_state(state));
+		state.section(EMULATOR, |state| state.bool(self.frame_complete));
+		state.section(MEMORY, |state| self.memory.write_state(state));
+		state.section(SCHEDULER, |state| self.memory.scheduler.write_state(state));
+		state.section(DMA, |state| self.memory.dma.write_state(state));
+		state.section(TIMERS, |state| self.memory.timers.write_state(state));
+		state.section(APU, |state| self.memory.apu.write_state(state));
+		state.section(PPU, |state| self.ppu.write_state(state));
+		state.section(SAVE, |state| self.memory.save.write_state(state));
+		state.section(PERIPHERALS, |state| {
+			self.memory.peripherals.write_state(state)
+		});
+
+		state.finish()
+	}
+
+	/// Puts the emulator back into a state captured by `save_state`. If the
+	/// state can't be loaded, the emulator is left as it was.
+	pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+		let mut state = StateReader::init(data);
+		if state.take::<4>() != Ok(MAGIC) {
+			return Err(StateError::NotAState);
+		}
+
+		let version = state.u32()?;
+		if version != VERSION {
+			return Err(StateError::UnsupportedVersion(version));
+		}
+
+		// Make sure that the state is for this game before touching anything
+		let (tag, mut cartridge) = state.section()?;
+		if tag != CARTRIDGE || cartridge.bytes()? != self.cartridge_id() {
+			return Err(StateError::WrongGame);
+		}
+
+		// Sections are read straight into the hardware, so if one of them turns
+		// out to be broken, everything before it has to be put back
+		let backup = self.save_state();
+		let result = self.read_sections(&mut state);
+		if result.is_err() {
+			let mut backup = StateReader::init(&backup[8..]);
+			self.read_sections(&mut backup)
+				.expect("the emulator's own state should always load");
+		}
+
+		result
+	}
+
+	fn read_sections(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		while !state.data.is_empty() {
+			let (tag, mut section) = state.section()?;
+			let section = &mut section;
+
+			match tag {
+				CPU => self.cpu.registers.read_state(section)?,
+				EMULATOR => self.frame_complete = section.bool()?,
+				MEMORY => self.memory.read_state(section)?,
+				SCHEDULER => self.memory.scheduler.read_state(section)?,
+				DMA => self.memory.dma.read_state(section)?,
+				TIMERS => self.memory.timers.read_state(section)?,
+				APU => self.memory.apu.read_state(section)?,
+				PPU => self.ppu.read_state(section)?,
+				SAVE => self.memory.save.read_state(section)?,
+				PERIPHERALS => self.memory.peripherals.read_state(section)?,
+				// Either the cartridge, which has already been checked, or
+				// something from a newer version
+				_ => (),
+			}
+		}
+
+		Ok(())
+	}
+
+	fn cartridge_id(&self) -> Vec<u8> {
+		let rom = &self.memory.rom;
+		let mut id = rom.get(CARTRIDGE_ID).unwrap_or_default().to_vec();
+		id.extend((rom.len() as u32).to_le_bytes());
+		id
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::RAM_START;
+
+	#[test]
+	fn round_trip() {
+		let mut emulator = Emulator::new_idle();
+		emulator.step_frame();
+		emulator.memory.write_word(RAM_START as u32, 0x1234_5678);
+		emulator.memory.save.write(0x10, 0x42);
+		let state = emulator.save_state();
+
+		emulator.step_frame();
+		emulator.memory.write_word(RAM_START as u32, 0);
+		emulator.memory.save.write(0x10, 0);
+		assert_ne!(emulator.save_state(), state);
+
+		emulator.load_state(&state).unwrap();
+		assert_eq!(emulator.save_state(), state);
+		assert_eq!(emulator.memory.read_word(RAM_START as u32), 0x1234_5678);
+		assert_eq!(emulator.memory.save.read(0x10), 0x42);
+
+		// Carrying on from the state ends up in the same place each time
+		emulator.step_frame();
+		let after = emulator.save_state();
+		emulator.load_state(&state).unwrap();
+		emulator.step_frame();
+		assert_eq!(emulator.save_state(), after);
+	}
+
+	#[test]
+	fn newer_sections_are_skipped() {
+		let mut emulator = Emulator::new_idle();
+		emulator.step_frame();
+		let mut state = emulator.save_state();
+		state.extend(b"NEW!");
+		state.extend(3u32.to_le_bytes());
+		state.extend([1, 2, 3]);
+
+		assert_eq!(emulator.load_state(&state), Ok(()));
+	}
+
+	#[test]
+	fn rejected_states() {
+		let mut emulator = Emulator::new_idle();
+		emulator.step_frame();
+		let state = emulator.save_state();
+
+		assert_eq!(emulator.load_state(b"nope"), Err(StateError::NotAState));
+
+		let mut newer = state.clone();
+		newer[4] = 2;
+		assert_eq!(
+			emulator.load_state(&newer),
+			Err(StateError::UnsupportedVersion(2))
+		);
+
+		let mut other = Emulator::new();
+		other.load_rom(&[0; 0x200]);
+		assert_eq!(other.load_state(&state), Err(StateError::WrongGame));
+
+		// A broken state doesn't leave the emulator half loaded
+		emulator.step_frame();
+		let before = emulator.save_state();
+		assert_eq!(
+			emulator.load_state(&state[..state.len() - 10]),
+			Err(StateError::Truncated)
+		);
+		assert_eq!(emulator.save_state(), before);
+	}
+}
@@ -0,0 +1,276 @@
+//! The four 16-bit timers count up at a fraction of the CPU clock, and can
+//! raise an interrupt when they overflow. Rather than counting every cycle, each
+//! timer remembers when it was last started, works out its counter from the
+//! current time when it is read, and schedules an event for when it overflows.
+
+use crate::interrupts::Interrupt;
+use crate::memory::{Memory, IO_START};
+use crate::scheduler::Event;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Reading this register gives the current value of the counter, but writing to
+/// it sets the reload value, which the counter starts from when the timer is
+/// enabled and after every overflow. The registers of each timer are 4 bytes
+/// after the previous one.
+pub const TM0CNT_L: u32 = 0x0400_0100;
+pub const TM0CNT_H: u32 = 0x0400_0102;
+
+/// How far to shift the number of elapsed cycles to turn them into ticks, for
+/// each prescaler setting (1, 64, 256 and 1024 cycles per tick).
+const PRESCALER_SHIFTS: [u32; 4] = [0, 6, 8, 10];
+
+// Bits of TMxCNT_H
+const COUNT_UP: u16 = 1 << 2;
+const IRQ: u16 = 1 << 6;
+const ENABLE: u16 = 1 << 7;
+
+const INTERRUPTS: [Interrupt; 4] = [
+	Interrupt::Timer0,
+	Interrupt::Timer1,
+	Interrupt::Timer2,
+	Interrupt::Timer3,
+];
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timer {
+	/// The value of the counter at `since`.
+	counter: u16,
+	/// The time that the counter was last updated.
+	since: u64,
+}
+
+#[derive(Default)]
+pub struct Timers {
+	pub timers: [Timer; 4],
+}
+
+impl Timers {
+	pub fn init() -> Self {
+		Self::default()
+	}
+}
+
+fn register(base: u32, timer: usize) -> u32 {
+	base + timer as u32 * 4
+}
+
+/// If the address is part of a timer's counter register, returns the number of
+/// that timer.
+pub fn counter_timer(address: u32) -> Option<usize> {
+	(0..4).find(|&timer| register(TM0CNT_L, timer) == address & !1)
+}
+
+/// If the address is the byte of a timer's control register with all of the
+/// settings in it, returns the number of that timer.
+pub fn control_timer(address: u32) -> Option<usize> {
+	(0..4).find(|&timer| register(TM0CNT_H, timer) == address)
+}
+
+/// Cascading timers only count when the previous timer overflows. Timer 0 has
+/// no previous timer, so the setting does nothing there.
+fn is_ticking(timer: usize, control: u16) -> bool {
+	control & ENABLE > 0 && (timer == 0 || control & COUNT_UP == 0)
+}
+
+impl Memory {
+	fn timer_control(&self, timer: usize) -> u16 {
+		self.io[register(TM0CNT_H, timer) as usize - IO_START] as u16
+	}
+
+	fn timer_reload(&self, timer: usize) -> u16 {
+		let offset = register(TM0CNT_L, timer) as usize - IO_START;
+		u16::from_le_bytes([self.io[offset], self.io[offset + 1]])
+	}
+
+	/// Works out the current value of a timer's counter, as if it were using the
+	/// settings in `control`.
+	fn timer_counter_with(&self, timer: usize, control: u16) -> u16 {
+		let state = self.timers.timers[timer];
+
+		if !is_ticking(timer, control) {
+			return state.counter;
+		}
+
+		let shift = PRESCALER_SHIFTS[(control & 3) as usize];
+		let ticks = (self.scheduler.now - state.since) >> shift;
+		state.counter.wrapping_add(ticks as u16)
+	}
+
+	/// Returns the current value of a timer's counter.
+	pub fn timer_counter(&self, timer: usize) -> u16 {
+		self.timer_counter_with(timer, self.timer_control(timer))
+	}
+
+	/// Called after a timer's control register has been written to, with the
+	/// value that it had beforehand.
+	pub fn timer_control_written(&mut self, timer: usize, previous: u8) {
+		let control = self.timer_control(timer);
+
+		// Bring the counter up to date using the old settings before the new ones
+		// take effect. Starting a timer loads the reload value into the counter.
+		let counter = if control & ENABLE > 0 && previous as u16 & ENABLE == 0 {
+			self.timer_reload(timer)
+		} else {
+			self.timer_counter_with(timer, previous as u16)
+		};
+
+		self.timers.timers[timer] = Timer {
+			counter,
+			since: self.scheduler.now,
+		};
+		self.schedule_timer(timer);
+	}
+
+	/// Schedules the next overflow of a timer, if it is counting on its own.
+	fn schedule_timer(&mut self, timer: usize) {
+		let control = self.timer_control(timer);
+		let state = self.timers.timers[timer];
+
+		self.scheduler.cancel(Event::TimerOverflow(timer));
+
+		if is_ticking(timer, control) {
+			let shift = PRESCALER_SHIFTS[(control & 3) as usize];
+			let remaining = (0x10000 - state.counter as u64) << shift;
+			self.scheduler
+				.schedule_at(state.since + remaining, Event::TimerOverflow(timer));
+		}
+	}
+
+	/// Called when a timer's overflow event happens.
+	pub fn timer_overflow(&mut self, timer: usize, time: u64) {
+		self.timers.timers[timer] = Timer {
+			counter: self.timer_reload(timer),
+			since: time,
+		};
+		self.schedule_timer(timer);
+		self.timer_overflowed(timer);
+	}
+
+	/// Raises the interrupt for a timer that just overflowed, lets the sound FIFOs
+	/// know, and ticks the next timer if it is cascading.
+	fn timer_overflowed(&mut self, timer: usize) {
+		if self.timer_control(timer) & IRQ > 0 {
+			self.request_interrupt(INTERRUPTS[timer]);
+		}
+
+		if timer < 2 {
+			self.sound_timer_overflow(timer);
+		}
+
+		let next = timer + 1;
+		if next == 4 {
+			return;
+		}
+
+		let control = self.timer_control(next);
+		if control & ENABLE == 0 || control & COUNT_UP == 0 {
+			return;
+		}
+
+		let counter = self.timers.timers[next].counter.wrapping_add(1);
+
+		if counter == 0 {
+			self.timers.timers[next].counter = self.timer_reload(next);
+			self.timer_overflowed(next);
+		} else {
+			self.timers.timers[next].counter = counter;
+		}
+	}
+}
+
+impl Snapshot for Timers {
+	fn write_state(&self, state: &mut StateWriter) {
+		for timer in &self.timers {
+			state.u16(timer.counter);
+			state.u64(timer.since);
+		}
+	}
+
+	fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+		for timer in &mut self.timers {
+			timer.counter = state.u16()?;
+			timer.since = state.u64()?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::interrupts::IF;
+
+	/// Moves the clock forward, and handles any timer events along the way.
+	fn run_until(memory: &mut Memory, time: u64) {
+		memory.scheduler.now = time;
+
+		while let Some((time, event)) = memory.scheduler.pop_due() {
+			if let Event::TimerOverflow(timer) = event {
+				memory.timer_overflow(timer, time);
+			}
+		}
+	}
+
+	#[test]
+	fn prescaler_and_reload() {
+		let mut memory = Memory::init();
+
+		// Count every 64 cycles, starting from 0xff00
+		memory.write_half_word(TM0CNT_L, 0xff00);
+		memory.write_half_word(TM0CNT_H, ENABLE | IRQ | 1);
+		assert_eq!(memory.read_half_word(TM0CNT_L), 0xff00);
+
+		run_until(&mut memory, 64 * 0x10 + 63);
+		assert_eq!(memory.read_half_word(TM0CNT_L), 0xff10);
+
+		// Changing the reload value doesn't affect the running counter
+		memory.write_half_word(TM0CNT_L, 0xfff0);
+		assert_eq!(memory.read_half_word(TM0CNT_L), 0xff10);
+		assert_eq!(memory.read_half_word(IF), 0);
+
+		run_until(&mut memory, 64 * 0x100);
+		assert_eq!(memory.read_half_word(TM0CNT_L), 0xfff0);
+		assert_eq!(memory.read_half_word(IF), 1 << 3);
+
+		run_until(&mut memory, 64 * 0x108);
+		assert_eq!(memory.read_half_word(TM0CNT_L), 0xfff8);
+	}
+
+	#[test]
+	fn stopping_keeps_the_counter() {
+		let mut memory = Memory::init();
+
+		memory.write_half_word(TM0CNT_H, ENABLE);
+		run_until(&mut memory, 100);
+		memory.write_half_word(TM0CNT_H, 0);
+		run_until(&mut memory, 200);
+		assert_eq!(memory.read_half_word(TM0CNT_L), 100);
+
+		// Starting it again reloads the counter
+		memory.write_half_word(TM0CNT_H, ENABLE);
+		assert_eq!(memory.read_half_word(TM0CNT_L), 0);
+	}
+
+	#[test]
+	fn cascade() {
+		let mut memory = Memory::init();
+
+		// Timer 1 counts timer 0's overflows, and overflows itself every 2
+		memory.write_half_word(register(TM0CNT_L, 1), 0xfffe);
+		memory.write_half_word(register(TM0CNT_H, 1), ENABLE | COUNT_UP | IRQ);
+		memory.write_half_word(TM0CNT_L, 0xfffc);
+		memory.write_half_word(TM0CNT_H, ENABLE);
+
+		run_until(&mut memory, 4);
+		assert_eq!(memory.read_half_word(register(TM0CNT_L, 1)), 0xffff);
+
+		run_until(&mut memory, 6);
+		assert_eq!(memory.read_half_word(register(TM0CNT_L, 1)), 0xffff);
+		assert_eq!(memory.read_half_word(IF), 0);
+
+		run_until(&mut memory, 8);
+		assert_eq!(memory.read_half_word(register(TM0CNT_L, 1)), 0xfffe);
+		assert_eq!(memory.read_half_word(IF), 1 << 4);
+	}
+}
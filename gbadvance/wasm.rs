@@ -0,0 +1,375 @@
+//! The bindings that let JavaScript run the emulator in the browser. Each
+//! `Emulator` that JavaScript creates owns a separate emulator, and everything
+//! else is a method on it. The hardware, including the picture processing unit,
+//! is emulated inside of Rust, and JavaScript is only responsible for putting
+//! the finished frames on the screen and playing the audio.
+
+use crate::bios::BiosMode;
+use crate::emulator::Emulator;
+use crate::movie::Movie;
+use crate::peripherals::rtc::Clock;
+use crate::rewind::Rewind;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+extern "C" {
+	type Date;
+
+	#[wasm_bindgen(constructor)]
+	fn new() -> Date;
+	#[wasm_bindgen(method, js_name = getTime)]
+	fn get_time(this: &Date) -> f64;
+	#[wasm_bindgen(method, js_name = getTimezoneOffset)]
+	fn get_timezone_offset(this: &Date) -> f64;
+}
+
+/// Gives the cartridge clock the local time of the browser, since games
+/// expect the clock to show the time where the player is.
+struct BrowserClock;
+
+impl Clock for BrowserClock {
+	fn now(&self) -> i64 {
+		let date = Date::new();
+		let offset = date.get_timezone_offset() as i64 * 60;
+		(date.get_time() / 1000.0) as i64 - offset
+	}
+}
+
+/// An emulator, as JavaScript sees it.
+#[wasm_bindgen(js_name = Emulator)]
+pub struct Handle {
+	emulator: Emulator,
+}
+
+#[wasm_bindgen(js_class = Emulator)]
+impl Handle {
+	#[wasm_bindgen(constructor)]
+	pub fn init() -> Self {
+		// Panics are printed to the browser console, rather than just showing
+		// up as an unreachable instruction
+		console_error_panic_hook::set_once();
+
+		Self {
+			emulator: Emulator::new(),
+		}
+	}
+
+	/// Starts the emulation of the provided ROM. Skipping the BIOS jumps straight
+	/// into the game, rather than showing the intro first.
+	pub fn init_emulation(&mut self, rom: &[u8], skip_bios: bool) {
+		self.emulator.memory.peripherals.host.clock = Box::new(BrowserClock);
+		self.emulator.load_rom(rom);
+		self.emulator.boot(skip_bios);
+	}
+
+	/// Returns the title from the cartridge header.
+	pub fn get_cartridge_title(&self) -> Option<String> {
+		self.emulator
+			.cartridge
+			.as_ref()
+			.map(|cartridge| cartridge.title.clone())
+	}
+
+	/// Returns the 4 character game code from the cartridge header.
+	pub fn get_cartridge_game_code(&self) -> Option<String> {
+		self.emulator
+			.cartridge
+			.as_ref()
+			.map(|cartridge| cartridge.game_code.clone())
+	}
+
+	/// Returns the 2 character maker code from the cartridge header.
+	pub fn get_cartridge_maker_code(&self) -> Option<String> {
+		self.emulator
+			.cartridge
+			.as_ref()
+			.map(|cartridge| cartridge.maker_code.clone())
+	}
+
+	/// Returns the software version from the cartridge header.
+	pub fn get_cartridge_version(&self) -> Option<u8> {
+		self.emulator
+			.cartridge
+			.as_ref()
+			.map(|cartridge| cartridge.version)
+	}
+
+	/// Checks whether the cartridge header has a valid Nintendo logo and checksum,
+	/// which the BIOS requires before it will boot the game.
+	pub fn is_cartridge_header_valid(&self) -> bool {
+		self.emulator
+			.cartridge
+			.as_ref()
+			.is_some_and(|cartridge| cartridge.is_valid())
+	}
+
+	/// Copies out the cartridge's save data, in the .sav format used by other
+	/// emulators and flash carts. The save counts as clean again afterwards.
+	pub fn export_save(&mut self) -> Vec<u8> {
+		self.emulator.memory.save.mark_clean();
+		self.emulator.memory.save.export()
+	}
+
+	/// Loads a .sav file into the cartridge's save memory. Returns `false` if it
+	/// isn't the right size for the kind of save memory that the game uses.
+	pub fn import_save(&mut self, data: &[u8]) -> bool {
+		self.emulator.memory.save.import(data)
+	}
+
+	/// Checks whether the game has changed its save data since it was last
+	/// exported, which means that it should be written out again.
+	pub fn is_save_dirty(&self) -> bool {
+		self.emulator.memory.save.is_dirty()
+	}
+
+	/// Returns a pointer to the beginning of the IO memory section.
+	pub fn get_io_address(&mut self) -> *mut u8 {
+		&mut self.emulator.memory.io[0] as *mut u8
+	}
+
+	/// Returns a pointer to the beginning of the palette memory section.
+	pub fn get_palette_address(&self) -> *const u8 {
+		&self.emulator.memory.palette[0] as *const u8
+	}
+
+	/// Returns a pointer to the beginning of the VRAM memory section.
+	pub fn get_vram_address(&self) -> *const u8 {
+		&self.emulator.memory.vram[0] as *const u8
+	}
+
+	/// Returns a pointer to the beginning of the object attribute memory section.
+	pub fn get_object_address(&self) -> *const u8 {
+		&self.emulator.memory.object[0] as *const u8
+	}
+
+	/// Returns a pointer to the beginning of the most recently rendered frame. The
+	/// frame is 240x160 pixels, stored as 8-bit RGBA.
+	pub fn get_framebuffer_address(&self) -> *const u8 {
+		&self.emulator.ppu.framebuffer[0] as *const u8
+	}
+
+	/// Called from JavaScript when it is time to produce the next frame.
+	pub fn step_frames(&mut self, frames: u32) {
+		for _ in 0..frames {
+			self.emulator.step_frame();
+		}
+	}
+
+	/// Switches on rewinding, taking a snapshot every `interval` frames and
+	/// keeping up to `budget` bytes of them. An interval of 0 switches it off.
+	pub fn set_rewind(&mut self, interval: u32, budget: usize) {
+		self.emulator.rewind = (interval > 0).then(|| Rewind::init(interval, budget));
+	}
+
+	/// Goes back in time by about `frames` frames. Returns the number of frames
+	/// that were actually rewound, which is 0 once the history runs out.
+	pub fn rewind(&mut self, frames: u32) -> u32 {
+		self.emulator.rewind(frames)
+	}
+
+	/// Captures everything about the running game, so that it can be restored
+	/// later with `load_state`.
+	pub fn save_state(&self) -> Vec<u8> {
+		self.emulator.save_state()
+	}
+
+	/// Restores a save state made by `save_state`. Returns `false` if it isn't a
+	/// save state, or was made with a different game, in which case nothing
+	/// changes.
+	pub fn load_state(&mut self, data: &[u8]) -> bool {
+		self.emulator.load_state(data).is_ok()
+	}
+
+	/// Starts recording a movie from the current state, with the cartridge clock
+	/// starting at the browser's current time.
+	pub fn start_recording(&mut self) {
+		self.emulator.start_recording(BrowserClock.now());
+	}
+
+	/// Stops recording or playing a movie, and gives the cartridge clock the
+	/// browser's time again. Returns the recorded movie, if one was recording.
+	pub fn stop_movie(&mut self) -> Option<Vec<u8>> {
+		let movie = self.emulator.stop_recording().map(|movie| movie.encode());
+
+		self.emulator.movie = None;
+		self.emulator.memory.peripherals.host.clock = Box::new(BrowserClock);
+		movie
+	}
+
+	/// Starts playing a movie made by `stop_movie`. Returns `false` if it isn't a
+	/// movie, or the game hasn't been started the same way with the same save
+	/// data as when it was recorded.
+	pub fn play_movie(&mut self, data: &[u8]) -> bool {
+		Movie::decode(data)
+			.and_then(|movie| self.emulator.play_movie(movie))
+			.is_ok()
+	}
+
+	/// Whether a movie is still playing, rather than having reached its end.
+	pub fn is_playing_movie(&self) -> bool {
+		self.emulator.is_playing_movie()
+	}
+
+	/// The first frame where the movie stopped matching what happened when it was
+	/// recorded, if it has.
+	pub fn movie_desync(&self) -> Option<u32> {
+		self.emulator.movie_desync().map(|frame| frame as u32)
+	}
+
+	/// Sets which buttons are held down, with a bit set for each pressed button in
+	/// the same order as KEYINPUT.
+	pub fn set_keys(&mut self, mask: u16) {
+		self.emulator.memory.set_keys(mask);
+	}
+
+	/// Sets how much light is reaching the solar sensor, from 0 for darkness up to
+	/// 255 for bright sunlight.
+	pub fn set_light_level(&mut self, level: u8) {
+		self.emulator.memory.peripherals.host.light = level;
+	}
+
+	/// Sets how fast the cartridge is turning for the gyro sensor, from -1 to 1.
+	pub fn set_rotation(&mut self, rate: f32) {
+		self.emulator.memory.peripherals.host.rotation = rate;
+	}
+
+	/// Sets how far the cartridge is tilted along each axis for the tilt sensor,
+	/// from -1 to 1.
+	pub fn set_tilt(&mut self, x: f32, y: f32) {
+		self.emulator.memory.peripherals.host.tilt = [x, y];
+	}
+
+	/// Replaces the built-in BIOS with a dump of the official one, which should be
+	/// done before starting the emulation. Returns `false` if the image isn't a
+	/// known dump, in which case the built-in BIOS is kept.
+	pub fn load_bios(&mut self, data: &[u8]) -> bool {
+		self.emulator.memory.load_bios(data).is_ok()
+	}
+
+	/// Chooses whether BIOS functions are emulated in Rust, or run by the BIOS
+	/// itself. Running the BIOS code is slower, and needs a real BIOS to work
+	/// properly.
+	pub fn set_bios_hle(&mut self, enabled: bool) {
+		self.emulator.bios_mode = if enabled {
+			BiosMode::HighLevel
+		} else {
+			BiosMode::Native
+		};
+	}
+
+	/// Returns whether the game has switched the cartridge's rumble motor on.
+	pub fn is_rumbling(&self) -> bool {
+		self.emulator.memory.peripherals.is_rumbling()
+	}
+
+	/// Starts resampling audio to the rate that the host plays it at. Until this is
+	/// called, no audio is produced for the host.
+	pub fn set_audio_output_rate(&mut self, rate: u32) {
+		self.emulator.memory.apu.set_output_rate(rate);
+	}
+
+	/// Returns a pointer to the beginning of the audio ring buffer. It holds 32-bit
+	/// float samples, with the left and right channels interleaved.
+	pub fn get_audio_buffer_address(&self) -> *const f32 {
+		match &self.emulator.memory.apu.output {
+			Some(output) => output.buffer.data.as_ptr(),
+			None => std::ptr::null(),
+		}
+	}
+
+	/// Returns the number of left and right pairs that the audio ring buffer holds.
+	pub fn get_audio_buffer_capacity(&self) -> usize {
+		self.emulator
+			.memory
+			.apu
+			.output
+			.as_ref()
+			.map_or(0, |output| output.buffer.capacity())
+	}
+
+	/// Returns the position of the oldest unread pair of samples in the audio ring
+	/// buffer.
+	pub fn get_audio_read_position(&self) -> usize {
+		self.emulator
+			.memory
+			.apu
+			.output
+			.as_ref()
+			.map_or(0, |output| output.buffer.read)
+	}
+
+	/// Returns how many pairs of samples are waiting to be read from the audio ring
+	/// buffer.
+	pub fn get_audio_available(&self) -> usize {
+		self.emulator
+			.memory
+			.apu
+			.output
+			.as_ref()
+			.map_or(0, |output| output.buffer.len)
+	}
+
+	/// Marks pairs of samples as read, making room in the audio ring buffer for
+	/// more.
+	pub fn consume_audio(&mut self, frames: usize) {
+		if let Some(output) = &mut self.emulator.memory.apu.output {
+			output.buffer.consume(frames);
+		}
+	}
+
+	/// Chooses whether lines drawn during forced blank come out white like they do
+	/// on real hardware, or are drawn as usual.
+	pub fn set_show_forced_blank(&mut self, enabled: bool) {
+		self.emulator.ppu.show_forced_blank = enabled;
+	}
+
+	/// Turns on color correction, which makes frames look closer to how they did on
+	/// the GBA's screen.
+	pub fn set_color_correction(&mut self, enabled: bool) {
+		self.emulator.ppu.set_color_correction(enabled);
+	}
+
+	/// Step forward by one instruction
+	pub fn step_instruction(&mut self) {
+		self.emulator.step_instruction();
+	}
+
+	/// Get the values of the current register bank
+	pub fn read_registers(&self) -> Vec<u32> {
+		use lavender_armv4t::registers::Reg::*;
+
+		vec![
+			self.emulator.cpu.registers.get_value(r0),
+			self.emulator.cpu.registers.get_value(r1),
+			self.emulator.cpu.registers.get_value(r2),
+			self.emulator.cpu.registers.get_value(r3),
+			self.emulator.cpu.registers.get_value(r4),
+			self.emulator.cpu.registers.get_value(r5),
+			self.emulator.cpu.registers.get_value(r6),
+			self.emulator.cpu.registers.get_value(r7),
+			self.emulator.cpu.registers.get_value(r8),
+			self.emulator.cpu.registers.get_value(r9),
+			self.emulator.cpu.registers.get_value(r10),
+			self.emulator.cpu.registers.get_value(r11),
+			self.emulator.cpu.registers.get_value(r12),
+			self.emulator.cpu.registers.get_value(r13),
+			self.emulator.cpu.registers.get_value(r14),
+			self.emulator.cpu.registers.get_value(r15),
+		]
+	}
+
+	/// Get the status of the cpsr register.
+	pub fn read_cpsr(&self) -> u32 {
+		use lavender_armv4t::registers::Reg::cpsr;
+
+		self.emulator.cpu.registers.get_value(cpsr)
+	}
+
+	/// Allows us to inspect parts of memory the way that the emulator sees them.
+	// todo: Needs to be robustified for Thumb instructions.
+	pub fn read_next_instruction(&self) -> u32 {
+		self.emulator
+			.memory
+			.read_word(self.emulator.cpu.registers.r15)
+	}
+}
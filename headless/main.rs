@@ -0,0 +1,314 @@
+//! Runs a game without a browser, which is handy for CI and for debugging. The
+//! game runs for a number of frames, or until a word in memory has a certain
+//! value, which is how most test ROMs report that they have finished. Once it
+//! stops, the CPU's registers are printed, and the last frame, the audio, the
+//! save data, and any regions of memory can be written out to files.
+
+use clap::Parser;
+use lavender_armv4t::modes::OperationMode;
+use lavender_armv4t::registers::{Reg, RegisterSet};
+use lavender_gbadvance::bios::BiosMode;
+use lavender_gbadvance::emulator::Emulator;
+use lavender_gbadvance::peripherals::rtc::FixedClock;
+use lavender_gbadvance::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Set in the CPSR while running Thumb instructions.
+const THUMB_STATE: u32 = 1 << 5;
+
+#[derive(Parser)]
+#[command(version, about = "Runs a Gameboy Advance game without a browser")]
+struct Options {
+	/// The ROM to run
+	rom: PathBuf,
+	/// A dump of the official BIOS, to use instead of the built-in one
+	#[arg(long)]
+	bios: Option<PathBuf>,
+	/// Runs BIOS functions with the BIOS itself, rather than emulating them
+	#[arg(long)]
+	native_bios: bool,
+	/// Jumps straight into the game, rather than booting through the BIOS
+	#[arg(long)]
+	skip_bios: bool,
+	/// A .sav file to load into the cartridge before starting
+	#[arg(long)]
+	save: Option<PathBuf>,
+	/// The time for the cartridge clock to start at, in seconds since 1970.
+	/// The clock stays at this time, so that runs always turn out the same
+	#[arg(long, value_name = "SECONDS")]
+	clock: Option<i64>,
+
+	/// The most frames to run for
+	#[arg(long, default_value_t = 60)]
+	frames: u32,
+	/// Stops once the word at ADDRESS is equal to VALUE, which is checked after
+	/// every frame. The run fails if it never is
+	#[arg(long, value_name = "ADDRESS=VALUE", value_parser = parse_condition)]
+	until: Option<(u32, u32)>,
+
+	/// Writes the last frame to a PNG
+	#[arg(long, value_name = "FILE")]
+	screenshot: Option<PathBuf>,
+	/// Writes all of the audio that was played to a WAV
+	#[arg(long, value_name = "FILE")]
+	audio: Option<PathBuf>,
+	/// Writes the cartridge's save data to a .sav file
+	#[arg(long, value_name = "FILE")]
+	export_save: Option<PathBuf>,
+	/// Writes LENGTH bytes of memory, starting at ADDRESS, to FILE. Can be used
+	/// more than once
+	#[arg(long, value_name = "ADDRESS:LENGTH=FILE", value_parser = parse_dump)]
+	dump: Vec<Dump>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Dump {
+	address: u32,
+	length: u32,
+	path: PathBuf,
+}
+
+fn main() -> ExitCode {
+	let options = Options::parse();
+
+	match run(&options) {
+		Ok(true) => ExitCode::SUCCESS,
+		Ok(false) => {
+			eprintln!("The condition wasn't met within {} frames", options.frames);
+			ExitCode::FAILURE
+		}
+		Err(error) => {
+			eprintln!("error: {error}");
+			ExitCode::from(2)
+		}
+	}
+}
+
+/// Runs the game, and writes out everything that was asked for. Returns
+/// whether the condition to stop at was met, if there was one.
+fn run(options: &Options) -> Result<bool, Box<dyn Error>> {
+	let mut emulator = Emulator::new();
+
+	if let Some(path) = &options.bios {
+		let bios = fs::read(path)?;
+		if let Err(error) = emulator.memory.load_bios(&bios) {
+			return Err(format!("{} isn't a known BIOS: {error:?}", path.display()).into());
+		}
+	}
+	if options.native_bios {
+		emulator.bios_mode = BiosMode::Native;
+	}
+
+	emulator.load_rom(&fs::read(&options.rom)?);
+	if let Some(path) = &options.save {
+		if !emulator.memory.save.import(&fs::read(path)?) {
+			return Err(format!("{} is the wrong size for the game's save", path.display()).into());
+		}
+	}
+	if let Some(time) = options.clock {
+		emulator.memory.peripherals.host.clock = Box::new(FixedClock(time));
+	}
+	emulator.boot(options.skip_bios);
+
+	// Samples pile up in the mixer until they are taken, since there isn't an
+	// output for them to go to
+	let sample_rate = emulator.memory.apu.sample_rate();
+	let mut samples = Vec::new();
+	let mut met = options.until.is_none();
+	let mut frames = 0;
+
+	while frames < options.frames {
+		emulator.step_frame();
+		frames += 1;
+		samples.append(&mut emulator.memory.apu.samples);
+
+		if let Some((address, value)) = options.until {
+			if emulator.memory.read_word(address) == value {
+				met = true;
+				break;
+			}
+		}
+
+		// Nothing can wake the CPU back up without any input
+		if emulator.memory.stopped {
+			println!("The CPU stopped");
+			break;
+		}
+	}
+
+	println!("Ran {frames} frames");
+	print_registers(&emulator.cpu.registers);
+
+	if emulator.memory.apu.sample_rate() != sample_rate {
+		eprintln!(
+			"warning: the game changed the sample rate, so the audio will play at the wrong speed"
+		);
+	}
+
+	if let Some(path) = &options.screenshot {
+		write_screenshot(path, &emulator.ppu.framebuffer)?;
+	}
+	if let Some(path) = &options.audio {
+		write_audio(path, &samples, sample_rate)?;
+	}
+	if let Some(path) = &options.export_save {
+		fs::write(path, emulator.memory.save.export())?;
+	}
+	for dump in &options.dump {
+		let memory = (0..dump.length)
+			.map(|offset| emulator.memory.read_byte(dump.address.wrapping_add(offset)))
+			.collect::<Vec<_>>();
+		fs::write(&dump.path, memory)?;
+	}
+
+	Ok(met)
+}
+
+fn print_registers(registers: &RegisterSet) {
+	use Reg::*;
+
+	let general = [
+		r0, r1, r2, r3, r4, r5, r6, r7, r8, r9, r10, r11, r12, r13, r14, r15,
+	];
+	for (index, row) in general.chunks(4).enumerate() {
+		let row = row
+			.iter()
+			.enumerate()
+			.map(|(column, &register)| {
+				let name = format!("r{}", index * 4 + column);
+				format!("{name:>3}: {:08x}", registers.get_value(register))
+			})
+			.collect::<Vec<_>>();
+		println!("{}", row.join("  "));
+	}
+
+	let mode = OperationMode::from(registers);
+	let state = if registers.cpsr & THUMB_STATE > 0 {
+		"Thumb"
+	} else {
+		"ARM"
+	};
+	println!("cpsr: {:08x} ({mode:?}, {state})", registers.cpsr);
+
+	// Only the privileged modes have a saved copy
+	if !matches!(mode, OperationMode::USR | OperationMode::SYS) {
+		println!("spsr: {:08x}", registers.get_value(spsr));
+	}
+}
+
+fn write_screenshot(path: &Path, framebuffer: &[u8]) -> Result<(), Box<dyn Error>> {
+	let file = BufWriter::new(File::create(path)?);
+	let mut encoder = png::Encoder::new(file, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+	encoder.set_color(png::ColorType::Rgba);
+	encoder.set_depth(png::BitDepth::Eight);
+
+	encoder.write_header()?.write_image_data(framebuffer)?;
+	Ok(())
+}
+
+/// Writes interleaved stereo samples straight from the mixer.
+fn write_audio(path: &Path, samples: &[i16], sample_rate: u32) -> Result<(), Box<dyn Error>> {
+	let spec = hound::WavSpec {
+		channels: 2,
+		sample_rate,
+		bits_per_sample: 16,
+		sample_format: hound::SampleFormat::Int,
+	};
+
+	let mut writer = hound::WavWriter::create(path, spec)?;
+	for &sample in samples {
+		writer.write_sample(sample)?;
+	}
+	writer.finalize()?;
+	Ok(())
+}
+
+/// Parses a number in decimal, or in hex if it starts with 0x.
+fn parse_number(text: &str) -> Result<u32, String> {
+	let result = match text.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+		None => text.replace('_', "").parse(),
+	};
+
+	result.map_err(|_| format!("`{text}` isn't a number"))
+}
+
+fn parse_condition(text: &str) -> Result<(u32, u32), String> {
+	let (address, value) = text
+		.split_once('=')
+		.ok_or("should look like ADDRESS=VALUE")?;
+
+	Ok((parse_number(address)?, parse_number(value)?))
+}
+
+fn parse_dump(text: &str) -> Result<Dump, String> {
+	let (range, path) = text
+		.split_once('=')
+		.ok_or("should look like ADDRESS:LENGTH=FILE")?;
+	let (address, length) = range
+		.split_once(':')
+		.ok_or("should look like ADDRESS:LENGTH=FILE")?;
+
+	Ok(Dump {
+		address: parse_number(address)?,
+		length: parse_number(length)?,
+		path: PathBuf::from(path),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn numbers() {
+		assert_eq!(parse_number("1234"), Ok(1234));
+		assert_eq!(parse_number("0x0300_0000"), Ok(0x0300_0000));
+		assert!(parse_number("0xg").is_err());
+		assert!(parse_number("").is_err());
+	}
+
+	#[test]
+	fn arguments() {
+		assert_eq!(
+			parse_condition("0x03000000=0xdead"),
+			Ok((0x0300_0000, 0xdead))
+		);
+		assert!(parse_condition("0x03000000").is_err());
+
+		assert_eq!(
+			parse_dump("0x03000000:0x8000=ram.bin"),
+			Ok(Dump {
+				address: 0x0300_0000,
+				length: 0x8000,
+				path: PathBuf::from("ram.bin"),
+			})
+		);
+		assert!(parse_dump("0x03000000=ram.bin").is_err());
+	}
+
+	#[test]
+	fn options() {
+		let options = Options::try_parse_from([
+			"lavender-headless",
+			"game.gba",
+			"--skip-bios",
+			"--until",
+			"0x03000000=1",
+			"--dump",
+			"0:4=bios.bin",
+			"--dump",
+			"0x04000000:0x400=io.bin",
+		])
+		.unwrap();
+
+		assert_eq!(options.frames, 60);
+		assert!(options.skip_bios);
+		assert_eq!(options.until, Some((0x0300_0000, 1)));
+		assert_eq!(options.dump.len(), 2);
+	}
+}
@@ -1,6 +1,6 @@
 declare global {
 	namespace Lv {
-		export type Core = typeof import("lavender");
+		export type Core = import("lavender").Emulator;
 	}
 }
 
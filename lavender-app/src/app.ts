@@ -1,9 +1,10 @@
 import { Controller } from "./controller/EmulatorController";
+import { loadSave } from "./controller/util";
 
 // We have to import both of these because index doesn't export memory,
-// but index_bg does. index exports the functions, but index_bg doesn't.
+// but index_bg does. index exports the Emulator class, but index_bg doesn't.
 async function main() {
-	const [emulator, { memory }] = await Promise.all([
+	const [core, { memory }] = await Promise.all([
 		import("lavender"),
 		import("lavender/target/index_bg.wasm"),
 	]);
@@ -12,7 +13,9 @@ async function main() {
 	// fetch("/game/pokemon_emerald.gba")
 	const response = await fetch("/rom_tests/bin/first.gba");
 	const buffer = await response.arrayBuffer();
-	emulator.init_emulation(new Uint8Array(buffer));
+	const emulator = new core.Emulator();
+	emulator.init_emulation(new Uint8Array(buffer), false);
+	loadSave(emulator);
 
 	// Create a controller to interact with the emulation
 	new Controller(emulator, memory).enableDrawing();
@@ -1,16 +1,43 @@
 import ReactDOM from "react-dom";
 
 import { Overlay } from "../components/Overlay";
-import { createMemoryView, MemoryView } from "./util";
+import { createMemoryView, MemoryView, storeSave } from "./util";
+
+// Which bit of KEYINPUT each key on the keyboard controls
+const KEY_BINDINGS: { [code: string]: number } = {
+	KeyX: 0, // A
+	KeyZ: 1, // B
+	Backspace: 2, // Select
+	Enter: 3, // Start
+	ArrowRight: 4,
+	ArrowLeft: 5,
+	ArrowUp: 6,
+	ArrowDown: 7,
+	KeyS: 8, // R
+	KeyA: 9, // L
+};
+
+// How far ahead of the speakers to keep audio queued up, in seconds
+const AUDIO_LATENCY = 0.05;
+// How long to vibrate for when the game turns on the rumble motor, in
+// milliseconds. The vibration is stopped early when the game turns it off.
+const RUMBLE_LENGTH = 10_000;
 
 export class Controller {
 	memory: MemoryView;
 
 	canvas: HTMLCanvasElement;
 	context: CanvasRenderingContext2D;
+	frameCanvas: HTMLCanvasElement;
+	frameContext: CanvasRenderingContext2D;
 	frame: number;
 	shouldEmulate: boolean;
 
+	audio?: AudioContext;
+	audioTime: number;
+	keys: number;
+	rumbling: boolean;
+
 	showOverlay: boolean;
 	emulationTime: number;
 	frameEnd: number;
@@ -25,8 +52,18 @@ export class Controller {
 
 		this.canvas = document.querySelector<HTMLCanvasElement>("#display")!;
 		this.context = this.canvas.getContext("2d")!;
+
+		// putImageData doesn't provide any scaling support, so we draw each frame
+		// onto an unscaled canvas first, and then draw that onto the real one.
+		this.frameCanvas = document.createElement("canvas");
+		this.frameCanvas.width = 240;
+		this.frameCanvas.height = 160;
+		this.frameContext = this.frameCanvas.getContext("2d")!;
 		this.frame = 0;
 		this.shouldEmulate = false;
+		this.audioTime = 0;
+		this.keys = 0;
+		this.rumbling = false;
 
 		// Hide the overlay by default in production, show it by default in dev
 		this.showOverlay = webpack_mode !== "production";
@@ -60,6 +97,7 @@ export class Controller {
 		this.canvas.width = width;
 		this.canvas.height = height;
 		this.context.scale(scaleX, scaleY);
+		this.context.imageSmoothingEnabled = false;
 	}
 
 	enableDrawing() {
@@ -68,6 +106,13 @@ export class Controller {
 			if (event.code === "Space") {
 				this.shouldEmulate = !this.shouldEmulate;
 				if (this.shouldEmulate) {
+					// Browsers only allow audio to start in response to user input
+					if (!this.audio) {
+						this.audio = new AudioContext();
+						this.emulator.set_audio_output_rate(this.audio.sampleRate);
+					}
+					this.audio.resume();
+
 					// eslint-disable-next-line no-console
 					console.log("Drawing enabled");
 					requestAnimationFrame(() => this.emulate());
@@ -75,9 +120,28 @@ export class Controller {
 			} else if (event.code === "Backquote") {
 				this.showOverlay = !this.showOverlay;
 				this.updateOverlay();
+			} else if (event.code in KEY_BINDINGS) {
+				event.preventDefault();
+				this.setKey(KEY_BINDINGS[event.code], true);
+			}
+		});
+
+		window.addEventListener("keyup", (event) => {
+			if (event.code in KEY_BINDINGS) {
+				this.setKey(KEY_BINDINGS[event.code], false);
 			}
 		});
 
+		// Make sure the latest save data isn't lost when the page closes
+		window.addEventListener("beforeunload", () => storeSave(this.emulator));
+
+		// Let go of everything if the window loses focus, since we won't hear
+		// about keys being released while it's in the background.
+		window.addEventListener("blur", () => {
+			this.keys = 0;
+			this.emulator.set_keys(0);
+		});
+
 		// Render the frame once, and then pause until manually resumed. The wrap is
 		// necessary so that the call is put on the event loop rather than executing
 		// immediately. If it executes immediately it will attempt to call step_frame
@@ -87,6 +151,14 @@ export class Controller {
 		return this;
 	}
 
+	setKey(bit: number, pressed: boolean) {
+		const keys = pressed ? this.keys | (1 << bit) : this.keys & ~(1 << bit);
+		if (keys !== this.keys) {
+			this.keys = keys;
+			this.emulator.set_keys(keys);
+		}
+	}
+
 	emulate() {
 		if (!this.shouldEmulate) return;
 
@@ -98,6 +170,14 @@ export class Controller {
 		// would be sad.
 		this.emulator.step_frames(1);
 		this.emulationTime = Date.now() - emulationBeginning;
+		this.playAudio();
+		this.updateRumble();
+
+		// Games often write their save over several frames, so only check for
+		// changes about once a second.
+		if (this.frame % 60 === 0) {
+			storeSave(this.emulator);
+		}
 
 		// if (this.frame % 30 === 0) {
 		// 	this.fillScreenWithRandomStuffForTesting();
@@ -112,44 +192,66 @@ export class Controller {
 		requestAnimationFrame(() => this.emulate());
 	}
 
-	render() {
-		const _2d = this.context;
-		const io = this.memory.io;
-		const vram = this.memory.vram;
-
-		const displayControl = io[0] + (io[1] << 8);
-		const displayMode = displayControl & 7;
-
-		// I'm not a fan of any of this, but putImageData doesn't provide any scaling
-		// support and this performs...okay, now that I've added some optimizations.
-		// WebGL is definitely still the route we need to go down eventually.
-		let prevColor = null;
-
-		if (displayMode === 3) {
-			for (let y = 0; y < 160; y++) {
-				let beginX = 0;
-
-				for (let x = 0; x < 240; x++) {
-					const i = y * 240 + x;
-					const rgb15 = vram[i * 2] + (vram[i * 2 + 1] << 8);
-
-					if (rgb15 !== prevColor) {
-						// Draw the rectangle before we change colors
-						_2d.fillRect(beginX, y, x - beginX + 0.2, 1.2);
-
-						// Translate from 15-bit color to 32-bit color
-						_2d.fillStyle = `rgba(${(rgb15 & 0x1f) * 8}, ${
-							((rgb15 >> 5) & 0x1f) * 8
-						}, ${((rgb15 >> 10) & 0x1f) * 8}, 1)`;
-						prevColor = rgb15;
-						beginX = x;
-					}
-				}
+	updateRumble() {
+		const rumbling = this.emulator.is_rumbling();
+		if (rumbling === this.rumbling) return;
+		this.rumbling = rumbling;
 
-				// Draw to the end of the line.
-				_2d.fillRect(beginX, y, 240.2 - beginX, 1.2);
-			}
+		// Not every device can vibrate, and a length of 0 stops the vibration
+		navigator.vibrate?.(rumbling ? RUMBLE_LENGTH : 0);
+	}
+
+	playAudio() {
+		if (!this.audio) return;
+
+		// Only take as much as we need to stay a little ahead of the speakers, and
+		// leave the rest in the emulator's buffer. How full it is tells the
+		// emulator whether it needs to speed up or slow down to match us.
+		this.audioTime = Math.max(this.audioTime, this.audio.currentTime);
+		const queued = this.audioTime - this.audio.currentTime;
+		const rate = this.audio.sampleRate;
+		const wanted = Math.ceil((AUDIO_LATENCY - queued) * rate);
+		const length = Math.min(wanted, this.emulator.get_audio_available());
+		if (length <= 0) return;
+
+		const capacity = this.emulator.get_audio_buffer_capacity();
+		const ring = new Float32Array(
+			this.rawMemory.buffer,
+			this.emulator.get_audio_buffer_address(),
+			capacity * 2,
+		);
+		const start = this.emulator.get_audio_read_position();
+
+		const buffer = this.audio.createBuffer(2, length, rate);
+		const left = buffer.getChannelData(0);
+		const right = buffer.getChannelData(1);
+		for (let i = 0; i < length; i++) {
+			const position = ((start + i) % capacity) * 2;
+			left[i] = ring[position];
+			right[i] = ring[position + 1];
 		}
+		this.emulator.consume_audio(length);
+
+		const source = this.audio.createBufferSource();
+		source.buffer = buffer;
+		source.connect(this.audio.destination);
+		source.start(this.audioTime);
+		this.audioTime += buffer.duration;
+	}
+
+	render() {
+		// The emulator renders the frame as RGBA, so we can hand it straight to the
+		// canvas. We copy it out of wasm memory because the memory might grow (and
+		// detach the buffer) while the frame is still being used.
+		const frameAddress = this.emulator.get_framebuffer_address();
+		const frame = new Uint8ClampedArray(
+			this.rawMemory.buffer,
+			frameAddress,
+			240 * 160 * 4,
+		).slice();
+
+		this.frameContext.putImageData(new ImageData(frame, 240, 160), 0, 0);
+		this.context.drawImage(this.frameCanvas, 0, 0);
 
 		this.updateOverlay();
 	}
@@ -23,3 +23,28 @@ export const createMemoryView = (
 		object: new Uint8Array(buf.slice(objectAddr, objectAddr + 1024)),
 	};
 };
+
+// Saves are kept in local storage, under the game code from the cartridge
+// header so that each game gets its own.
+const saveKey = (emulator: Lv.Core) =>
+	`save:${emulator.get_cartridge_game_code() ?? "unknown"}`;
+
+export const loadSave = (emulator: Lv.Core) => {
+	const stored = localStorage.getItem(saveKey(emulator));
+	if (!stored) return;
+
+	const data = Uint8Array.from(atob(stored), (char) => char.charCodeAt(0));
+	if (!emulator.import_save(data)) {
+		// eslint-disable-next-line no-console
+		console.warn("Stored save data doesn't fit this game's save memory");
+	}
+};
+
+export const storeSave = (emulator: Lv.Core) => {
+	if (!emulator.is_save_dirty()) return;
+
+	const data = emulator.export_save();
+	let binary = "";
+	for (const byte of data) binary += String.fromCharCode(byte);
+	localStorage.setItem(saveKey(emulator), btoa(binary));
+};
@@ -56,6 +56,7 @@ module.exports = (env, argv) => ({
 		new WasmPackPlugin({
 			crateDirectory: path.join(__dirname, "../lavender"),
 			outDir: "target",
+			extraArgs: "--features wasm",
 		}),
 	],
 